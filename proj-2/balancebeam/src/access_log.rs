@@ -0,0 +1,440 @@
+use crate::headers::REQUEST_ID;
+use crate::SharedState;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWrite;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// The formats the access log can be written in, selected with `--access-log-format`.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessLogFormat {
+    /// Common Log Format: client, time, request line, status and bytes
+    Common,
+    /// Combined Log Format: Common Log Format plus the Referer and User-Agent headers
+    Combined,
+    /// One JSON object per line, which also records the upstream and how long the request took
+    Json,
+}
+
+/// Where the access log goes and how it's written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessLogSettings {
+    /// File to append to, or `-` for stdout
+    pub path: PathBuf,
+    pub format: AccessLogFormat,
+    /// Rotate the file once it reaches this many bytes (0 = never)
+    pub max_size: u64,
+    /// How many rotated files (`access.log.1`, `access.log.2`, ...) to keep
+    pub max_files: usize,
+}
+
+/// What the access log records about a request, gathered when its head has been read.
+pub struct RequestInfo {
+    pub client_ip: IpAddr,
+    /// When the request arrived
+    pub received: SystemTime,
+    /// For working out how long the request took
+    pub start: Instant,
+    /// Method, path and version, or None if the request couldn't be parsed
+    request_line: Option<(String, String, String)>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    /// The request's X-Request-Id, which is echoed back on error responses too
+    pub request_id: Option<http::HeaderValue>,
+}
+
+impl RequestInfo {
+    pub fn new(client_ip: IpAddr, request: Option<&http::Request<Vec<u8>>>) -> RequestInfo {
+        let header = |name: http::header::HeaderName| {
+            let value = request?.headers().get(name)?;
+            Some(String::from_utf8_lossy(value.as_bytes()).to_string())
+        };
+        RequestInfo {
+            client_ip,
+            received: SystemTime::now(),
+            start: Instant::now(),
+            request_line: request.map(|request| {
+                (
+                    request.method().to_string(),
+                    request.uri().to_string(),
+                    format!("{:?}", request.version()),
+                )
+            }),
+            referer: header(http::header::REFERER),
+            user_agent: header(http::header::USER_AGENT),
+            request_id: request.and_then(|request| request.headers().get(REQUEST_ID).cloned()),
+        }
+    }
+}
+
+/// A line of the JSON access log.
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    time: String,
+    client_ip: String,
+    method: Option<&'a str>,
+    path: Option<&'a str>,
+    protocol: Option<&'a str>,
+    status: u16,
+    bytes: u64,
+    upstream: Option<&'a str>,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    request_id: Option<&'a str>,
+    latency_ms: f64,
+}
+
+enum Message {
+    Line(String),
+    Reopen,
+}
+
+/// Writes access log lines from a background thread, so that request handling never waits on the
+/// disk.
+pub struct AccessLog {
+    settings: AccessLogSettings,
+    /// Only None while the AccessLog is being dropped
+    sender: Option<mpsc::Sender<Message>>,
+    writer: Option<std::thread::JoinHandle<()>>,
+}
+
+impl AccessLog {
+    /// Opens the log and starts the thread that writes to it. The thread exits once the AccessLog
+    /// is dropped, after writing out every line it was sent.
+    pub fn open(settings: &AccessLogSettings) -> io::Result<AccessLog> {
+        let mut writer = LogWriter::open(settings.clone())?;
+        let (sender, receiver) = mpsc::channel();
+        let writer = std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || writer.run(receiver))?;
+        Ok(AccessLog {
+            settings: settings.clone(),
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    /// Returns true if this log was opened with the given settings, in which case it can be kept
+    /// across a configuration reload.
+    pub fn has_settings(&self, settings: &AccessLogSettings) -> bool {
+        self.settings == *settings
+    }
+
+    /// Records a finished request. `upstream` is None if the request was never sent upstream, and
+    /// `bytes` is the size of the response body sent to the client.
+    pub fn log(
+        &self,
+        info: &RequestInfo,
+        upstream: Option<&str>,
+        status: http::StatusCode,
+        bytes: u64,
+        latency: Duration,
+    ) {
+        let line = match self.settings.format {
+            AccessLogFormat::Common => format_clf(info, status, bytes),
+            AccessLogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                format_clf(info, status, bytes),
+                escape(info.referer.as_deref().unwrap_or("-")),
+                escape(info.user_agent.as_deref().unwrap_or("-"))
+            ),
+            AccessLogFormat::Json => {
+                let (method, path, protocol) = match &info.request_line {
+                    Some((method, path, protocol)) => (Some(method), Some(path), Some(protocol)),
+                    None => (None, None, None),
+                };
+                serde_json::to_string(&JsonEntry {
+                    time: format_rfc3339(info.received),
+                    client_ip: info.client_ip.to_string(),
+                    method: method.map(String::as_str),
+                    path: path.map(String::as_str),
+                    protocol: protocol.map(String::as_str),
+                    status: status.as_u16(),
+                    bytes,
+                    upstream,
+                    referer: info.referer.as_deref(),
+                    user_agent: info.user_agent.as_deref(),
+                    request_id: info.request_id.as_ref().and_then(|id| id.to_str().ok()),
+                    latency_ms: latency.as_secs_f64() * 1000.0,
+                })
+                .unwrap()
+            }
+        };
+        // This only fails if the writer thread has died, which it will have logged
+        self.send(Message::Line(line));
+    }
+
+    /// Closes and reopens the log file, e.g. after it has been moved aside by logrotate.
+    pub fn reopen(&self) {
+        self.send(Message::Reopen);
+    }
+
+    fn send(&self, message: Message) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(message);
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    /// Waits for the lines that are still queued to be written, so that none are lost on exit.
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// `client - - [time] "request line" status bytes`
+fn format_clf(info: &RequestInfo, status: http::StatusCode, bytes: u64) -> String {
+    let request_line = match &info.request_line {
+        Some((method, path, version)) => escape(&format!("{} {} {}", method, path, version)),
+        None => "-".to_string(),
+    };
+    let bytes = match bytes {
+        0 => "-".to_string(),
+        bytes => bytes.to_string(),
+    };
+    format!(
+        "{} - - [{}] \"{}\" {} {}",
+        info.client_ip,
+        format_clf_time(info.received),
+        request_line,
+        status.as_u16(),
+        bytes
+    )
+}
+
+/// Escapes quotes, backslashes and unprintable characters so that a quoted field can't be broken
+/// out of.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Splits a time into UTC (year, month, day, hour, minute, second, millisecond).
+fn utc_fields(time: SystemTime) -> (i64, u32, u32, u64, u64, u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+    // Convert days since 1970-01-01 to a civil date (Howard Hinnant's days_from_civil, inverted)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+/// e.g. `18/Oct/2026:13:55:36 +0000`
+fn format_clf_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, _) = utc_fields(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+/// e.g. `2026-10-18T13:55:36.123Z`
+fn format_rfc3339(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, millis) = utc_fields(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, hour, minute, second, millis
+    )
+}
+
+/// The log file (or stdout), owned by the writer thread.
+struct LogWriter {
+    settings: AccessLogSettings,
+    output: Box<dyn Write + Send>,
+    /// Bytes in the current file, for deciding when to rotate it
+    size: u64,
+}
+
+impl LogWriter {
+    fn open(settings: AccessLogSettings) -> io::Result<LogWriter> {
+        let mut writer = LogWriter {
+            settings,
+            output: Box::new(io::sink()),
+            size: 0,
+        };
+        writer.reopen()?;
+        Ok(writer)
+    }
+
+    fn is_stdout(&self) -> bool {
+        self.settings.path == Path::new("-")
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        if self.is_stdout() {
+            self.output = Box::new(io::stdout());
+            return Ok(());
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.settings.path)?;
+        self.size = file.metadata()?.len();
+        self.output = Box::new(io::BufWriter::new(file));
+        Ok(())
+    }
+
+    /// Moves `access.log` to `access.log.1` (and `access.log.1` to `access.log.2`, and so on,
+    /// dropping the oldest), then starts a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.output.flush()?;
+        self.output = Box::new(io::sink());
+        let rotated = |n: usize| {
+            let mut path = self.settings.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        for n in (1..self.settings.max_files).rev() {
+            if rotated(n).exists() {
+                std::fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        std::fs::rename(&self.settings.path, rotated(1))?;
+        self.reopen()
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let max_size = self.settings.max_size;
+        if !self.is_stdout() && max_size > 0 && self.size > 0 && self.size + len > max_size {
+            self.rotate()?;
+        }
+        writeln!(self.output, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn handle(&mut self, message: Message) {
+        let result = match message {
+            Message::Line(line) => self.write_line(&line),
+            Message::Reopen => {
+                log::info!("Reopening access log {}", self.settings.path.display());
+                self.output.flush().and_then(|()| self.reopen())
+            }
+        };
+        if let Err(err) = result {
+            log::error!(
+                "Error writing access log {}: {}",
+                self.settings.path.display(),
+                err
+            );
+        }
+    }
+
+    /// Writes lines as they arrive, flushing whenever there's nothing more waiting.
+    fn run(&mut self, receiver: mpsc::Receiver<Message>) {
+        while let Ok(message) = receiver.recv() {
+            self.handle(message);
+            while let Ok(message) = receiver.try_recv() {
+                self.handle(message);
+            }
+            if let Err(err) = self.output.flush() {
+                log::error!("Error flushing access log: {}", err);
+            }
+        }
+    }
+}
+
+/// Reopens the access log every time balancebeam receives SIGUSR1, so that it can be rotated by
+/// an external tool.
+pub async fn reopen_on_sigusr1(shared_state: SharedState) {
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            log::error!(
+                "Could not listen for SIGUSR1, access log reopening is disabled: {}",
+                err
+            );
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        let access_log = shared_state.read().access_log.clone();
+        if let Some(access_log) = access_log {
+            access_log.reopen();
+        }
+    }
+}
+
+/// Wraps a writer, counting the bytes written through it.
+pub struct CountingWriter<'a, W> {
+    inner: &'a mut W,
+    pub count: u64,
+}
+
+impl<'a, W> CountingWriter<'a, W> {
+    pub fn new(inner: &'a mut W) -> CountingWriter<'a, W> {
+        CountingWriter { inner, count: 0 }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<'_, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.count += written as u64;
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
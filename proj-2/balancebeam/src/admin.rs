@@ -0,0 +1,308 @@
+use crate::balancing::{Upstream, UpstreamStatus};
+use crate::circuit_breaker::CircuitState;
+use crate::routing::DEFAULT_POOL;
+use crate::{request, response, SharedState};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use tokio::net::{TcpListener, TcpStream};
+
+/// What the admin interface reports about each upstream.
+#[derive(Serialize)]
+struct UpstreamInfo {
+    address: String,
+    weight: usize,
+    pool: String,
+    /// Whether requests are sent to the upstream over TLS
+    tls: bool,
+    /// Whether the upstream is currently in the live list (i.e. healthy and enabled)
+    live: bool,
+    status: UpstreamStatus,
+    circuit: CircuitState,
+    active_connections: usize,
+    requests: usize,
+    failures: usize,
+}
+
+/// Request body for adding an upstream.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewUpstream {
+    address: String,
+    #[serde(default = "default_weight")]
+    weight: usize,
+    #[serde(default = "default_pool")]
+    pool: String,
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+fn default_pool() -> String {
+    DEFAULT_POOL.to_string()
+}
+
+/// Builds a response with a JSON body.
+fn json_response<T: Serialize>(status: http::StatusCode, body: &T) -> http::Response<Vec<u8>> {
+    let body = serde_json::to_vec_pretty(body).unwrap();
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+fn json_error(status: http::StatusCode, message: &str) -> http::Response<Vec<u8>> {
+    json_response(status, &serde_json::json!({ "error": message }))
+}
+
+async fn upstream_info(shared_state: &SharedState, upstream: &Upstream) -> UpstreamInfo {
+    let state = shared_state.read().clone();
+    let live = state
+        .live_upstream
+        .read()
+        .await
+        .iter()
+        .any(|live| live.address == upstream.address);
+    let counters = state.stats.get(&upstream.address);
+    let active_connections = counters.active_connections.load(Ordering::SeqCst);
+    let status = match state.upstream_status.read().get(&upstream.address).copied() {
+        // Nothing new is sent to a draining upstream, so once nothing is left open to it, it's as
+        // good as disabled
+        Some(UpstreamStatus::Draining) if active_connections == 0 => UpstreamStatus::Disabled,
+        status => status.unwrap_or_default(),
+    };
+    UpstreamInfo {
+        address: upstream.address.clone(),
+        weight: upstream.weight,
+        pool: upstream.pool.clone(),
+        tls: upstream.tls,
+        live,
+        status,
+        circuit: state.circuit_breakers.state(&upstream.address),
+        active_connections,
+        requests: counters.requests.load(Ordering::SeqCst),
+        failures: counters.failures.load(Ordering::SeqCst),
+    }
+}
+
+fn find_upstream(shared_state: &SharedState, address: &str) -> Option<Upstream> {
+    shared_state
+        .read()
+        .upstream_addresses
+        .iter()
+        .find(|upstream| upstream.address == address)
+        .cloned()
+}
+
+/// GET /upstreams
+async fn list_upstreams(shared_state: &SharedState) -> http::Response<Vec<u8>> {
+    let upstreams = shared_state.read().upstream_addresses.clone();
+    let mut infos = Vec::new();
+    for upstream in &upstreams {
+        infos.push(upstream_info(shared_state, upstream).await);
+    }
+    json_response(http::StatusCode::OK, &infos)
+}
+
+/// POST /upstreams with a body like {"address": "10.0.0.3:80", "weight": 2, "pool": "api"}. The
+/// address may start with https:// to connect to the upstream over TLS, and the upstream goes in
+/// the default pool if no pool is given.
+async fn add_upstream(shared_state: &SharedState, body: &[u8]) -> http::Response<Vec<u8>> {
+    let new_upstream: NewUpstream = match serde_json::from_slice(body) {
+        Ok(new_upstream) => new_upstream,
+        Err(err) => return json_error(http::StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    if new_upstream.weight == 0 {
+        return json_error(http::StatusCode::BAD_REQUEST, "weight must be at least 1");
+    }
+    let upstream = Upstream {
+        pool: new_upstream.pool,
+        ..Upstream::new(&new_upstream.address, new_upstream.weight)
+    };
+
+    let live_upstream = {
+        let mut state = shared_state.write();
+        if state.router.pool(&upstream.pool).is_none() {
+            return json_error(http::StatusCode::BAD_REQUEST, "no such pool");
+        }
+        if state
+            .upstream_addresses
+            .iter()
+            .any(|existing| existing.address == upstream.address)
+        {
+            return json_error(http::StatusCode::CONFLICT, "upstream already exists");
+        }
+        state.upstream_addresses.push(upstream.clone());
+        state.live_upstream.clone()
+    };
+    // Like upstreams added by a configuration reload, assume it's up until a health check says
+    // otherwise
+    live_upstream.write().await.push(upstream.clone());
+    log::info!("Admin added upstream {}", upstream.address);
+    json_response(
+        http::StatusCode::CREATED,
+        &upstream_info(shared_state, &upstream).await,
+    )
+}
+
+/// DELETE /upstreams/{address}
+async fn remove_upstream(shared_state: &SharedState, address: &str) -> http::Response<Vec<u8>> {
+    let live_upstream = {
+        let mut state = shared_state.write();
+        let before = state.upstream_addresses.len();
+        state
+            .upstream_addresses
+            .retain(|upstream| upstream.address != address);
+        if state.upstream_addresses.len() == before {
+            return json_error(http::StatusCode::NOT_FOUND, "no such upstream");
+        }
+        state.upstream_status.write().remove(address);
+        state.circuit_breakers.remove(address);
+        state.pool.remove(address);
+        state.live_upstream.clone()
+    };
+    live_upstream
+        .write()
+        .await
+        .retain(|upstream| upstream.address != address);
+    log::info!("Admin removed upstream {}", address);
+    json_response(
+        http::StatusCode::OK,
+        &serde_json::json!({ "removed": address }),
+    )
+}
+
+/// POST /upstreams/{address}/{drain,disable,enable}
+async fn set_upstream_status(
+    shared_state: &SharedState,
+    address: &str,
+    status: UpstreamStatus,
+) -> http::Response<Vec<u8>> {
+    let upstream = match find_upstream(shared_state, address) {
+        Some(upstream) => upstream,
+        None => return json_error(http::StatusCode::NOT_FOUND, "no such upstream"),
+    };
+    let state = shared_state.read().clone();
+    if status == UpstreamStatus::Enabled {
+        state.upstream_status.write().remove(address);
+        // Put it straight back into rotation; the health checker takes it out again if it's down
+        let mut live = state.live_upstream.write().await;
+        if !live.iter().any(|live| live.address == address) {
+            live.push(upstream.clone());
+        }
+    } else {
+        state
+            .upstream_status
+            .write()
+            .insert(address.to_string(), status);
+        state.pool.remove(address);
+        state
+            .live_upstream
+            .write()
+            .await
+            .retain(|live| live.address != address);
+    }
+    state.status_changed.notify_waiters();
+    log::info!("Admin set upstream {} to {:?}", address, status);
+    json_response(
+        http::StatusCode::OK,
+        &upstream_info(shared_state, &upstream).await,
+    )
+}
+
+/// Routes an admin request to the right handler.
+async fn handle_request(
+    shared_state: &SharedState,
+    request: &http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let segments: Vec<&str> = request
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let method = request.method();
+    match segments.as_slice() {
+        ["upstreams"] if method == http::Method::GET => list_upstreams(shared_state).await,
+        ["upstreams"] if method == http::Method::POST => {
+            add_upstream(shared_state, request.body()).await
+        }
+        ["upstreams", address] if method == http::Method::GET => {
+            match find_upstream(shared_state, address) {
+                Some(upstream) => json_response(
+                    http::StatusCode::OK,
+                    &upstream_info(shared_state, &upstream).await,
+                ),
+                None => json_error(http::StatusCode::NOT_FOUND, "no such upstream"),
+            }
+        }
+        ["upstreams", address] if method == http::Method::DELETE => {
+            remove_upstream(shared_state, address).await
+        }
+        ["upstreams", address, action] if method == http::Method::POST => {
+            let status = match *action {
+                "enable" => UpstreamStatus::Enabled,
+                "drain" => UpstreamStatus::Draining,
+                "disable" => UpstreamStatus::Disabled,
+                _ => return json_error(http::StatusCode::NOT_FOUND, "unknown action"),
+            };
+            set_upstream_status(shared_state, address, status).await
+        }
+        ["upstreams", ..] => json_error(http::StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        _ => json_error(http::StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, shared_state: &SharedState) {
+    loop {
+        let request = match request::read_from_stream(&mut stream).await {
+            Ok(request) => request,
+            Err(request::Error::IncompleteRequest(0)) | Err(request::Error::ConnectionError(_)) => {
+                return;
+            }
+            Err(error) => {
+                log::debug!("Error parsing admin request: {:?}", error);
+                let response = json_error(http::StatusCode::BAD_REQUEST, "malformed request");
+                let _ = response::write_to_stream(&response, &mut stream).await;
+                return;
+            }
+        };
+        let response = handle_request(shared_state, &request).await;
+        log::debug!(
+            "Admin: {} -> {}",
+            request::format_request_line(&request),
+            response::format_response_line(&response)
+        );
+        if let Err(error) = response::write_to_stream(&response, &mut stream).await {
+            log::warn!("Failed to send admin response: {}", error);
+            return;
+        }
+    }
+}
+
+/// Serves the admin interface on the given listener. It speaks JSON:
+///
+/// * `GET /upstreams` lists every upstream with its pool, health, status and counters
+/// * `GET /upstreams/{address}` shows a single upstream
+/// * `POST /upstreams` with `{"address": ..., "weight": ..., "pool": ...}` adds an upstream
+/// * `DELETE /upstreams/{address}` removes an upstream
+/// * `POST /upstreams/{address}/drain` stops sending new requests to an upstream, and lets the
+///   requests and tunnels already open to it finish. It's reported as disabled once they have.
+/// * `POST /upstreams/{address}/disable` also closes the tunnels open to it (WebSocket connections
+///   and `--mode tcp` connections) right away
+/// * `POST /upstreams/{address}/enable` puts a drained or disabled upstream back in rotation
+///
+/// Changes made here last until the configuration is reloaded.
+pub async fn serve(listener: TcpListener, shared_state: SharedState) {
+    loop {
+        if let Ok((stream, _)) = listener.accept().await {
+            let shared_state = shared_state.clone();
+            tokio::spawn(async move {
+                handle_connection(stream, &shared_state).await;
+            });
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use crate::balancing::Upstream;
+use std::net::IpAddr;
+
+/// Ways of keeping a client's requests on the same upstream, selected with `--affinity`.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AffinityMode {
+    /// Every request is balanced on its own
+    None,
+    /// Hash the client's IP address onto the live upstreams. When an upstream goes down or comes
+    /// back, only the clients that were (or will be) on it move.
+    IpHash,
+    /// Pin each client to an upstream with a cookie that names it
+    Cookie,
+}
+
+/// What a particular request is pinned by, if anything.
+pub enum Affinity {
+    None,
+    ClientIp(IpAddr),
+    /// The upstream ID from the client's affinity cookie, if it sent one
+    Cookie(Option<String>),
+}
+
+impl Affinity {
+    /// Picks the upstream the request is pinned to, returning its index in `candidates`, or None
+    /// if the request isn't pinned (or its upstream isn't a candidate any more) and should be
+    /// balanced as usual. `candidates` is never empty.
+    pub fn choose(&self, candidates: &[Upstream]) -> Option<usize> {
+        match self {
+            Affinity::None | Affinity::Cookie(None) => None,
+            Affinity::ClientIp(ip) => {
+                let key = match ip {
+                    IpAddr::V4(ip) => ip.octets().to_vec(),
+                    IpAddr::V6(ip) => ip.octets().to_vec(),
+                };
+                Some(rendezvous(&key, candidates))
+            }
+            Affinity::Cookie(Some(id)) => candidates
+                .iter()
+                .position(|upstream| upstream_id(&upstream.address) == *id),
+        }
+    }
+
+    /// Returns the Set-Cookie header to send with the response if the request went to a different
+    /// upstream than its cookie named (or it had no cookie), so that the client's next requests
+    /// follow it there.
+    pub fn set_cookie(&self, name: &str, address: &str) -> Option<http::HeaderValue> {
+        let id = upstream_id(address);
+        match self {
+            Affinity::Cookie(pinned) if pinned.as_ref() != Some(&id) => {
+                let cookie = format!("{}={}; Path=/; HttpOnly", name, id);
+                Some(http::HeaderValue::from_str(&cookie).unwrap())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Weighted rendezvous (highest random weight) hashing: every upstream gets a pseudo-random score
+/// for the key, scaled by its weight, and the highest score wins. Taking an upstream away only
+/// moves the keys it was winning, and adding one only takes keys away from the others.
+fn rendezvous(key: &[u8], candidates: &[Upstream]) -> usize {
+    let score = |upstream: &Upstream| {
+        let hash = hash(&[key, upstream.address.as_bytes()]);
+        // Map the hash onto (0, 1), so that -weight / ln(u) is positive and finite
+        let u = ((hash >> 11) as f64 + 0.5) / (1_u64 << 53) as f64;
+        -(upstream.weight as f64) / u.ln()
+    };
+    (0..candidates.len())
+        .max_by(|&a, &b| score(&candidates[a]).total_cmp(&score(&candidates[b])))
+        .unwrap()
+}
+
+/// 64-bit FNV-1a over the given byte strings, followed by a finalizer that spreads small
+/// differences in the input over every bit. Unlike std's hasher, the result is the same in every
+/// process, so clients keep their upstream across restarts.
+fn hash(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for &byte in *part {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        // Separate the parts so that ("ab", "c") and ("a", "bc") hash differently
+        hash ^= 0xff;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/// The value of the affinity cookie that pins a client to the upstream at `address`. It's derived
+/// from the address so that it survives restarts, without giving the address away.
+fn upstream_id(address: &str) -> String {
+    format!("{:016x}", hash(&[address.as_bytes()]))
+}
+
+/// Returns the value of the named cookie, if the request has one.
+pub fn find_cookie(headers: &http::HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value.trim_matches('"').to_string())
+}
+
+/// Returns true if `name` can be used as a cookie name.
+pub fn is_valid_cookie_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b))
+}
@@ -0,0 +1,274 @@
+use crate::routing::DEFAULT_POOL;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// An upstream server along with its static balancing weight. On the command line, the weight can
+/// be given after an `@` (e.g. `--upstream 10.0.0.1:80@3`); it defaults to 1. Upstreams given as
+/// `https://host:port` are connected to over TLS.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upstream {
+    /// Host and port, without the scheme
+    pub address: String,
+    pub weight: usize,
+    pub tls: bool,
+    /// Name of the pool the upstream belongs to
+    pub pool: String,
+}
+
+impl Upstream {
+    /// Builds an upstream in the default pool from an address that may start with `http://` or
+    /// `https://`.
+    pub fn new(address: &str, weight: usize) -> Upstream {
+        let (address, tls) = match address.strip_prefix("https://") {
+            Some(address) => (address, true),
+            None => (address.strip_prefix("http://").unwrap_or(address), false),
+        };
+        Upstream {
+            address: address.to_string(),
+            weight,
+            tls,
+            pool: DEFAULT_POOL.to_string(),
+        }
+    }
+
+    /// The hostname to verify the upstream's certificate against.
+    pub fn host(&self) -> &str {
+        match self.address.rsplit_once(':') {
+            Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => &self.address,
+        }
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Upstream, String> {
+        match s.rsplit_once('@') {
+            Some((address, weight)) => {
+                let weight = weight.parse::<usize>().map_err(|_| {
+                    format!("invalid weight \"{}\" for upstream {}", weight, address)
+                })?;
+                if weight == 0 {
+                    return Err(format!(
+                        "weight for upstream {} must be at least 1",
+                        address
+                    ));
+                }
+                Ok(Upstream::new(address, weight))
+            }
+            None => Ok(Upstream::new(s, 1)),
+        }
+    }
+}
+
+/// Whether an operator has taken an upstream out of rotation through the admin interface.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpstreamStatus {
+    /// In rotation whenever health checks say it is up
+    #[default]
+    Enabled,
+    /// Receives no new requests, but requests and tunnels already open to it finish normally. The
+    /// admin interface reports it as disabled once they have.
+    Draining,
+    /// Receives no new requests, and tunnels open to it are closed right away. Requests already
+    /// sent to it still get their response.
+    Disabled,
+}
+
+/// Runtime counters for a single upstream.
+#[derive(Default)]
+pub struct UpstreamCounters {
+    /// Client connections currently being proxied to the upstream
+    pub active_connections: AtomicUsize,
+    /// Requests forwarded to the upstream
+    pub requests: AtomicUsize,
+    /// Failed attempts to connect to, send a request to, or read a response from the upstream
+    pub failures: AtomicUsize,
+}
+
+/// Counters for every upstream we have proxied to, keyed by address.
+#[derive(Default)]
+pub struct UpstreamStats {
+    counters: Mutex<HashMap<String, Arc<UpstreamCounters>>>,
+}
+
+impl UpstreamStats {
+    /// Returns the counters for the given upstream, creating them if needed.
+    pub fn get(&self, address: &str) -> Arc<UpstreamCounters> {
+        self.counters
+            .lock()
+            .entry(address.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Returns the number of outstanding connections to the given upstream.
+    pub fn active_connections(&self, address: &str) -> usize {
+        match self.counters.lock().get(address) {
+            Some(counters) => counters.active_connections.load(Ordering::SeqCst),
+            None => 0,
+        }
+    }
+
+    /// Counts a new connection to the given upstream. The connection stops being counted when the
+    /// returned guard is dropped.
+    pub fn track(&self, address: &str) -> ConnectionGuard {
+        let counters = self.get(address);
+        counters.active_connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard {
+            address: address.to_string(),
+            counters,
+        }
+    }
+}
+
+/// Keeps a connection counted in UpstreamStats for as long as it is alive.
+pub struct ConnectionGuard {
+    address: String,
+    counters: Arc<UpstreamCounters>,
+}
+
+impl ConnectionGuard {
+    /// Address of the upstream this connection goes to
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Counters for the upstream this connection goes to
+    pub fn counters(&self) -> &UpstreamCounters {
+        &self.counters
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.counters
+            .active_connections
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A policy for choosing which live upstream a request should be sent to.
+pub trait LoadBalancingStrategy: Send + Sync {
+    /// Picks one of the upstreams in `live`, returning its index. `live` is never empty.
+    fn choose(&self, live: &[Upstream], stats: &UpstreamStats) -> usize;
+}
+
+/// The strategies that can be selected with `--strategy`.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StrategyKind {
+    /// Pick a uniformly random upstream
+    Random,
+    /// Cycle through the upstreams in order
+    RoundRobin,
+    /// Pick the upstream with the fewest outstanding connections
+    LeastConnections,
+    /// Pick two random upstreams and use the one with fewer outstanding connections
+    PowerOfTwo,
+    /// Cycle through the upstreams in proportion to their weights
+    Weighted,
+}
+
+impl StrategyKind {
+    pub fn build(self) -> Arc<dyn LoadBalancingStrategy> {
+        match self {
+            StrategyKind::Random => Arc::new(Random),
+            StrategyKind::RoundRobin => Arc::new(RoundRobin::default()),
+            StrategyKind::LeastConnections => Arc::new(LeastConnections),
+            StrategyKind::PowerOfTwo => Arc::new(PowerOfTwoChoices),
+            StrategyKind::Weighted => Arc::new(Weighted::default()),
+        }
+    }
+}
+
+pub struct Random;
+
+impl LoadBalancingStrategy for Random {
+    fn choose(&self, live: &[Upstream], _stats: &UpstreamStats) -> usize {
+        rand::thread_rng().gen_range(0..live.len())
+    }
+}
+
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl LoadBalancingStrategy for RoundRobin {
+    fn choose(&self, live: &[Upstream], _stats: &UpstreamStats) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % live.len()
+    }
+}
+
+pub struct LeastConnections;
+
+impl LoadBalancingStrategy for LeastConnections {
+    fn choose(&self, live: &[Upstream], stats: &UpstreamStats) -> usize {
+        // Ties go to the first upstream in the list, which keeps the choice deterministic
+        (0..live.len())
+            .min_by_key(|&idx| stats.active_connections(&live[idx].address))
+            .unwrap()
+    }
+}
+
+pub struct PowerOfTwoChoices;
+
+impl LoadBalancingStrategy for PowerOfTwoChoices {
+    fn choose(&self, live: &[Upstream], stats: &UpstreamStats) -> usize {
+        if live.len() == 1 {
+            return 0;
+        }
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0..live.len());
+        // Pick a second, different upstream by skipping over the first one
+        let mut second = rng.gen_range(0..live.len() - 1);
+        if second >= first {
+            second += 1;
+        }
+        if stats.active_connections(&live[second].address)
+            < stats.active_connections(&live[first].address)
+        {
+            second
+        } else {
+            first
+        }
+    }
+}
+
+/// Smooth weighted round robin (as used by nginx): every pick, each upstream's current weight is
+/// increased by its static weight, the upstream with the highest current weight is chosen, and the
+/// chosen upstream's current weight is reduced by the total weight. This spreads picks of heavily
+/// weighted upstreams out instead of sending them in bursts.
+#[derive(Default)]
+pub struct Weighted {
+    current_weights: Mutex<HashMap<String, i64>>,
+}
+
+impl LoadBalancingStrategy for Weighted {
+    fn choose(&self, live: &[Upstream], _stats: &UpstreamStats) -> usize {
+        let mut current_weights = self.current_weights.lock();
+        // Forget about upstreams that are no longer live so they start fresh if they come back
+        current_weights
+            .retain(|address, _| live.iter().any(|upstream| &upstream.address == address));
+
+        let total_weight: i64 = live.iter().map(|upstream| upstream.weight as i64).sum();
+        let mut best: Option<(usize, i64)> = None;
+        for (idx, upstream) in live.iter().enumerate() {
+            let current = current_weights.entry(upstream.address.clone()).or_insert(0);
+            *current += upstream.weight as i64;
+            if best.is_none() || *current > best.unwrap().1 {
+                best = Some((idx, *current));
+            }
+        }
+        let (chosen, _) = best.unwrap();
+        *current_weights.get_mut(&live[chosen].address).unwrap() -= total_weight;
+        chosen
+    }
+}
@@ -0,0 +1,174 @@
+use crate::chunked;
+use std::cmp::min;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the buffer used to pass a body from one connection to the other. This bounds how much
+/// of a body is held in memory at once, no matter how big the body is.
+const COPY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// How the end of a message body is marked (RFC 7230 section 3.3.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// There is no body
+    Empty,
+    /// The body is exactly this many bytes long
+    ContentLength(usize),
+    /// The body uses the chunked transfer coding
+    Chunked,
+    /// The body runs until the sender closes the connection. Only responses can do this
+    UntilClose,
+}
+
+// The wrapped errors are only read through the Debug impl when logging
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum Error {
+    /// The sender hung up before sending the whole body
+    Incomplete,
+    /// The sender sent more bytes than the Content-Length said it would
+    TooLong,
+    /// The body is chunked, but the chunk framing is invalid
+    MalformedChunkedBody(chunked::Error),
+    /// Encountered an I/O error when reading the body
+    Read(std::io::Error),
+    /// Encountered an I/O error when passing the body along
+    Write(std::io::Error),
+}
+
+impl From<chunked::Error> for Error {
+    fn from(err: chunked::Error) -> Error {
+        match err {
+            chunked::Error::Incomplete => Error::Incomplete,
+            chunked::Error::ConnectionError(io_err) => Error::Read(io_err),
+            err => Error::MalformedChunkedBody(err),
+        }
+    }
+}
+
+/// A message body that is still (at least partly) sitting on the connection, waiting to be passed
+/// along. Only the headers of a proxied message are buffered; the body is streamed from one
+/// connection to the other with `copy`.
+#[derive(Debug)]
+pub struct PendingBody {
+    /// Bytes of the body that were read from the connection along with the headers
+    already_read: Vec<u8>,
+    framing: Framing,
+}
+
+impl PendingBody {
+    pub fn new(already_read: Vec<u8>, framing: Framing) -> PendingBody {
+        PendingBody {
+            already_read,
+            framing,
+        }
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Returns whatever was read from the connection after the headers. Once a connection has
+    /// switched protocols (101 Switching Protocols), these bytes belong to the new protocol.
+    pub fn into_already_read(self) -> Vec<u8> {
+        self.already_read
+    }
+
+    /// Reads the rest of the body from `reader` and writes it to `writer` as it arrives. A chunked
+    /// body is passed along chunk by chunk (trailers included), so it stays chunked.
+    pub async fn copy<R, W>(self, reader: &mut R, writer: &mut W) -> Result<(), Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        match self.framing {
+            Framing::Empty => {
+                if !self.already_read.is_empty() {
+                    return Err(Error::TooLong);
+                }
+                Ok(())
+            }
+            Framing::ContentLength(len) => copy_exact(reader, writer, self.already_read, len).await,
+            Framing::Chunked => chunked::copy_body(reader, writer, self.already_read).await,
+            Framing::UntilClose => copy_until_close(reader, writer, self.already_read).await,
+        }
+    }
+
+    /// Like `copy`, but a chunked body has its chunk framing and trailers stripped, for passing it
+    /// on over a protocol that frames bodies itself (HTTP/2).
+    pub async fn copy_decoded<R, W>(self, reader: &mut R, writer: &mut W) -> Result<(), Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        match self.framing {
+            Framing::Chunked => chunked::decode_body(reader, writer, self.already_read).await,
+            _ => self.copy(reader, writer).await,
+        }
+    }
+}
+
+/// Copies exactly `len` bytes (including those in `already_read`) from `reader` to `writer`.
+async fn copy_exact<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    already_read: Vec<u8>,
+    len: usize,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if already_read.len() > len {
+        return Err(Error::TooLong);
+    }
+    writer
+        .write_all(&already_read)
+        .await
+        .map_err(Error::Write)?;
+    let mut remaining = len - already_read.len();
+    let mut buffer = vec![0_u8; min(COPY_BUFFER_SIZE, remaining)];
+    while remaining > 0 {
+        // Don't read past the end of the body, in case the sender has already sent something else
+        let to_read = min(buffer.len(), remaining);
+        let bytes_read = reader
+            .read(&mut buffer[..to_read])
+            .await
+            .map_err(Error::Read)?;
+        if bytes_read == 0 {
+            return Err(Error::Incomplete);
+        }
+        writer
+            .write_all(&buffer[..bytes_read])
+            .await
+            .map_err(Error::Write)?;
+        remaining -= bytes_read;
+    }
+    Ok(())
+}
+
+/// Copies everything from `reader` to `writer` until `reader` reaches end of file.
+async fn copy_until_close<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    already_read: Vec<u8>,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    writer
+        .write_all(&already_read)
+        .await
+        .map_err(Error::Write)?;
+    let mut buffer = vec![0_u8; COPY_BUFFER_SIZE];
+    loop {
+        let bytes_read = reader.read(&mut buffer).await.map_err(Error::Read)?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        writer
+            .write_all(&buffer[..bytes_read])
+            .await
+            .map_err(Error::Write)?;
+    }
+}
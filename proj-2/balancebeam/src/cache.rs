@@ -0,0 +1,594 @@
+use crate::body::Framing;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Responses that can be cached without an explicit freshness lifetime, as long as they have one
+/// (RFC 9110 section 15.1)
+const CACHEABLE_STATUSES: [u16; 10] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 501];
+
+/// Prefix of the files bodies are stored in when the cache is disk-backed. The full name also has
+/// the process ID, so that files left over by processes that have exited can be told apart and
+/// removed.
+const FILE_PREFIX: &str = "balancebeam-";
+const FILE_SUFFIX: &str = ".cache";
+
+/// Numbers the body files, across every cache in this process so that a cache replaced by a
+/// configuration reload can't clash with the new one
+static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// How big the cache is and where it keeps response bodies.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheSettings {
+    /// Evict the least recently used responses once their total size goes over this many bytes
+    pub max_size: u64,
+    /// Don't store responses with bodies bigger than this many bytes
+    pub max_object_size: u64,
+    /// Directory to keep response bodies in, or None to keep them in memory
+    pub dir: Option<PathBuf>,
+}
+
+/// Whether a response was served from the cache, as told to the client in the X-Cache header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+        }
+    }
+
+    pub fn add_header(&self, headers: &mut HeaderMap) {
+        headers.insert("x-cache", HeaderValue::from_static(self.as_str()));
+    }
+}
+
+/// Where a stored response's body is kept.
+#[derive(Clone)]
+enum StoredBody {
+    Memory(Arc<Vec<u8>>),
+    File(PathBuf),
+}
+
+struct Entry {
+    status: http::StatusCode,
+    headers: HeaderMap,
+    body: StoredBody,
+    /// Bytes counted against the cache size
+    size: u64,
+    /// When the response was received, and how old the upstream said it already was
+    stored_at: Instant,
+    initial_age: Duration,
+    /// How long the response is fresh for, counting from when the upstream generated it
+    lifetime: Duration,
+    /// Position in the LRU list
+    last_used: u64,
+}
+
+impl Entry {
+    fn age(&self) -> Duration {
+        self.initial_age + self.stored_at.elapsed()
+    }
+
+    fn has_validators(&self) -> bool {
+        self.headers.contains_key(header::ETAG) || self.headers.contains_key(header::LAST_MODIFIED)
+    }
+}
+
+/// The responses stored for one URL. Responses that carry a Vary header are stored once for each
+/// combination of the request headers it names.
+#[derive(Default)]
+struct Url {
+    vary: Vec<HeaderName>,
+    variants: HashSet<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    urls: HashMap<String, Url>,
+    entries: HashMap<String, Entry>,
+    /// Keys of the entries, least recently used first
+    lru: BTreeMap<u64, String>,
+    clock: u64,
+    size: u64,
+}
+
+impl Inner {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.last_used);
+            entry.last_used = clock;
+            self.lru.insert(clock, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        let entry = match self.entries.remove(key) {
+            Some(entry) => entry,
+            None => return,
+        };
+        self.lru.remove(&entry.last_used);
+        self.size -= entry.size;
+        let url_key = key.split_once('\n').map_or(key, |(url, _)| url);
+        if let Some(url) = self.urls.get_mut(url_key) {
+            url.variants.remove(key);
+            if url.variants.is_empty() {
+                self.urls.remove(url_key);
+            }
+        }
+        if let StoredBody::File(path) = entry.body {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A response found in the cache, with its body loaded.
+pub struct CachedResponse {
+    key: String,
+    response: http::Response<Vec<u8>>,
+    fresh: bool,
+}
+
+impl CachedResponse {
+    /// Returns true if the response can be served as it is. Otherwise it has to be revalidated
+    /// with the upstream first.
+    pub fn is_fresh(&self) -> bool {
+        self.fresh
+    }
+
+    /// Makes the request conditional on the cached response having changed, so that the upstream
+    /// can answer 304 Not Modified instead of sending the whole response again.
+    pub fn add_validators(&self, request: &mut http::Request<Vec<u8>>) {
+        let headers = self.response.headers();
+        if let Some(etag) = headers.get(header::ETAG) {
+            request
+                .headers_mut()
+                .insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = headers.get(header::LAST_MODIFIED) {
+            request
+                .headers_mut()
+                .insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+
+    /// Returns the response to send to the client: 304 Not Modified if the client's own
+    /// If-None-Match matches the cached response, or the whole cached response otherwise.
+    pub fn into_response(self, request: &http::Request<Vec<u8>>) -> http::Response<Vec<u8>> {
+        let mut response = self.response;
+        let etag = response.headers().get(header::ETAG);
+        if etag.is_some_and(|etag| etag_matches(request, etag)) {
+            *response.status_mut() = http::StatusCode::NOT_MODIFIED;
+            response.headers_mut().remove(header::CONTENT_LENGTH);
+            response.body_mut().clear();
+        }
+        response
+    }
+}
+
+/// Returns the ID of the process that stored a body in the file with the given name, or None if it
+/// isn't a body file.
+fn file_owner(name: &str) -> Option<i32> {
+    let name = name.strip_prefix(FILE_PREFIX)?.strip_suffix(FILE_SUFFIX)?;
+    let (pid, _) = name.split_once('-')?;
+    pid.parse().ok()
+}
+
+/// Returns true if a process with the given ID is running. Sending it signal 0 only checks that
+/// it exists; being denied permission to signal it means it does.
+fn is_running(pid: i32) -> bool {
+    kill(Pid::from_raw(pid), None) != Err(Errno::ESRCH)
+}
+
+/// Stores cacheable responses to GET requests so that they can be served without going to an
+/// upstream, following the caching rules of RFC 9111 for a shared cache. Stale responses are
+/// revalidated with a conditional request when they have an ETag or Last-Modified header.
+pub struct ResponseCache {
+    settings: CacheSettings,
+    inner: Mutex<Inner>,
+}
+
+impl ResponseCache {
+    /// Opens the cache, making sure that its directory (if it has one) exists, and removing any
+    /// files left there by processes that are no longer running. Files of a process that is still
+    /// running are left alone, since it may be handing over to this one on an upgrade and still be
+    /// serving from them.
+    pub fn open(settings: &CacheSettings) -> io::Result<ResponseCache> {
+        if let Some(dir) = &settings.dir {
+            std::fs::create_dir_all(dir)?;
+            for file in std::fs::read_dir(dir)? {
+                let path = file?.path();
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                if file_owner(&name).is_some_and(|pid| !is_running(pid)) {
+                    // Another process starting up may have got to it first
+                    match std::fs::remove_file(&path) {
+                        Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(ResponseCache {
+            settings: settings.clone(),
+            inner: Mutex::new(Inner::default()),
+        })
+    }
+
+    /// Returns true if this cache was opened with the given settings, in which case it can be kept
+    /// (along with what it has stored) across a configuration reload.
+    pub fn has_settings(&self, settings: &CacheSettings) -> bool {
+        self.settings == *settings
+    }
+
+    /// Looks up the stored response for a request. Stale responses are only returned if they can
+    /// be revalidated, and the client hasn't made the request conditional itself.
+    pub async fn lookup(&self, request: &http::Request<Vec<u8>>) -> Option<CachedResponse> {
+        let url_key = url_key(request);
+        let (key, mut response, body, fresh) = {
+            let mut inner = self.inner.lock();
+            let vary = &inner.urls.get(&url_key)?.vary;
+            let key = variant_key(&url_key, vary, request.headers());
+            let entry = inner.entries.get(&key)?;
+            let age = entry.age();
+            let fresh = age < entry.lifetime && !wants_revalidation(request);
+            if !fresh && (!entry.has_validators() || is_conditional(request)) {
+                return None;
+            }
+            let mut response = http::Response::new(Vec::new());
+            *response.status_mut() = entry.status;
+            *response.headers_mut() = entry.headers.clone();
+            let age = HeaderValue::from(age.as_secs());
+            response.headers_mut().insert(header::AGE, age);
+            let body = entry.body.clone();
+            inner.touch(&key);
+            (key, response, body, fresh)
+        };
+        *response.body_mut() = match body {
+            StoredBody::Memory(body) => body.to_vec(),
+            // The file may have been evicted since the lock was released
+            StoredBody::File(path) => tokio::fs::read(&path).await.ok()?,
+        };
+        Some(CachedResponse {
+            key,
+            response,
+            fresh,
+        })
+    }
+
+    /// Returns true if the response to the given request can be stored. `framing` is how the
+    /// response's body is framed; only bodies with a Content-Length are stored.
+    pub fn is_storable(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+        framing: Framing,
+    ) -> bool {
+        let size = match framing {
+            Framing::Empty => 0,
+            Framing::ContentLength(len) => len as u64,
+            Framing::Chunked | Framing::UntilClose => return false,
+        };
+        let directives = cache_control(response.headers());
+        size <= self.settings.max_object_size
+            && is_cacheable_request(request)
+            && CACHEABLE_STATUSES.contains(&response.status().as_u16())
+            && !directives.contains_key("no-store")
+            && !directives.contains_key("private")
+            && !response.headers().contains_key(header::SET_COOKIE)
+            && !vary_names(response.headers()).contains(&HeaderName::from_static("*"))
+            && (lifetime(response.headers()).is_some()
+                || response.headers().contains_key(header::ETAG)
+                || response.headers().contains_key(header::LAST_MODIFIED))
+    }
+
+    /// Stores a response (body included), evicting the least recently used responses to make room
+    /// for it. The caller must have checked that it is storable.
+    pub async fn store(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+    ) {
+        let mut headers = response.headers().clone();
+        for name in [
+            header::AGE,
+            header::CONNECTION,
+            HeaderName::from_static("keep-alive"),
+        ] {
+            headers.remove(name);
+        }
+        let size = stored_size(&headers, response.body());
+        if size > self.settings.max_size {
+            return;
+        }
+        let body = match &self.settings.dir {
+            None => StoredBody::Memory(Arc::new(response.body().clone())),
+            Some(dir) => {
+                let file = NEXT_FILE.fetch_add(1, Ordering::SeqCst);
+                let path = dir.join(format!(
+                    "{}{}-{}{}",
+                    FILE_PREFIX,
+                    std::process::id(),
+                    file,
+                    FILE_SUFFIX
+                ));
+                if let Err(error) = tokio::fs::write(&path, response.body()).await {
+                    log::warn!("Could not write cached response to {:?}: {}", path, error);
+                    return;
+                }
+                StoredBody::File(path)
+            }
+        };
+        let entry = Entry {
+            status: response.status(),
+            lifetime: lifetime(&headers).unwrap_or_default(),
+            initial_age: initial_age(&headers),
+            headers,
+            body,
+            size,
+            stored_at: Instant::now(),
+            last_used: 0,
+        };
+
+        let url_key = url_key(request);
+        let vary = vary_names(response.headers());
+        let key = variant_key(&url_key, &vary, request.headers());
+        let mut inner = self.inner.lock();
+        // A response that varies on different headers replaces every stored variant
+        let stale_variants: Vec<String> = match inner.urls.get(&url_key) {
+            Some(url) if url.vary != vary => url.variants.iter().cloned().collect(),
+            _ => vec![key.clone()],
+        };
+        for variant in stale_variants {
+            inner.remove(&variant);
+        }
+        let url = inner.urls.entry(url_key).or_default();
+        url.vary = vary;
+        url.variants.insert(key.clone());
+        inner.size += entry.size;
+        inner.entries.insert(key.clone(), entry);
+        inner.touch(&key);
+        while inner.size > self.settings.max_size {
+            let oldest = match inner.lru.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            log::debug!("Evicting {} from the cache", oldest.replace('\n', " "));
+            inner.remove(&oldest);
+        }
+    }
+
+    /// Updates a stale response with the headers of the 304 Not Modified the upstream sent when it
+    /// was revalidated, making it fresh again, and returns it.
+    pub fn refresh(
+        &self,
+        cached: CachedResponse,
+        not_modified: &http::Response<Vec<u8>>,
+    ) -> http::Response<Vec<u8>> {
+        let mut response = cached.response;
+        for name in not_modified.headers().keys() {
+            if *name == header::CONTENT_LENGTH || *name == header::CONNECTION {
+                continue;
+            }
+            response.headers_mut().remove(name);
+            for value in not_modified.headers().get_all(name) {
+                response.headers_mut().append(name, value.clone());
+            }
+        }
+        response.headers_mut().remove(header::AGE);
+        let mut inner = self.inner.lock();
+        if let Some(entry) = inner.entries.get_mut(&cached.key) {
+            entry.headers = response.headers().clone();
+            entry.lifetime = lifetime(&entry.headers).unwrap_or_default();
+            entry.initial_age = initial_age(&entry.headers);
+            entry.stored_at = Instant::now();
+        }
+        response
+    }
+
+    /// Forgets the stored responses for a URL that an unsafe request (e.g. a POST) has changed
+    /// (RFC 9111 section 4.4).
+    pub fn invalidate(&self, request: &http::Request<Vec<u8>>) {
+        let url_key = url_key(request);
+        let mut inner = self.inner.lock();
+        let variants: Vec<String> = match inner.urls.get(&url_key) {
+            Some(url) => url.variants.iter().cloned().collect(),
+            None => return,
+        };
+        for variant in variants {
+            inner.remove(&variant);
+        }
+    }
+}
+
+impl Drop for ResponseCache {
+    /// Removes the files the stored bodies are in. A cache is dropped when a configuration reload
+    /// replaces it, and nothing else would remove them while this process is still running.
+    fn drop(&mut self) {
+        for entry in self.inner.get_mut().entries.values() {
+            if let StoredBody::File(path) = &entry.body {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Returns true if a response to the request could come from (or go into) the cache: it's a GET
+/// without credentials, and the client hasn't asked for the response not to be stored.
+pub fn is_cacheable_request(request: &http::Request<Vec<u8>>) -> bool {
+    request.method() == http::Method::GET
+        && !request.headers().contains_key(header::AUTHORIZATION)
+        && !cache_control(request.headers()).contains_key("no-store")
+}
+
+/// Returns true if the request's method can change what is stored at its URL.
+pub fn is_unsafe_request(request: &http::Request<Vec<u8>>) -> bool {
+    !matches!(
+        *request.method(),
+        http::Method::GET | http::Method::HEAD | http::Method::OPTIONS | http::Method::TRACE
+    )
+}
+
+/// Returns true if the client asked for the response to be checked with the upstream even if the
+/// cached copy is fresh.
+fn wants_revalidation(request: &http::Request<Vec<u8>>) -> bool {
+    let directives = cache_control(request.headers());
+    directives.contains_key("no-cache")
+        || directives.get("max-age").map(String::as_str) == Some("0")
+        || request
+            .headers()
+            .get(header::PRAGMA)
+            .is_some_and(|pragma| pragma.as_bytes() == b"no-cache")
+}
+
+fn is_conditional(request: &http::Request<Vec<u8>>) -> bool {
+    [
+        header::IF_NONE_MATCH,
+        header::IF_MODIFIED_SINCE,
+        header::IF_MATCH,
+        header::IF_UNMODIFIED_SINCE,
+        header::IF_RANGE,
+    ]
+    .iter()
+    .any(|name| request.headers().contains_key(name))
+}
+
+/// Returns true if the request's If-None-Match header lists the given ETag (weak comparison, RFC
+/// 9110 section 13.1.2).
+fn etag_matches(request: &http::Request<Vec<u8>>, etag: &HeaderValue) -> bool {
+    let etag = etag.to_str().unwrap_or_default();
+    let etag = etag.trim_start_matches("W/");
+    request
+        .headers()
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+/// Identifies a URL: the method, the host the request was for and the request target.
+fn url_key(request: &http::Request<Vec<u8>>) -> String {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| request.uri().host())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let target = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |target| target.as_str());
+    format!("GET {} {}", host, target)
+}
+
+/// Identifies one variant of a URL's response: the URL plus the values of the request headers the
+/// response varies on.
+fn variant_key(url_key: &str, vary: &[HeaderName], headers: &HeaderMap) -> String {
+    let mut key = format!("{}\n", url_key);
+    for name in vary {
+        let values: Vec<&[u8]> = headers.get_all(name).iter().map(|v| v.as_bytes()).collect();
+        key.push_str(&format!(
+            "{}: {}\n",
+            name,
+            String::from_utf8_lossy(&values.join(&b", "[..]))
+        ));
+    }
+    key
+}
+
+fn vary_names(headers: &HeaderMap) -> Vec<HeaderName> {
+    let mut names: Vec<HeaderName> = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    names.dedup();
+    names
+}
+
+/// Parses the Cache-Control directives in a message's headers, with any values unquoted.
+fn cache_control(headers: &HeaderMap) -> HashMap<String, String> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter(|directive| !directive.trim().is_empty())
+        .map(|directive| {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            (
+                name.trim().to_ascii_lowercase(),
+                value.trim().trim_matches('"').to_string(),
+            )
+        })
+        .collect()
+}
+
+/// Works out how long a response stays fresh from its Cache-Control and Expires headers (RFC 9111
+/// section 4.2.1), or returns None if it doesn't say. Responses marked no-cache are stale straight
+/// away.
+fn lifetime(headers: &HeaderMap) -> Option<Duration> {
+    let directives = cache_control(headers);
+    if directives.contains_key("no-cache") {
+        return Some(Duration::ZERO);
+    }
+    for directive in ["s-maxage", "max-age"] {
+        if let Some(value) = directives.get(directive) {
+            return Some(Duration::from_secs(value.parse().unwrap_or(0)));
+        }
+    }
+    let expires = headers.get(header::EXPIRES)?;
+    // An invalid Expires header means the response has already expired
+    let expires = match parse_date(expires) {
+        Some(expires) => expires,
+        None => return Some(Duration::ZERO),
+    };
+    let date = headers
+        .get(header::DATE)
+        .and_then(parse_date)
+        .unwrap_or_else(SystemTime::now);
+    Some(expires.duration_since(date).unwrap_or_default())
+}
+
+/// How old the upstream said the response already was when we got it.
+fn initial_age(headers: &HeaderMap) -> Duration {
+    let age = headers
+        .get(header::AGE)
+        .and_then(|age| age.to_str().ok())
+        .and_then(|age| age.parse().ok())
+        .unwrap_or(0);
+    Duration::from_secs(age)
+}
+
+fn parse_date(value: &HeaderValue) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.to_str().ok()?).ok()
+}
+
+/// Bytes a stored response counts against the cache size: its body plus its headers.
+fn stored_size(headers: &HeaderMap, body: &[u8]) -> u64 {
+    let headers: usize = headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum();
+    (headers + body.len()) as u64
+}
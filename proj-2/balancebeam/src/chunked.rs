@@ -0,0 +1,428 @@
+use crate::body;
+use std::cmp::min;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest chunk size line (size plus any chunk extensions) we are willing to buffer
+const MAX_CHUNK_LINE_SIZE: usize = 1024;
+/// Largest trailer section we are willing to buffer
+const MAX_TRAILERS_SIZE: usize = 8000;
+const MAX_NUM_TRAILERS: usize = 32;
+/// Largest chunk written by ChunkedWriter
+const MAX_WRITTEN_CHUNK_SIZE: usize = 16 * 1024;
+
+#[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
+pub enum Error {
+    /// The chunk framing is invalid. The string describes what was wrong with it
+    Malformed(&'static str),
+    /// The peer hung up before sending the last chunk and the trailers
+    Incomplete,
+    /// The decoded body is bigger than the allowed maximum
+    TooLarge,
+    /// Encountered an I/O error when reading from the stream
+    ConnectionError(std::io::Error),
+}
+
+/// Trailer fields that followed a chunked body. These are stashed in the extensions of the
+/// http::Request or http::Response so that they can be passed along when the message is forwarded.
+#[derive(Clone, Debug, Default)]
+pub struct Trailers(pub http::HeaderMap);
+
+/// Returns the transfer codings listed in the Transfer-Encoding header(s), in order, or None if
+/// there is no Transfer-Encoding header.
+pub fn transfer_codings(headers: &http::HeaderMap) -> Option<Vec<String>> {
+    let mut codings = Vec::new();
+    for value in headers.get_all("transfer-encoding") {
+        for coding in String::from_utf8_lossy(value.as_bytes()).split(',') {
+            let coding = coding.trim();
+            if !coding.is_empty() {
+                codings.push(coding.to_ascii_lowercase());
+            }
+        }
+    }
+    if codings.is_empty() {
+        None
+    } else {
+        Some(codings)
+    }
+}
+
+/// Returns true if the message body is framed using the chunked transfer coding. (Chunked has to be
+/// the last coding applied, if it is used at all.)
+pub fn is_chunked(headers: &http::HeaderMap) -> bool {
+    match transfer_codings(headers) {
+        Some(codings) => codings.last().map(String::as_str) == Some("chunked"),
+        None => false,
+    }
+}
+
+/// Reads bytes from a stream on top of whatever was already read into `buffer`.
+struct ChunkReader<'a, S> {
+    stream: &'a mut S,
+    buffer: Vec<u8>,
+    /// How much of `buffer` has already been consumed
+    pos: usize,
+}
+
+impl<S: AsyncRead + Unpin> ChunkReader<'_, S> {
+    /// Reads more data from the stream into the buffer.
+    async fn fill(&mut self) -> Result<(), Error> {
+        // Throw away what has already been consumed, so the buffer doesn't grow without bound
+        if self.pos == self.buffer.len() {
+            self.buffer.clear();
+            self.pos = 0;
+        }
+        let mut read_buffer = [0_u8; 512];
+        let bytes_read = self
+            .stream
+            .read(&mut read_buffer)
+            .await
+            .map_err(Error::ConnectionError)?;
+        if bytes_read == 0 {
+            return Err(Error::Incomplete);
+        }
+        self.buffer.extend_from_slice(&read_buffer[..bytes_read]);
+        Ok(())
+    }
+
+    /// Reads a line terminated by CRLF (or a bare LF), returning it without the line terminator.
+    async fn read_line(&mut self, max_len: usize) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(newline) = self.buffer[self.pos..].iter().position(|&b| b == b'\n') {
+                let mut line = self.buffer[self.pos..self.pos + newline].to_vec();
+                self.pos += newline + 1;
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Ok(line);
+            }
+            if self.buffer.len() - self.pos > max_len {
+                return Err(Error::Malformed("line too long"));
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Reads exactly `len` bytes.
+    async fn read_exact(&mut self, len: usize) -> Result<&[u8], Error> {
+        while self.buffer.len() - self.pos < len {
+            self.fill().await?;
+        }
+        let start = self.pos;
+        self.pos += len;
+        Ok(&self.buffer[start..self.pos])
+    }
+
+    /// Reads at most `max_len` bytes, returning as soon as any are available.
+    async fn read_some(&mut self, max_len: usize) -> Result<&[u8], Error> {
+        if self.pos == self.buffer.len() {
+            self.fill().await?;
+        }
+        let start = self.pos;
+        self.pos += min(max_len, self.buffer.len() - start);
+        Ok(&self.buffer[start..self.pos])
+    }
+}
+
+/// Parses a chunk size line, which is a hex number optionally followed by ";extensions".
+fn parse_chunk_size(line: &[u8]) -> Result<usize, Error> {
+    let line = std::str::from_utf8(line).map_err(|_| Error::Malformed("invalid chunk size"))?;
+    let size = line.split(';').next().unwrap().trim();
+    if size.is_empty() || !size.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::Malformed("invalid chunk size"));
+    }
+    usize::from_str_radix(size, 16).map_err(|_| Error::Malformed("chunk size out of range"))
+}
+
+/// Checks that a trailer line looks like "name: value".
+fn is_valid_trailer(line: &[u8]) -> bool {
+    match line.iter().position(|&b| b == b':') {
+        Some(colon) => http::HeaderName::from_bytes(&line[..colon]).is_ok(),
+        None => false,
+    }
+}
+
+/// Decodes a chunked body from the stream. `already_read` holds any bytes after the headers that
+/// were read from the stream along with them. Returns the decoded body along with any trailer
+/// fields.
+pub async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    already_read: Vec<u8>,
+    max_body_size: usize,
+) -> Result<(Vec<u8>, Trailers), Error> {
+    let mut reader = ChunkReader {
+        stream,
+        buffer: already_read,
+        pos: 0,
+    };
+    let mut body = Vec::new();
+
+    // Read chunks until we hit the zero-length last chunk
+    loop {
+        let size = parse_chunk_size(&reader.read_line(MAX_CHUNK_LINE_SIZE).await?)?;
+        if size == 0 {
+            break;
+        }
+        if size > max_body_size.saturating_sub(body.len()) {
+            return Err(Error::TooLarge);
+        }
+        body.extend_from_slice(reader.read_exact(size).await?);
+        if reader.read_exact(2).await? != b"\r\n" {
+            return Err(Error::Malformed("missing CRLF after chunk data"));
+        }
+    }
+
+    // Read the trailer section, which ends with an empty line
+    let mut trailer_block = Vec::new();
+    loop {
+        let line = reader.read_line(MAX_TRAILERS_SIZE).await?;
+        if line.is_empty() {
+            break;
+        }
+        trailer_block.extend_from_slice(&line);
+        trailer_block.extend_from_slice(b"\r\n");
+        if trailer_block.len() > MAX_TRAILERS_SIZE {
+            return Err(Error::Malformed("trailers too long"));
+        }
+    }
+    let mut trailers = http::HeaderMap::new();
+    if !trailer_block.is_empty() {
+        trailer_block.extend_from_slice(b"\r\n");
+        let mut parsed = [httparse::EMPTY_HEADER; MAX_NUM_TRAILERS];
+        match httparse::parse_headers(&trailer_block, &mut parsed) {
+            Ok(httparse::Status::Complete((_, parsed))) => {
+                for trailer in parsed {
+                    trailers.append(
+                        http::HeaderName::from_bytes(trailer.name.as_bytes())
+                            .map_err(|_| Error::Malformed("invalid trailer name"))?,
+                        http::HeaderValue::from_bytes(trailer.value)
+                            .map_err(|_| Error::Malformed("invalid trailer value"))?,
+                    );
+                }
+            }
+            _ => return Err(Error::Malformed("invalid trailers")),
+        }
+    }
+
+    // We don't support pipelining, so the peer shouldn't have sent anything past the body
+    if reader.pos != reader.buffer.len() {
+        return Err(Error::Malformed("unexpected data after chunked body"));
+    }
+    Ok((body, Trailers(trailers)))
+}
+
+/// Writes a body to the stream using the chunked transfer coding, followed by the given trailers.
+pub async fn write_body<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    body: &[u8],
+    trailers: Option<&Trailers>,
+) -> Result<(), std::io::Error> {
+    if !body.is_empty() {
+        stream
+            .write_all(format!("{:x}\r\n", body.len()).as_bytes())
+            .await?;
+        stream.write_all(body).await?;
+        stream.write_all(b"\r\n").await?;
+    }
+    stream.write_all(b"0\r\n").await?;
+    if let Some(Trailers(trailers)) = trailers {
+        for (name, value) in trailers {
+            stream.write_all(format!("{}: ", name).as_bytes()).await?;
+            stream.write_all(value.as_bytes()).await?;
+            stream.write_all(b"\r\n").await?;
+        }
+    }
+    stream.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+/// Passes a chunked body from `reader` to `writer` one piece at a time, so that a body of any size
+/// can go through with only a small buffer. The chunk framing is checked and re-encoded (dropping
+/// any chunk extensions) and the trailers are passed along as-is. `already_read` holds any bytes
+/// after the headers that were read from the stream along with them.
+pub async fn copy_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    already_read: Vec<u8>,
+) -> Result<(), body::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    pass_body(reader, writer, already_read, true).await
+}
+
+/// Like `copy_body`, but only the chunk data is written, without the chunk framing or trailers.
+pub async fn decode_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    already_read: Vec<u8>,
+) -> Result<(), body::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    pass_body(reader, writer, already_read, false).await
+}
+
+async fn pass_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    already_read: Vec<u8>,
+    keep_framing: bool,
+) -> Result<(), body::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = ChunkReader {
+        stream: reader,
+        buffer: already_read,
+        pos: 0,
+    };
+
+    // Pass chunks along until we hit the zero-length last chunk
+    loop {
+        let size = parse_chunk_size(&reader.read_line(MAX_CHUNK_LINE_SIZE).await?)?;
+        if keep_framing {
+            writer
+                .write_all(format!("{:x}\r\n", size).as_bytes())
+                .await
+                .map_err(body::Error::Write)?;
+        }
+        if size == 0 {
+            break;
+        }
+        let mut remaining = size;
+        while remaining > 0 {
+            let data = reader.read_some(remaining).await?;
+            remaining -= data.len();
+            writer.write_all(data).await.map_err(body::Error::Write)?;
+        }
+        if reader.read_exact(2).await? != b"\r\n" {
+            return Err(Error::Malformed("missing CRLF after chunk data").into());
+        }
+        if keep_framing {
+            writer
+                .write_all(b"\r\n")
+                .await
+                .map_err(body::Error::Write)?;
+        }
+    }
+
+    // Pass the trailer section along, up to and including the empty line that ends it
+    let mut trailers_size = 0;
+    loop {
+        let line = reader.read_line(MAX_TRAILERS_SIZE).await?;
+        trailers_size += line.len() + 2;
+        if trailers_size > MAX_TRAILERS_SIZE {
+            return Err(Error::Malformed("trailers too long").into());
+        }
+        if !line.is_empty() && !is_valid_trailer(&line) {
+            return Err(Error::Malformed("invalid trailers").into());
+        }
+        if keep_framing {
+            writer
+                .write_all(&[&line[..], b"\r\n"].concat())
+                .await
+                .map_err(body::Error::Write)?;
+        }
+        if line.is_empty() {
+            break;
+        }
+    }
+
+    if reader.pos != reader.buffer.len() {
+        return Err(Error::Malformed("unexpected data after chunked body").into());
+    }
+    Ok(())
+}
+
+/// Encodes whatever is written to it with the chunked transfer coding, for bodies whose length
+/// isn't known up front. Writes are gathered into chunks of up to 16 KiB; flushing writes out what
+/// has been gathered so far. Shutting the writer down writes the last chunk, but leaves the
+/// underlying stream open.
+pub struct ChunkedWriter<W> {
+    inner: W,
+    /// Data waiting to be sent as a chunk
+    data: Vec<u8>,
+    /// Framed bytes waiting to be written to `inner`, and how many of them have been written
+    out: Vec<u8>,
+    out_pos: usize,
+    finished: bool,
+}
+
+impl<W: AsyncWrite + Unpin> ChunkedWriter<W> {
+    pub fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter {
+            inner,
+            data: Vec::new(),
+            out: Vec::new(),
+            out_pos: 0,
+            finished: false,
+        }
+    }
+
+    /// Moves the gathered data into the output as a chunk.
+    fn frame(&mut self) {
+        if !self.data.is_empty() {
+            self.out
+                .extend_from_slice(format!("{:x}\r\n", self.data.len()).as_bytes());
+            self.out.append(&mut self.data);
+            self.out.extend_from_slice(b"\r\n");
+        }
+    }
+
+    fn poll_write_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.out_pos < self.out.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out[self.out_pos..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out_pos += written;
+        }
+        self.out.clear();
+        self.out_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ChunkedWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        if this.data.len() >= MAX_WRITTEN_CHUNK_SIZE {
+            this.frame();
+            ready!(this.poll_write_out(cx))?;
+        }
+        let len = min(buf.len(), MAX_WRITTEN_CHUNK_SIZE - this.data.len());
+        this.data.extend_from_slice(&buf[..len]);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.frame();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.frame();
+        if !this.finished {
+            this.out.extend_from_slice(b"0\r\n\r\n");
+            this.finished = true;
+        }
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+}
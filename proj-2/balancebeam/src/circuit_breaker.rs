@@ -0,0 +1,279 @@
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// When circuit breakers open, and how they recover.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BreakerSettings {
+    /// Open after this many 5xx responses in a row (0 = never)
+    pub consecutive_5xx: usize,
+    /// Open once this percentage of the last `window` requests failed (0 = never)
+    pub error_rate: usize,
+    /// How many recent requests the error rate is worked out over
+    pub window: usize,
+    /// How long an open breaker turns requests away before letting trial requests through
+    pub cooldown: Duration,
+    /// How many trial requests in a row have to succeed for a half-open breaker to close
+    pub half_open_requests: usize,
+}
+
+/// The state of one upstream's circuit breaker.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests flow normally
+    Closed,
+    /// The upstream has been failing, so no requests are sent to it until the cooldown is over
+    Open,
+    /// The cooldown is over, and a few trial requests are being let through to see whether the
+    /// upstream has recovered
+    HalfOpen,
+}
+
+/// How a request sent to an upstream turned out, as far as its circuit breaker is concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The upstream responded with anything other than a 5xx error
+    Success,
+    /// The upstream responded with a 5xx error
+    ServerError,
+    /// The upstream couldn't be connected to, or didn't respond at all. This opens the breaker
+    /// straight away.
+    Failure,
+}
+
+struct Breaker {
+    state: CircuitState,
+    /// Whether each of the most recent requests failed, oldest first (only tracked while closed)
+    recent: VecDeque<bool>,
+    consecutive_5xx: usize,
+    /// When the breaker last opened
+    opened_at: Instant,
+    /// Trial requests that are still waiting for a response while half-open
+    trials_in_flight: usize,
+    /// Trial requests that have succeeded since the breaker went half-open
+    trial_successes: usize,
+}
+
+impl Breaker {
+    fn new() -> Breaker {
+        Breaker {
+            state: CircuitState::Closed,
+            recent: VecDeque::new(),
+            consecutive_5xx: 0,
+            opened_at: Instant::now(),
+            trials_in_flight: 0,
+            trial_successes: 0,
+        }
+    }
+
+    /// Opens the breaker. Like `close`, this keeps count of trial requests that are still running,
+    /// since those give their slot back when they finish.
+    fn open(&mut self, address: &str) {
+        if self.state != CircuitState::Open {
+            log::warn!("Circuit breaker for upstream {} is open", address);
+        }
+        *self = Breaker {
+            state: CircuitState::Open,
+            opened_at: Instant::now(),
+            trials_in_flight: self.trials_in_flight,
+            ..Breaker::new()
+        };
+    }
+
+    fn close(&mut self, address: &str) {
+        if self.state != CircuitState::Closed {
+            log::info!("Circuit breaker for upstream {} is closed", address);
+        }
+        *self = Breaker {
+            trials_in_flight: self.trials_in_flight,
+            ..Breaker::new()
+        };
+    }
+
+    /// Moves an open breaker to half-open once its cooldown is over.
+    fn refresh(&mut self, address: &str, cooldown: Duration) {
+        if self.state == CircuitState::Open && self.opened_at.elapsed() >= cooldown {
+            log::info!(
+                "Circuit breaker for upstream {} is half-open; sending trial requests",
+                address
+            );
+            self.state = CircuitState::HalfOpen;
+        }
+    }
+
+    fn admits(&self, settings: &BreakerSettings) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                self.trial_successes + self.trials_in_flight < settings.half_open_requests
+            }
+        }
+    }
+}
+
+/// A circuit breaker for each upstream, which stops requests from being sent to an upstream that
+/// keeps failing until it has had some time to recover.
+pub struct CircuitBreakers {
+    settings: BreakerSettings,
+    breakers: Mutex<HashMap<String, Breaker>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(settings: BreakerSettings) -> CircuitBreakers {
+        CircuitBreakers {
+            settings,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if these breakers use the given settings, in which case they can be kept
+    /// (along with their state) across a configuration reload.
+    pub fn has_settings(&self, settings: &BreakerSettings) -> bool {
+        self.settings == *settings
+    }
+
+    /// Returns true if a request could be sent to the upstream right now.
+    pub fn is_available(&self, address: &str) -> bool {
+        let mut breakers = self.breakers.lock();
+        match breakers.get_mut(address) {
+            Some(breaker) => {
+                breaker.refresh(address, self.settings.cooldown);
+                breaker.admits(&self.settings)
+            }
+            None => true,
+        }
+    }
+
+    /// Lets a request through to the upstream if its breaker allows it. The outcome of the request
+    /// should be recorded with the returned permit.
+    pub fn try_admit(self: &Arc<Self>, address: &str) -> Option<Permit> {
+        let mut breakers = self.breakers.lock();
+        let breaker = breakers
+            .entry(address.to_string())
+            .or_insert_with(Breaker::new);
+        breaker.refresh(address, self.settings.cooldown);
+        if !breaker.admits(&self.settings) {
+            return None;
+        }
+        let trial = breaker.state == CircuitState::HalfOpen;
+        if trial {
+            breaker.trials_in_flight += 1;
+        }
+        Some(Permit {
+            breakers: self.clone(),
+            address: address.to_string(),
+            trial,
+            recorded: false,
+        })
+    }
+
+    pub fn state(&self, address: &str) -> CircuitState {
+        let mut breakers = self.breakers.lock();
+        match breakers.get_mut(address) {
+            Some(breaker) => {
+                breaker.refresh(address, self.settings.cooldown);
+                breaker.state
+            }
+            None => CircuitState::Closed,
+        }
+    }
+
+    /// Closes the upstream's breaker, e.g. because the active health checker found that the
+    /// upstream has recovered.
+    pub fn reset(&self, address: &str) {
+        if let Some(breaker) = self.breakers.lock().get_mut(address) {
+            breaker.close(address);
+        }
+    }
+
+    /// Forgets about an upstream that has been removed.
+    pub fn remove(&self, address: &str) {
+        self.breakers.lock().remove(address);
+    }
+
+    fn record(&self, address: &str, trial: bool, outcome: Option<Outcome>) {
+        let settings = &self.settings;
+        let mut breakers = self.breakers.lock();
+        let breaker = match breakers.get_mut(address) {
+            Some(breaker) => breaker,
+            None => return,
+        };
+        if trial {
+            breaker.trials_in_flight = breaker.trials_in_flight.saturating_sub(1);
+        }
+        let outcome = match outcome {
+            Some(outcome) => outcome,
+            None => return,
+        };
+
+        match breaker.state {
+            CircuitState::Closed => {
+                if outcome == Outcome::Failure {
+                    breaker.open(address);
+                    return;
+                }
+                if outcome == Outcome::ServerError {
+                    breaker.consecutive_5xx += 1;
+                } else {
+                    breaker.consecutive_5xx = 0;
+                }
+                breaker.recent.push_back(outcome != Outcome::Success);
+                if breaker.recent.len() > settings.window {
+                    breaker.recent.pop_front();
+                }
+                let failures = breaker.recent.iter().filter(|&&failed| failed).count();
+                let too_many_5xx = settings.consecutive_5xx > 0
+                    && breaker.consecutive_5xx >= settings.consecutive_5xx;
+                let error_rate_too_high = settings.error_rate > 0
+                    && breaker.recent.len() == settings.window
+                    && failures * 100 >= settings.error_rate * settings.window;
+                if too_many_5xx || error_rate_too_high {
+                    breaker.open(address);
+                }
+            }
+            // Requests that were let through before the breaker opened don't count; only the
+            // trial requests decide whether it closes again
+            CircuitState::HalfOpen if trial => {
+                if outcome != Outcome::Success {
+                    breaker.open(address);
+                    return;
+                }
+                breaker.trial_successes += 1;
+                if breaker.trial_successes >= settings.half_open_requests {
+                    breaker.close(address);
+                }
+            }
+            CircuitState::HalfOpen | CircuitState::Open => {}
+        }
+    }
+}
+
+/// Permission to send one request to an upstream. Dropping the permit without recording an
+/// outcome (e.g. because the client hung up) doesn't count for or against the upstream.
+pub struct Permit {
+    breakers: Arc<CircuitBreakers>,
+    address: String,
+    /// Whether this is one of the trial requests of a half-open breaker
+    trial: bool,
+    recorded: bool,
+}
+
+impl Permit {
+    pub fn record(mut self, outcome: Outcome) {
+        self.recorded = true;
+        self.breakers
+            .record(&self.address, self.trial, Some(outcome));
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breakers.record(&self.address, self.trial, None);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use crate::body::Framing;
+use async_compression::tokio::write::{BrotliEncoder, GzipEncoder};
+use async_compression::Level;
+use http::header::{self, HeaderMap, HeaderValue};
+use std::io;
+use std::pin::Pin;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Content types compressed unless others are given. A type ending in `/*` matches every subtype.
+pub const DEFAULT_TYPES: [&str; 5] = [
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "image/svg+xml",
+];
+
+/// Content types never compressed unless others are given. Server-sent events have to reach the
+/// client as they are sent, which compression would get in the way of.
+pub const DEFAULT_EXCLUDED_TYPES: [&str; 1] = ["text/event-stream"];
+
+/// Brotli's highest qualities are far too slow to use on every response
+const BROTLI_QUALITY: i32 = 4;
+
+/// Which responses to compress.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompressionSettings {
+    /// Don't compress bodies smaller than this many bytes, which wouldn't get much smaller
+    pub min_size: u64,
+    /// Content types to compress
+    pub types: Vec<String>,
+    /// Content types not to compress, even if `types` matches them
+    pub exclude: Vec<String>,
+}
+
+/// A content coding balancebeam can compress responses with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Wraps a writer so that whatever is written to it comes out compressed. The writer must be
+    /// shut down to finish the compressed stream.
+    pub fn encoder<'a, W: AsyncWrite + Unpin + Send + 'a>(
+        &self,
+        writer: W,
+    ) -> Pin<Box<dyn AsyncWrite + Send + 'a>> {
+        match self {
+            Encoding::Brotli => Box::pin(BrotliEncoder::with_quality(
+                writer,
+                Level::Precise(BROTLI_QUALITY),
+            )),
+            Encoding::Gzip => Box::pin(GzipEncoder::new(writer)),
+        }
+    }
+}
+
+/// Picks the encoding to compress a response with, or returns None if it should be sent as it is:
+/// the client has to accept one of our encodings, the upstream must not have encoded the body
+/// already, and the body has to be of a compressible type and big enough to be worth it.
+/// `framing` is how the body that is still to be sent is framed.
+pub fn choose_encoding(
+    settings: &CompressionSettings,
+    request: &http::Request<Vec<u8>>,
+    response: &http::Response<Vec<u8>>,
+    framing: Framing,
+) -> Option<Encoding> {
+    let headers = response.headers();
+    let size_ok = match framing {
+        Framing::Empty => false,
+        Framing::ContentLength(len) => len > 0 && len as u64 >= settings.min_size,
+        Framing::Chunked | Framing::UntilClose => true,
+    };
+    let encoded = headers
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding.as_bytes() != b"identity");
+    let no_transform = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+    if !size_ok
+        || encoded
+        || no_transform
+        // Ranges would refer to the compressed body, which we don't keep
+        || response.status() == http::StatusCode::PARTIAL_CONTENT
+        || !is_compressible(settings, headers.get(header::CONTENT_TYPE))
+    {
+        return None;
+    }
+    accepted_encoding(request.headers())
+}
+
+fn is_compressible(settings: &CompressionSettings, content_type: Option<&HeaderValue>) -> bool {
+    let content_type = match content_type.and_then(|value| value.to_str().ok()) {
+        Some(content_type) => content_type,
+        None => return false,
+    };
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let matches = |pattern: &String| match pattern.strip_suffix('*') {
+        Some(prefix) => media_type.starts_with(&prefix.to_ascii_lowercase()),
+        None => media_type.eq_ignore_ascii_case(pattern),
+    };
+    settings.types.iter().any(matches) && !settings.exclude.iter().any(matches)
+}
+
+/// Picks the encoding the client prefers from its Accept-Encoding header (RFC 9110 section
+/// 12.5.3). Brotli wins a tie, since it compresses better.
+fn accepted_encoding(headers: &HeaderMap) -> Option<Encoding> {
+    let mut brotli = None;
+    let mut gzip = None;
+    let mut any = None;
+    let codings = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for coding in codings {
+        let mut params = coding.split(';');
+        let name = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match name.as_str() {
+            "br" => brotli = Some(quality),
+            "gzip" | "x-gzip" => gzip = Some(quality),
+            "*" => any = Some(quality),
+            _ => {}
+        }
+    }
+    let brotli = brotli.or(any).unwrap_or(0.0);
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    if brotli <= 0.0 && gzip <= 0.0 {
+        None
+    } else if brotli >= gzip {
+        Some(Encoding::Brotli)
+    } else {
+        Some(Encoding::Gzip)
+    }
+}
+
+/// Updates a response's headers for its body being compressed: the length is no longer known, the
+/// response now varies on Accept-Encoding, and an ETag can only be a weak one, since the bytes
+/// differ from the upstream's.
+pub fn mark_compressed(headers: &mut HeaderMap, encoding: Encoding) {
+    headers.remove(header::CONTENT_LENGTH);
+    headers.remove(header::ACCEPT_RANGES);
+    headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    let varies = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !varies {
+        headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+    if let Some(etag) = headers.get(header::ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let weak = [&b"W/"[..], etag.as_bytes()].concat();
+            if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                headers.insert(header::ETAG, weak);
+            }
+        }
+    }
+}
+
+/// Compresses a response whose whole body is in memory, giving it the new Content-Length.
+pub async fn compress_response(
+    response: &mut http::Response<Vec<u8>>,
+    encoding: Encoding,
+) -> io::Result<()> {
+    let mut compressed = Vec::new();
+    let mut encoder = encoding.encoder(&mut compressed);
+    encoder.write_all(response.body()).await?;
+    encoder.shutdown().await?;
+    drop(encoder);
+    mark_compressed(response.headers_mut(), encoding);
+    response
+        .headers_mut()
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+    *response.body_mut() = compressed;
+    Ok(())
+}
@@ -0,0 +1,979 @@
+use crate::access_log::{AccessLog, AccessLogFormat, AccessLogSettings};
+use crate::affinity::{self, AffinityMode};
+use crate::balancing::{StrategyKind, Upstream};
+use crate::cache::{CacheSettings, ResponseCache};
+use crate::circuit_breaker::BreakerSettings;
+use crate::compress::{self, CompressionSettings};
+use crate::headers::{FieldName, HeaderField, HeaderRules};
+use crate::rate_limiter::RateLimitStrategy;
+use crate::routing::{PoolSettings, Route, DEFAULT_POOL};
+use crate::tcp::ProxyMode;
+use crate::tls::{self, SniCert, TlsSettings, UpstreamTlsSettings};
+use crate::{CmdOptions, SharedState};
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The configuration file could not be read
+    Io(std::io::Error),
+    /// The configuration file is not valid TOML, or has unknown or mistyped settings
+    Parse(toml::de::Error),
+    /// The configuration parsed, but the settings don't make sense (e.g. no upstreams)
+    Invalid(String),
+    /// A TLS certificate or key could not be loaded
+    Tls(tls::Error),
+    /// The access log could not be opened
+    AccessLog(std::io::Error),
+    /// The response cache's directory could not be set up
+    Cache(std::io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "could not read configuration file: {}", err),
+            Error::Parse(err) => write!(f, "could not parse configuration file: {}", err),
+            Error::Invalid(reason) => write!(f, "invalid configuration: {}", reason),
+            Error::Tls(err) => write!(f, "could not load TLS certificates: {}", err),
+            Error::AccessLog(err) => write!(f, "could not open access log: {}", err),
+            Error::Cache(err) => write!(f, "could not open response cache: {}", err),
+        }
+    }
+}
+
+/// The layout of the configuration file. Every setting is optional; anything left out falls back
+/// to the value given on the command line (or its default). For example:
+///
+/// ```toml
+/// bind = "0.0.0.0:1100"
+/// mode = "http"
+/// strategy = "weighted"
+/// affinity = "cookie"
+/// affinity_cookie = "backend"
+/// max_retries = 1
+///
+/// [[upstream]]
+/// address = "10.0.0.1:80"
+/// weight = 3
+///
+/// [[upstream]]
+/// address = "10.0.0.2:80"
+///
+/// [[upstream]]
+/// address = "https://backend.internal:443"
+///
+/// # Requests that match a route go to its pool; anything else goes to the upstreams above (or
+/// # gets a 404 if there aren't any)
+/// [[pool]]
+/// name = "api"
+/// strategy = "least-connections"
+/// affinity = "ip-hash"
+/// health_check_path = "/api/health"
+///
+/// [[pool.upstream]]
+/// address = "10.0.1.1:8080"
+///
+/// [[pool.upstream]]
+/// address = "10.0.1.2:8080"
+///
+/// [[route]]
+/// host = "api.example.com"
+/// pool = "api"
+///
+/// [[route]]
+/// path_prefix = "/api/"
+/// method = "GET"
+/// headers = { "X-Api-Version" = "2" }
+/// pool = "api"
+///
+/// [health_check]
+/// path = "/healthz"
+/// interval = 5
+/// timeout = 2
+/// healthy_threshold = 2
+/// unhealthy_threshold = 3
+///
+/// [circuit_breaker]
+/// consecutive_5xx = 3
+/// error_rate = 25
+/// window = 40
+/// cooldown = 30
+/// half_open_requests = 5
+///
+/// [rate_limit]
+/// max_requests_per_minute = 600
+/// strategy = "sliding"
+///
+/// [connection_pool]
+/// max_idle = 32
+/// idle_timeout = 30
+///
+/// [access_log]
+/// path = "/var/log/balancebeam/access.log"
+/// format = "json"
+/// max_size = 104857600
+/// max_files = 10
+///
+/// [cache]
+/// size = 268435456
+/// max_object_size = 4194304
+/// dir = "/var/cache/balancebeam"
+///
+/// [compression]
+/// enabled = true
+/// min_size = 512
+/// types = ["text/*", "application/json"]
+/// exclude = ["text/event-stream"]
+///
+/// [headers.request]
+/// set = ["X-Environment: production"]
+/// remove = ["X-Debug"]
+///
+/// [headers.response]
+/// add = ["Strict-Transport-Security: max-age=31536000"]
+/// remove = ["Server", "X-Powered-By"]
+///
+/// [timeouts]
+/// upstream_connect = 5
+/// client_header = 10
+/// body = 30
+/// upstream_response = 30
+/// client_idle = 15
+/// tunnel_idle = 600
+/// drain = 20
+///
+/// [tls]
+/// cert = "/etc/balancebeam/default.pem"
+/// key = "/etc/balancebeam/default.key"
+///
+/// [[tls.sni]]
+/// hostname = "api.example.com"
+/// cert = "/etc/balancebeam/api.pem"
+/// key = "/etc/balancebeam/api.key"
+///
+/// [upstream_tls]
+/// ca = "/etc/balancebeam/internal-ca.pem"
+/// client_cert = "/etc/balancebeam/client.pem"
+/// client_key = "/etc/balancebeam/client.key"
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    bind: Option<String>,
+    mode: Option<ProxyMode>,
+    upstream: Option<Vec<UpstreamConfig>>,
+    strategy: Option<StrategyKind>,
+    affinity: Option<AffinityMode>,
+    affinity_cookie: Option<String>,
+    max_retries: Option<usize>,
+    pool: Option<Vec<PoolConfig>>,
+    route: Option<Vec<Route>>,
+    #[serde(default)]
+    health_check: HealthCheckConfig,
+    #[serde(default)]
+    circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+    #[serde(default)]
+    connection_pool: ConnectionPoolConfig,
+    #[serde(default)]
+    access_log: AccessLogConfig,
+    #[serde(default)]
+    cache: CacheConfig,
+    #[serde(default)]
+    compression: CompressionConfig,
+    #[serde(default)]
+    headers: HeadersConfig,
+    #[serde(default)]
+    timeouts: TimeoutsConfig,
+    #[serde(default)]
+    tls: TlsConfig,
+    #[serde(default)]
+    upstream_tls: UpstreamTlsConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamConfig {
+    address: String,
+    #[serde(default = "default_weight")]
+    weight: usize,
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PoolConfig {
+    name: String,
+    strategy: Option<StrategyKind>,
+    affinity: Option<AffinityMode>,
+    health_check_path: Option<String>,
+    upstream: Vec<UpstreamConfig>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct HealthCheckConfig {
+    path: Option<String>,
+    interval: Option<usize>,
+    timeout: Option<usize>,
+    healthy_threshold: Option<usize>,
+    unhealthy_threshold: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct CircuitBreakerConfig {
+    consecutive_5xx: Option<usize>,
+    error_rate: Option<usize>,
+    window: Option<usize>,
+    cooldown: Option<usize>,
+    half_open_requests: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RateLimitConfig {
+    max_requests_per_minute: Option<usize>,
+    strategy: Option<RateLimitStrategy>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConnectionPoolConfig {
+    max_idle: Option<usize>,
+    idle_timeout: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct AccessLogConfig {
+    path: Option<PathBuf>,
+    format: Option<AccessLogFormat>,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct CacheConfig {
+    size: Option<u64>,
+    max_object_size: Option<u64>,
+    dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct CompressionConfig {
+    enabled: Option<bool>,
+    min_size: Option<u64>,
+    types: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct HeadersConfig {
+    #[serde(default)]
+    request: HeaderRulesConfig,
+    #[serde(default)]
+    response: HeaderRulesConfig,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct HeaderRulesConfig {
+    add: Option<Vec<HeaderField>>,
+    set: Option<Vec<HeaderField>>,
+    remove: Option<Vec<FieldName>>,
+}
+
+impl HeaderRulesConfig {
+    /// Replaces whichever of the rules are given here.
+    fn apply_to(self, rules: &mut HeaderRules) {
+        if let Some(add) = self.add {
+            rules.add = add;
+        }
+        if let Some(set) = self.set {
+            rules.set = set;
+        }
+        if let Some(remove) = self.remove {
+            rules.remove = remove;
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct TimeoutsConfig {
+    upstream_connect: Option<usize>,
+    client_header: Option<usize>,
+    body: Option<usize>,
+    upstream_response: Option<usize>,
+    client_idle: Option<usize>,
+    tunnel_idle: Option<usize>,
+    drain: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct TlsConfig {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    sni: Option<Vec<SniCert>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct UpstreamTlsConfig {
+    ca: Option<PathBuf>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+}
+
+/// All of the settings balancebeam runs with, after combining the command line with the
+/// configuration file (if any).
+#[derive(Clone)]
+pub struct Config {
+    pub bind: String,
+    /// Upstreams in every pool, including the default one
+    pub upstreams: Vec<Upstream>,
+    /// Named pools, not including the default one
+    pub pools: Vec<PoolSettings>,
+    pub routes: Vec<Route>,
+    pub mode: ProxyMode,
+    /// The load balancing strategy, if one was given. See `strategy()` for the default.
+    pub strategy: Option<StrategyKind>,
+    pub affinity: AffinityMode,
+    pub affinity_cookie: String,
+    pub max_retries: usize,
+    pub breaker_consecutive_5xx: usize,
+    pub breaker_error_rate: usize,
+    pub breaker_window: usize,
+    pub breaker_cooldown: usize,
+    pub breaker_half_open_requests: usize,
+    pub active_health_check_interval: usize,
+    pub active_health_check_path: String,
+    pub active_health_check_timeout: usize,
+    pub healthy_threshold: usize,
+    pub unhealthy_threshold: usize,
+    pub max_requests_per_minute: usize,
+    pub rate_limit_strategy: RateLimitStrategy,
+    pub max_idle_connections: usize,
+    pub idle_connection_timeout: usize,
+    pub access_log_path: Option<PathBuf>,
+    pub access_log_format: AccessLogFormat,
+    pub access_log_max_size: u64,
+    pub access_log_max_files: usize,
+    /// Opened from the `access_log_*` settings when the configuration is loaded
+    pub access_log: Option<Arc<AccessLog>>,
+    pub cache_size: u64,
+    pub cache_max_object_size: u64,
+    pub cache_dir: Option<PathBuf>,
+    /// Opened from the `cache_*` settings when the configuration is loaded
+    pub cache: Option<Arc<ResponseCache>>,
+    pub compress: bool,
+    pub compress_min_size: u64,
+    pub compress_types: Vec<String>,
+    pub compress_exclude: Vec<String>,
+    pub request_headers: HeaderRules,
+    pub response_headers: HeaderRules,
+    pub upstream_connect_timeout: usize,
+    pub client_header_timeout: usize,
+    pub body_timeout: usize,
+    pub upstream_response_timeout: usize,
+    pub client_idle_timeout: usize,
+    pub tunnel_idle_timeout: usize,
+    pub drain_timeout: usize,
+    pub tls: TlsSettings,
+    /// Built from `tls` when the configuration is loaded, so that bad certificates are caught
+    /// before the configuration is used
+    pub tls_acceptor: Option<TlsAcceptor>,
+    pub upstream_tls: UpstreamTlsSettings,
+    /// Built from `upstream_tls` when the configuration is loaded
+    pub upstream_tls_connector: Option<TlsConnector>,
+}
+
+impl Config {
+    /// Builds a configuration from the command line alone.
+    pub fn from_options(options: &CmdOptions) -> Config {
+        Config {
+            bind: options.bind.clone(),
+            upstreams: options.upstream.clone(),
+            pools: Vec::new(),
+            routes: Vec::new(),
+            mode: options.mode,
+            strategy: options.strategy,
+            affinity: options.affinity,
+            affinity_cookie: options.affinity_cookie.clone(),
+            max_retries: options.max_retries,
+            breaker_consecutive_5xx: options.breaker_consecutive_5xx,
+            breaker_error_rate: options.breaker_error_rate,
+            breaker_window: options.breaker_window,
+            breaker_cooldown: options.breaker_cooldown,
+            breaker_half_open_requests: options.breaker_half_open_requests,
+            active_health_check_interval: options.active_health_check_interval,
+            active_health_check_path: options.active_health_check_path.clone(),
+            active_health_check_timeout: options.active_health_check_timeout,
+            healthy_threshold: options.healthy_threshold,
+            unhealthy_threshold: options.unhealthy_threshold,
+            max_requests_per_minute: options.max_requests_per_minute,
+            rate_limit_strategy: options.rate_limit_strategy,
+            max_idle_connections: options.max_idle_connections,
+            idle_connection_timeout: options.idle_connection_timeout,
+            access_log_path: options.access_log.clone(),
+            access_log_format: options.access_log_format,
+            access_log_max_size: options.access_log_max_size,
+            access_log_max_files: options.access_log_max_files,
+            access_log: None,
+            cache_size: options.cache_size,
+            cache_max_object_size: options.cache_max_object_size,
+            cache_dir: options.cache_dir.clone(),
+            cache: None,
+            compress: options.compress,
+            compress_min_size: options.compress_min_size,
+            compress_types: or_defaults(&options.compress_type, &compress::DEFAULT_TYPES),
+            compress_exclude: or_defaults(
+                &options.compress_exclude,
+                &compress::DEFAULT_EXCLUDED_TYPES,
+            ),
+            request_headers: HeaderRules {
+                add: options.request_header_add.clone(),
+                set: options.request_header_set.clone(),
+                remove: options.request_header_remove.clone(),
+            },
+            response_headers: HeaderRules {
+                add: options.response_header_add.clone(),
+                set: options.response_header_set.clone(),
+                remove: options.response_header_remove.clone(),
+            },
+            upstream_connect_timeout: options.upstream_connect_timeout,
+            client_header_timeout: options.client_header_timeout,
+            body_timeout: options.body_timeout,
+            upstream_response_timeout: options.upstream_response_timeout,
+            client_idle_timeout: options.client_idle_timeout,
+            tunnel_idle_timeout: options.tunnel_idle_timeout,
+            drain_timeout: options.drain_timeout,
+            tls: TlsSettings {
+                cert: options.tls_cert.clone(),
+                key: options.tls_key.clone(),
+                sni: options.tls_sni_cert.clone(),
+            },
+            tls_acceptor: None,
+            upstream_tls: UpstreamTlsSettings {
+                ca: options.upstream_ca.clone(),
+                client_cert: options.upstream_client_cert.clone(),
+                client_key: options.upstream_client_key.clone(),
+            },
+            upstream_tls_connector: None,
+        }
+    }
+
+    /// The load balancing strategy for pools that don't set their own. Unless one was given, TCP
+    /// connections go to the upstream with the fewest of them, since they can be long-lived.
+    pub fn strategy(&self) -> StrategyKind {
+        self.strategy.unwrap_or(match self.mode {
+            ProxyMode::Http => StrategyKind::Random,
+            ProxyMode::Tcp => StrategyKind::LeastConnections,
+        })
+    }
+
+    pub fn breaker_settings(&self) -> BreakerSettings {
+        BreakerSettings {
+            consecutive_5xx: self.breaker_consecutive_5xx,
+            error_rate: self.breaker_error_rate,
+            window: self.breaker_window,
+            cooldown: Duration::from_secs(self.breaker_cooldown as u64),
+            half_open_requests: self.breaker_half_open_requests,
+        }
+    }
+
+    pub fn access_log_settings(&self) -> Option<AccessLogSettings> {
+        Some(AccessLogSettings {
+            path: self.access_log_path.clone()?,
+            format: self.access_log_format,
+            max_size: self.access_log_max_size,
+            max_files: self.access_log_max_files,
+        })
+    }
+
+    /// Settings for the response cache, or None if it's disabled.
+    pub fn cache_settings(&self) -> Option<CacheSettings> {
+        (self.cache_size > 0).then(|| CacheSettings {
+            max_size: self.cache_size,
+            max_object_size: self.cache_max_object_size,
+            dir: self.cache_dir.clone(),
+        })
+    }
+
+    /// Settings for response compression, or None if it's disabled.
+    pub fn compression_settings(&self) -> Option<CompressionSettings> {
+        self.compress.then(|| CompressionSettings {
+            min_size: self.compress_min_size,
+            types: self.compress_types.clone(),
+            exclude: self.compress_exclude.clone(),
+        })
+    }
+
+    /// Builds the configuration to run with: the command-line settings, overridden by whatever is
+    /// in the configuration file at `path` (if one was given), and then validated.
+    pub fn load(options: &CmdOptions, path: Option<&Path>) -> Result<Config, Error> {
+        let mut config = Config::from_options(options);
+        if let Some(path) = path {
+            let contents = std::fs::read_to_string(path).map_err(Error::Io)?;
+            let file: ConfigFile = toml::from_str(&contents).map_err(Error::Parse)?;
+            config.apply(file);
+        }
+        config.validate()?;
+        let http2 = config.mode == ProxyMode::Http;
+        config.tls_acceptor = tls::build_acceptor(&config.tls, http2).map_err(Error::Tls)?;
+        config.upstream_tls_connector =
+            Some(tls::build_connector(&config.upstream_tls).map_err(Error::Tls)?);
+        if let Some(settings) = config.access_log_settings() {
+            let access_log = AccessLog::open(&settings).map_err(Error::AccessLog)?;
+            config.access_log = Some(Arc::new(access_log));
+        }
+        if let Some(settings) = config.cache_settings() {
+            let cache = ResponseCache::open(&settings).map_err(Error::Cache)?;
+            config.cache = Some(Arc::new(cache));
+        }
+        Ok(config)
+    }
+
+    fn apply(&mut self, file: ConfigFile) {
+        if let Some(bind) = file.bind {
+            self.bind = bind;
+        }
+        if let Some(upstreams) = file.upstream {
+            self.upstreams = upstreams
+                .into_iter()
+                .map(|upstream| Upstream::new(&upstream.address, upstream.weight))
+                .collect();
+        }
+        for pool in file.pool.unwrap_or_default() {
+            self.upstreams
+                .extend(pool.upstream.iter().map(|upstream| Upstream {
+                    pool: pool.name.clone(),
+                    ..Upstream::new(&upstream.address, upstream.weight)
+                }));
+            self.pools.push(PoolSettings {
+                name: pool.name,
+                strategy: pool.strategy,
+                affinity: pool.affinity,
+                health_check_path: pool.health_check_path,
+            });
+        }
+        if let Some(routes) = file.route {
+            self.routes = routes;
+        }
+        if let Some(mode) = file.mode {
+            self.mode = mode;
+        }
+        if let Some(strategy) = file.strategy {
+            self.strategy = Some(strategy);
+        }
+        if let Some(affinity) = file.affinity {
+            self.affinity = affinity;
+        }
+        if let Some(affinity_cookie) = file.affinity_cookie {
+            self.affinity_cookie = affinity_cookie;
+        }
+        if let Some(max_retries) = file.max_retries {
+            self.max_retries = max_retries;
+        }
+        let circuit_breaker = file.circuit_breaker;
+        if let Some(consecutive_5xx) = circuit_breaker.consecutive_5xx {
+            self.breaker_consecutive_5xx = consecutive_5xx;
+        }
+        if let Some(error_rate) = circuit_breaker.error_rate {
+            self.breaker_error_rate = error_rate;
+        }
+        if let Some(window) = circuit_breaker.window {
+            self.breaker_window = window;
+        }
+        if let Some(cooldown) = circuit_breaker.cooldown {
+            self.breaker_cooldown = cooldown;
+        }
+        if let Some(half_open_requests) = circuit_breaker.half_open_requests {
+            self.breaker_half_open_requests = half_open_requests;
+        }
+        let health_check = file.health_check;
+        if let Some(path) = health_check.path {
+            self.active_health_check_path = path;
+        }
+        if let Some(interval) = health_check.interval {
+            self.active_health_check_interval = interval;
+        }
+        if let Some(timeout) = health_check.timeout {
+            self.active_health_check_timeout = timeout;
+        }
+        if let Some(healthy_threshold) = health_check.healthy_threshold {
+            self.healthy_threshold = healthy_threshold;
+        }
+        if let Some(unhealthy_threshold) = health_check.unhealthy_threshold {
+            self.unhealthy_threshold = unhealthy_threshold;
+        }
+        if let Some(max_requests_per_minute) = file.rate_limit.max_requests_per_minute {
+            self.max_requests_per_minute = max_requests_per_minute;
+        }
+        if let Some(strategy) = file.rate_limit.strategy {
+            self.rate_limit_strategy = strategy;
+        }
+        if let Some(max_idle) = file.connection_pool.max_idle {
+            self.max_idle_connections = max_idle;
+        }
+        if let Some(idle_timeout) = file.connection_pool.idle_timeout {
+            self.idle_connection_timeout = idle_timeout;
+        }
+        let access_log = file.access_log;
+        if let Some(path) = access_log.path {
+            self.access_log_path = Some(path);
+        }
+        if let Some(format) = access_log.format {
+            self.access_log_format = format;
+        }
+        if let Some(max_size) = access_log.max_size {
+            self.access_log_max_size = max_size;
+        }
+        if let Some(max_files) = access_log.max_files {
+            self.access_log_max_files = max_files;
+        }
+        let cache = file.cache;
+        if let Some(size) = cache.size {
+            self.cache_size = size;
+        }
+        if let Some(max_object_size) = cache.max_object_size {
+            self.cache_max_object_size = max_object_size;
+        }
+        if let Some(dir) = cache.dir {
+            self.cache_dir = Some(dir);
+        }
+        let compression = file.compression;
+        if let Some(enabled) = compression.enabled {
+            self.compress = enabled;
+        }
+        if let Some(min_size) = compression.min_size {
+            self.compress_min_size = min_size;
+        }
+        if let Some(types) = compression.types {
+            self.compress_types = types;
+        }
+        if let Some(exclude) = compression.exclude {
+            self.compress_exclude = exclude;
+        }
+        file.headers.request.apply_to(&mut self.request_headers);
+        file.headers.response.apply_to(&mut self.response_headers);
+        let timeouts = file.timeouts;
+        if let Some(upstream_connect) = timeouts.upstream_connect {
+            self.upstream_connect_timeout = upstream_connect;
+        }
+        if let Some(client_header) = timeouts.client_header {
+            self.client_header_timeout = client_header;
+        }
+        if let Some(body) = timeouts.body {
+            self.body_timeout = body;
+        }
+        if let Some(upstream_response) = timeouts.upstream_response {
+            self.upstream_response_timeout = upstream_response;
+        }
+        if let Some(client_idle) = timeouts.client_idle {
+            self.client_idle_timeout = client_idle;
+        }
+        if let Some(tunnel_idle) = timeouts.tunnel_idle {
+            self.tunnel_idle_timeout = tunnel_idle;
+        }
+        if let Some(drain) = timeouts.drain {
+            self.drain_timeout = drain;
+        }
+        if let Some(cert) = file.tls.cert {
+            self.tls.cert = Some(cert);
+        }
+        if let Some(key) = file.tls.key {
+            self.tls.key = Some(key);
+        }
+        if let Some(sni) = file.tls.sni {
+            self.tls.sni = sni;
+        }
+        if let Some(ca) = file.upstream_tls.ca {
+            self.upstream_tls.ca = Some(ca);
+        }
+        if let Some(client_cert) = file.upstream_tls.client_cert {
+            self.upstream_tls.client_cert = Some(client_cert);
+        }
+        if let Some(client_key) = file.upstream_tls.client_key {
+            self.upstream_tls.client_key = Some(client_key);
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.upstreams.is_empty() {
+            return Err(Error::Invalid(
+                "at least one upstream server must be specified using the --upstream option or \
+                the configuration file"
+                    .to_string(),
+            ));
+        }
+        if self.mode == ProxyMode::Tcp {
+            if !self.pools.is_empty() || !self.routes.is_empty() {
+                return Err(Error::Invalid(
+                    "pools and routes can't be used in TCP mode".to_string(),
+                ));
+            }
+            if self.affinity == AffinityMode::Cookie {
+                return Err(Error::Invalid(
+                    "cookie affinity can't be used in TCP mode".to_string(),
+                ));
+            }
+            if self.cache_size > 0 {
+                return Err(Error::Invalid(
+                    "the response cache can't be used in TCP mode".to_string(),
+                ));
+            }
+            if self.compress {
+                return Err(Error::Invalid(
+                    "compression can't be used in TCP mode".to_string(),
+                ));
+            }
+            if !self.request_headers.is_empty() || !self.response_headers.is_empty() {
+                return Err(Error::Invalid(
+                    "header rules can't be used in TCP mode".to_string(),
+                ));
+            }
+        }
+        for upstream in &self.upstreams {
+            if upstream.weight == 0 {
+                return Err(Error::Invalid(format!(
+                    "weight for upstream {} must be at least 1",
+                    upstream.address
+                )));
+            }
+            if let Some(other) = self
+                .upstreams
+                .iter()
+                .find(|other| other.address == upstream.address && other.pool != upstream.pool)
+            {
+                return Err(Error::Invalid(format!(
+                    "upstream {} is in both the {} and {} pools",
+                    upstream.address, upstream.pool, other.pool
+                )));
+            }
+        }
+        for (idx, pool) in self.pools.iter().enumerate() {
+            if pool.name == DEFAULT_POOL || self.pools[..idx].iter().any(|p| p.name == pool.name) {
+                return Err(Error::Invalid(format!(
+                    "pool name \"{}\" is already taken",
+                    pool.name
+                )));
+            }
+            if !self
+                .upstreams
+                .iter()
+                .any(|upstream| upstream.pool == pool.name)
+            {
+                return Err(Error::Invalid(format!(
+                    "pool {} has no upstreams",
+                    pool.name
+                )));
+            }
+            if let Some(path) = &pool.health_check_path {
+                if !path.starts_with('/') {
+                    return Err(Error::Invalid(format!(
+                        "health check path \"{}\" for pool {} must start with /",
+                        path, pool.name
+                    )));
+                }
+            }
+        }
+        for route in &self.routes {
+            if route.pool != DEFAULT_POOL && !self.pools.iter().any(|pool| pool.name == route.pool)
+            {
+                return Err(Error::Invalid(format!(
+                    "route refers to unknown pool {}",
+                    route.pool
+                )));
+            }
+            if let Some(method) = &route.method {
+                if http::Method::from_bytes(method.as_bytes()).is_err() {
+                    return Err(Error::Invalid(format!(
+                        "route method \"{}\" is not a valid HTTP method",
+                        method
+                    )));
+                }
+            }
+            if let Some(path_prefix) = &route.path_prefix {
+                if !path_prefix.starts_with('/') {
+                    return Err(Error::Invalid(format!(
+                        "route path prefix \"{}\" must start with /",
+                        path_prefix
+                    )));
+                }
+            }
+        }
+        if !affinity::is_valid_cookie_name(&self.affinity_cookie) {
+            return Err(Error::Invalid(format!(
+                "\"{}\" is not a valid cookie name",
+                self.affinity_cookie
+            )));
+        }
+        if self.breaker_error_rate > 100 {
+            return Err(Error::Invalid(format!(
+                "circuit breaker error rate {}% is over 100%",
+                self.breaker_error_rate
+            )));
+        }
+        if self.breaker_window == 0
+            || self.breaker_cooldown == 0
+            || self.breaker_half_open_requests == 0
+        {
+            return Err(Error::Invalid(
+                "circuit breaker window, cooldown and half-open requests must be at least 1"
+                    .to_string(),
+            ));
+        }
+        if self.active_health_check_interval == 0 {
+            return Err(Error::Invalid(
+                "health check interval must be at least 1 second".to_string(),
+            ));
+        }
+        if !self.active_health_check_path.starts_with('/') {
+            return Err(Error::Invalid(format!(
+                "health check path \"{}\" must start with /",
+                self.active_health_check_path
+            )));
+        }
+        if self.healthy_threshold == 0 || self.unhealthy_threshold == 0 {
+            return Err(Error::Invalid(
+                "health check thresholds must be at least 1".to_string(),
+            ));
+        }
+        if self.idle_connection_timeout == 0 {
+            return Err(Error::Invalid(
+                "idle connection timeout must be at least 1 second".to_string(),
+            ));
+        }
+        for rules in [&self.request_headers, &self.response_headers] {
+            if let Some(name) = rules.protected_header() {
+                return Err(Error::Invalid(format!(
+                    "the {} header can't be changed by header rules",
+                    name
+                )));
+            }
+        }
+        if self.access_log_max_files == 0 {
+            return Err(Error::Invalid(
+                "at least one rotated access log must be kept".to_string(),
+            ));
+        }
+        let timeouts = [
+            self.upstream_connect_timeout,
+            self.client_header_timeout,
+            self.body_timeout,
+            self.upstream_response_timeout,
+            self.client_idle_timeout,
+            self.tunnel_idle_timeout,
+            self.drain_timeout,
+        ];
+        if timeouts.contains(&0) {
+            return Err(Error::Invalid(
+                "timeouts must be at least 1 second".to_string(),
+            ));
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            return Err(Error::Invalid(
+                "a TLS certificate and key must be given together".to_string(),
+            ));
+        }
+        if self.upstream_tls.client_cert.is_some() != self.upstream_tls.client_key.is_some() {
+            return Err(Error::Invalid(
+                "an upstream client certificate and key must be given together".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Re-reads the configuration file every time balancebeam receives SIGHUP, and swaps the new
+/// settings into the shared state. Connections that are already being handled keep the settings
+/// they started with. If the new configuration is invalid, the error is logged and the current
+/// configuration stays in effect.
+pub async fn reload_on_sighup(
+    options: CmdOptions,
+    path: PathBuf,
+    mut current: Config,
+    shared_state: SharedState,
+) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            log::error!(
+                "Could not listen for SIGHUP, configuration reloading is disabled: {}",
+                err
+            );
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        log::info!(
+            "Received SIGHUP, reloading configuration from {}",
+            path.display()
+        );
+        let mut config = match Config::load(&options, Some(&path)) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("Keeping the current configuration: {}", err);
+                continue;
+            }
+        };
+        if config.bind != current.bind {
+            log::warn!(
+                "Changing the bind address requires a restart; still listening on {}",
+                current.bind
+            );
+            config.bind = current.bind.clone();
+        }
+
+        let old_state = shared_state.read().clone();
+        {
+            // Upstreams that are still configured keep whatever health status they had, and newly
+            // added ones are assumed to be live until the health checker says otherwise
+            let mut live = old_state.live_upstream.write().await;
+            let new_live = config
+                .upstreams
+                .iter()
+                .filter(|upstream| {
+                    live.iter().any(|live| live.address == upstream.address)
+                        || !current
+                            .upstreams
+                            .iter()
+                            .any(|old| old.address == upstream.address)
+                })
+                .cloned()
+                .collect();
+            *live = new_live;
+        }
+        *shared_state.write() = old_state.reconfigure(&config);
+        log::info!(
+            "Configuration reloaded; proxying to {} upstreams",
+            config.upstreams.len()
+        );
+        current = config;
+    }
+}
+
+/// Returns the given values, or the defaults if none were given.
+fn or_defaults(values: &[String], defaults: &[&str]) -> Vec<String> {
+    match values.is_empty() {
+        true => defaults.iter().map(|value| value.to_string()).collect(),
+        false => values.to_vec(),
+    }
+}
@@ -0,0 +1,121 @@
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::signal::{kill, Signal};
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use tokio::net::TcpListener;
+use tokio::process::Command;
+
+/// Environment variable used to pass listening sockets to the new process during an upgrade, as
+/// a comma-separated list of ADDRESS=FD pairs. The address is the one the socket was bound to
+/// (e.g. the `--bind` address), so that the new process can tell which socket is which.
+const INHERITED_FDS_VAR: &str = "BALANCEBEAM_INHERITED_FDS";
+
+/// Binds the addresses balancebeam listens on, taking over sockets from the process being
+/// upgraded where it passed them on.
+pub struct Listeners {
+    /// Sockets inherited from the old process that haven't been claimed yet
+    inherited: HashMap<String, RawFd>,
+    /// Every socket bound so far, to pass on in the next upgrade
+    bound: Vec<(String, RawFd)>,
+    upgraded: bool,
+}
+
+impl Listeners {
+    /// Picks up any sockets passed on by the process being upgraded.
+    pub fn from_env() -> Listeners {
+        let inherited: HashMap<String, RawFd> = std::env::var(INHERITED_FDS_VAR)
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|pair| pair.rsplit_once('='))
+            .filter_map(|(address, fd)| Some((address.to_string(), fd.parse().ok()?)))
+            .collect();
+        std::env::remove_var(INHERITED_FDS_VAR);
+        // Don't let the sockets leak into any other processes we start
+        for &fd in inherited.values() {
+            let _ = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC));
+        }
+        Listeners {
+            upgraded: !inherited.is_empty(),
+            inherited,
+            bound: Vec::new(),
+        }
+    }
+
+    /// Returns a listener for `address`, reusing the old process's socket if it passed one on.
+    pub async fn bind(&mut self, address: &str) -> io::Result<TcpListener> {
+        let listener = match self.inherited.remove(address) {
+            Some(fd) => {
+                log::info!("Taking over the listening socket for {}", address);
+                // Safety: the old process passed this fd on for us alone, and nothing else in this
+                // process has claimed it
+                let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => TcpListener::bind(address).await?,
+        };
+        self.bound.push((address.to_string(), listener.as_raw_fd()));
+        Ok(listener)
+    }
+
+    /// Closes any inherited sockets that the new configuration doesn't listen on, and if this
+    /// process is an upgrade, tells the old process that it can start shutting down. Returns the
+    /// sockets to pass on in the next upgrade.
+    pub fn finish(self) -> Vec<(String, RawFd)> {
+        for (address, fd) in self.inherited {
+            log::info!("No longer listening on {}", address);
+            // Safety: as in bind()
+            drop(unsafe { std::net::TcpListener::from_raw_fd(fd) });
+        }
+        if self.upgraded {
+            let parent = nix::unistd::getppid();
+            log::info!("Took over from process {}, asking it to shut down", parent);
+            if let Err(err) = kill(parent, Signal::SIGTERM) {
+                log::error!("Could not ask process {} to shut down: {}", parent, err);
+            }
+        }
+        self.bound
+    }
+}
+
+/// Starts a new balancebeam process from the current executable, with the same arguments, handing
+/// it the given listening sockets. Once the new process is listening, it sends us SIGTERM and we
+/// drain as usual, so no connection is ever refused.
+pub fn upgrade(listeners: &[(String, RawFd)]) -> io::Result<()> {
+    let executable = std::env::current_exe()?;
+    // The listeners are close-on-exec, so pass on copies that aren't
+    let mut passed = Vec::new();
+    let mut inherited_fds = Vec::new();
+    for (address, fd) in listeners {
+        let copy = nix::unistd::dup(*fd)?;
+        passed.push(copy);
+        inherited_fds.push(format!("{}={}", address, copy));
+    }
+    let spawned = Command::new(&executable)
+        .args(std::env::args_os().skip(1))
+        .env(INHERITED_FDS_VAR, inherited_fds.join(","))
+        .spawn();
+    for fd in passed {
+        let _ = nix::unistd::close(fd);
+    }
+    let mut child = spawned?;
+    log::info!(
+        "Started new balancebeam process {} from {}",
+        child.id().unwrap_or_default(),
+        executable.display()
+    );
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if !status.success() => {
+                log::error!(
+                    "New balancebeam process failed ({}), upgrade aborted",
+                    status
+                )
+            }
+            Ok(_) => {}
+            Err(err) => log::error!("Could not wait for new balancebeam process: {}", err),
+        }
+    });
+    Ok(())
+}
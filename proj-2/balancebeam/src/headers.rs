@@ -0,0 +1,229 @@
+use crate::request;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Header that identifies a request to the upstream, and is echoed back to the client.
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// Longest request ID accepted from a client. Anything longer is replaced with a new one.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Headers that only mean something to a single connection, and are never passed on (RFC 7230
+/// section 6.1). Transfer-Encoding and Trailer are missing because bodies are relayed with the
+/// framing they arrived with.
+const HOP_BY_HOP: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "upgrade",
+];
+
+/// Headers that say where a message's body ends. They can't be named in the Connection header or
+/// changed by header rules, since the body is relayed as it arrived.
+const PROTECTED: [&str; 3] = ["content-length", "trailer", "transfer-encoding"];
+
+/// A header with its value, given as `Name: value`.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct HeaderField {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl FromStr for HeaderField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HeaderField, String> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected NAME: VALUE but got \"{}\"", s))?;
+        Ok(HeaderField {
+            name: parse_name(name.trim())?,
+            value: HeaderValue::from_str(value.trim())
+                .map_err(|_| format!("\"{}\" is not a valid header value", value.trim()))?,
+        })
+    }
+}
+
+impl TryFrom<String> for HeaderField {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<HeaderField, String> {
+        s.parse()
+    }
+}
+
+/// The name of a header to remove.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct FieldName(pub HeaderName);
+
+impl FromStr for FieldName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FieldName, String> {
+        parse_name(s.trim()).map(FieldName)
+    }
+}
+
+impl TryFrom<String> for FieldName {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<FieldName, String> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for FieldName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+fn parse_name(name: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("\"{}\" is not a valid header name", name))
+}
+
+/// Changes made to the headers of every request or response that passes through. Headers are
+/// removed first, then set (replacing any values they had), then added (alongside any values they
+/// already had).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderRules {
+    pub add: Vec<HeaderField>,
+    pub set: Vec<HeaderField>,
+    pub remove: Vec<FieldName>,
+}
+
+impl HeaderRules {
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.set.is_empty() && self.remove.is_empty()
+    }
+
+    /// Returns the first header named by a rule that rules aren't allowed to change, if any. That
+    /// includes the hop-by-hop headers, which are balancebeam's to manage.
+    pub fn protected_header(&self) -> Option<&HeaderName> {
+        let fields = self.add.iter().chain(&self.set).map(|field| &field.name);
+        fields
+            .chain(self.remove.iter().map(|name| &name.0))
+            .find(|name| PROTECTED.contains(&name.as_str()) || HOP_BY_HOP.contains(&name.as_str()))
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for name in &self.remove {
+            headers.remove(&name.0);
+        }
+        for field in &self.set {
+            headers.insert(field.name.clone(), field.value.clone());
+        }
+        for field in &self.add {
+            headers.append(field.name.clone(), field.value.clone());
+        }
+    }
+}
+
+/// Removes the hop-by-hop headers, along with any headers the Connection header lists, so that
+/// they aren't passed on to the next hop. If `upgrade` is true, the message is switching protocols
+/// (or asking to), so the Upgrade header and the Connection option that goes with it are kept.
+pub fn remove_hop_by_hop(headers: &mut HeaderMap, upgrade: bool) {
+    let upgrade = match upgrade {
+        true => headers.get(header::UPGRADE).cloned(),
+        false => None,
+    };
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .flat_map(|value| {
+            String::from_utf8_lossy(value.as_bytes())
+                .split(',')
+                .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    for name in listed {
+        if !PROTECTED.contains(&name.as_str()) {
+            headers.remove(name);
+        }
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+    if let Some(upgrade) = upgrade {
+        headers.insert(header::UPGRADE, upgrade);
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+}
+
+/// Makes sure the request has an X-Request-Id, keeping the one the client sent if it looks
+/// reasonable and generating a new one otherwise. Returns the ID.
+pub fn assign_request_id(headers: &mut HeaderMap) -> HeaderValue {
+    if let Some(id) = headers.get(REQUEST_ID) {
+        let printable = id.as_bytes().iter().all(|b| b.is_ascii_graphic());
+        if printable && !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN {
+            return id.clone();
+        }
+    }
+    let id = HeaderValue::from_str(&generate_request_id()).unwrap();
+    headers.insert(REQUEST_ID, id.clone());
+    id
+}
+
+/// Returns a random (version 4) UUID.
+fn generate_request_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Tells the upstream about the client the request came from and how it reached us, since the
+/// upstream only sees balancebeam's connection. The client's address is added to the end of
+/// X-Forwarded-For and Forwarded, while X-Forwarded-Proto and X-Forwarded-Host are replaced, since
+/// balancebeam is the one that knows what the client connected with. `proto` is "http" or "https".
+pub fn add_forwarding_headers(request: &mut http::Request<Vec<u8>>, client: IpAddr, proto: &str) {
+    request::extend_header_value(request, "x-forwarded-for", &client.to_string());
+    let headers = request.headers_mut();
+    headers.insert("x-forwarded-proto", HeaderValue::from_str(proto).unwrap());
+    let host = headers.get(header::HOST).cloned();
+    match &host {
+        Some(host) => headers.insert("x-forwarded-host", host.clone()),
+        None => headers.remove("x-forwarded-host"),
+    };
+
+    // RFC 7239 section 4
+    let node = match client {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    };
+    let mut forwarded = format!("for={};proto={}", forwarded_value(&node), proto);
+    if let Some(host) = host.as_ref().and_then(|host| host.to_str().ok()) {
+        forwarded += &format!(";host={}", forwarded_value(host));
+    }
+    request::extend_header_value(request, "forwarded", &forwarded);
+}
+
+/// Quotes a Forwarded parameter value, unless it's a token that can go in as it is.
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    match is_token {
+        true => value.to_string(),
+        false => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
@@ -0,0 +1,200 @@
+use crate::balancing::{Upstream, UpstreamStatus};
+use crate::tcp::ProxyMode;
+use crate::{request, response, upstream, ProxyState, SharedState};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsConnector;
+
+/// What the active health checker currently believes about one upstream.
+struct UpstreamHealth {
+    healthy: bool,
+    consecutive_passes: usize,
+    consecutive_failures: usize,
+}
+
+impl UpstreamHealth {
+    /// Records the result of a probe, flipping the upstream between healthy and unhealthy once
+    /// enough consecutive probes agree. Returns true if the upstream's health changed.
+    fn record(&mut self, passed: bool, state: &ProxyState) -> bool {
+        if passed {
+            self.consecutive_passes += 1;
+            self.consecutive_failures = 0;
+            if !self.healthy && self.consecutive_passes >= state.healthy_threshold {
+                self.healthy = true;
+                return true;
+            }
+        } else {
+            self.consecutive_failures += 1;
+            self.consecutive_passes = 0;
+            if self.healthy && self.consecutive_failures >= state.unhealthy_threshold {
+                self.healthy = false;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Sends a GET request for the health check path to the given upstream and checks that it
+/// responds with 200 OK, connecting the same way requests are sent (over TLS for https://
+/// upstreams). In TCP mode, being able to connect is enough. Returns an error message describing
+/// why the probe failed, if it did.
+async fn probe(
+    upstream: &Upstream,
+    mode: ProxyMode,
+    path: &str,
+    connector: Option<&TlsConnector>,
+) -> Result<(), String> {
+    let mut stream = upstream::connect(upstream, connector)
+        .await
+        .map_err(|err| format!("failed to connect: {}", err))?;
+    if mode == ProxyMode::Tcp {
+        return Ok(());
+    }
+    let request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(path)
+        .header("Host", &upstream.address)
+        .body(Vec::new())
+        .unwrap();
+    request::write_to_stream(&request, &mut stream)
+        .await
+        .map_err(|err| format!("failed to send request: {}", err))?;
+    let response = response::read_from_stream(&mut stream, request.method())
+        .await
+        .map_err(|err| format!("failed to read response: {:?}", err))?;
+    if response.status() != http::StatusCode::OK {
+        return Err(format!(
+            "unexpected response {}",
+            response::format_response_line(&response)
+        ));
+    }
+    Ok(())
+}
+
+/// Probes every upstream at the same time, giving each probe at most `timeout` to finish. Returns
+/// whether each upstream passed.
+async fn probe_all(state: &ProxyState, timeout: Duration) -> HashMap<String, bool> {
+    let mut probes = JoinSet::new();
+    for upstream in &state.upstream_addresses {
+        let upstream = upstream.clone();
+        let path = state.router.health_check_path(&upstream.pool).to_string();
+        let connector = state.upstream_tls.clone();
+        let metrics = state.metrics.clone();
+        let mode = state.mode;
+        probes.spawn(async move {
+            let probe = probe(&upstream, mode, &path, connector.as_ref());
+            let result = match tokio::time::timeout(timeout, probe).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("timed out after {:?}", timeout)),
+            };
+            if let Err(err) = &result {
+                log::warn!(
+                    "Health check of upstream {} failed: {}",
+                    upstream.address,
+                    err
+                );
+            }
+            metrics.record_health_check(&upstream.address, result.is_ok());
+            (upstream.address, result.is_ok())
+        });
+    }
+
+    let mut results = HashMap::new();
+    while let Some(joined) = probes.join_next().await {
+        match joined {
+            Ok((address, passed)) => {
+                results.insert(address, passed);
+            }
+            Err(err) => log::error!("Health check task panicked: {}", err),
+        }
+    }
+    results
+}
+
+/// Periodically probes every upstream and updates the live upstream list accordingly. An upstream
+/// is taken out of rotation after `unhealthy_threshold` consecutive failed probes and put back
+/// after `healthy_threshold` consecutive successful ones.
+pub async fn active_health_check(shared_state: &SharedState) {
+    let mut health: HashMap<String, UpstreamHealth> = HashMap::new();
+
+    loop {
+        // Pick up any configuration changes since the last round
+        let interval = Duration::from_secs(shared_state.read().active_health_check_interval as u64);
+        tokio::time::sleep(interval).await;
+        let state = shared_state.read().clone();
+        let timeout = Duration::from_secs(state.active_health_check_timeout as u64);
+
+        // Upstreams start out live, so newly configured upstreams start out healthy. Forget about
+        // upstreams that are no longer configured.
+        health.retain(|address, _| {
+            state
+                .upstream_addresses
+                .iter()
+                .any(|upstream| &upstream.address == address)
+        });
+        for upstream in &state.upstream_addresses {
+            health
+                .entry(upstream.address.clone())
+                .or_insert(UpstreamHealth {
+                    healthy: true,
+                    consecutive_passes: 0,
+                    consecutive_failures: 0,
+                });
+        }
+
+        // Probe without holding the lock, so that connections can keep being proxied meanwhile
+        let was_live: HashSet<String> = state
+            .live_upstream
+            .read()
+            .await
+            .iter()
+            .map(|upstream| upstream.address.clone())
+            .collect();
+        let results = probe_all(&state, timeout).await;
+        for (address, &passed) in &results {
+            let upstream_health = health.get_mut(address).unwrap();
+            if upstream_health.record(passed, &state) {
+                if upstream_health.healthy {
+                    log::info!("Upstream {} is healthy again", address);
+                    // Don't make the upstream wait out its circuit breaker's cooldown as well
+                    state.circuit_breakers.reset(address);
+                } else {
+                    log::error!("Upstream {} is unhealthy", address);
+                }
+            }
+        }
+
+        // Apply the results to the live list as it is now rather than replacing it, since the
+        // admin interface or a configuration reload may have changed it while we were probing.
+        // An upstream that left rotation during the round isn't put back until a later probe
+        // says it's healthy, and upstreams we haven't probed yet are left as they are.
+        let mut live = state.live_upstream.write().await;
+        let upstream_addresses = shared_state.read().upstream_addresses.clone();
+        let upstream_status = state.upstream_status.read();
+        let usable = |address: &str| {
+            let status = upstream_status.get(address).copied();
+            status.unwrap_or_default() == UpstreamStatus::Enabled
+                && health
+                    .get(address)
+                    .is_none_or(|upstream_health| upstream_health.healthy)
+        };
+        live.retain(|upstream| usable(&upstream.address));
+        for upstream in &upstream_addresses {
+            let restore = results.get(&upstream.address) == Some(&true)
+                && !was_live.contains(&upstream.address)
+                && !live.iter().any(|live| live.address == upstream.address);
+            if restore && usable(&upstream.address) {
+                live.push(upstream.clone());
+            }
+        }
+        // Keep upstreams in their configured order
+        live.sort_by_key(|live| {
+            upstream_addresses
+                .iter()
+                .position(|upstream| upstream.address == live.address)
+                .unwrap_or(usize::MAX)
+        });
+    }
+}
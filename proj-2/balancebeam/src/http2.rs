@@ -0,0 +1,644 @@
+use crate::access_log::{CountingWriter, RequestInfo};
+use crate::balancing::UpstreamStatus;
+use crate::cache::CacheStatus;
+use crate::compress;
+use crate::headers;
+use crate::shutdown::ShutdownHandle;
+use crate::timeout::ReadTimeout;
+use crate::upstream::UpstreamStream;
+use crate::{
+    body, exchange, fits_in_retry_buffer, lookup_cache, metrics, record_request,
+    request_body_error_status, response, rewrite_response_headers, route_request, wants_close,
+    CacheLookup, ExchangeError, ProxyState, RequestBody, UpstreamResponse,
+};
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::Instant;
+
+/// What a client sends first on an HTTP/2 connection (RFC 9113 section 3.4).
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Headers that only mean something to a single HTTP/1.1 connection, and must not be sent over
+/// HTTP/2 (RFC 9113 section 8.2.2).
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// A stream with the bytes that were read from it to check for the HTTP/2 preface put back in
+/// front, so that whichever protocol handles the connection sees it from the start.
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.prefix.len() {
+            let len = buf.remaining().min(this.prefix.len() - this.pos);
+            buf.put_slice(&this.prefix[this.pos..this.pos + len]);
+            this.pos += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Reads just enough of a plaintext connection to tell whether the client is starting HTTP/2
+/// without negotiating it first (h2c with prior knowledge). Returns true if it is, along with the
+/// connection to carry on with. Gives up if the client sends nothing for `timeout`.
+pub async fn sniff_preface<S: AsyncRead + Unpin>(
+    mut stream: S,
+    timeout: Duration,
+) -> io::Result<(bool, Rewind<S>)> {
+    let mut prefix = Vec::new();
+    let sniff = async {
+        let mut buf = [0_u8; PREFACE.len()];
+        // An HTTP/1.1 request line stops matching after a byte or two
+        while prefix.len() < PREFACE.len() && PREFACE.starts_with(&prefix) {
+            let bytes_read = stream
+                .read(&mut buf[..PREFACE.len() - prefix.len()])
+                .await?;
+            if bytes_read == 0 {
+                break;
+            }
+            prefix.extend_from_slice(&buf[..bytes_read]);
+        }
+        Ok::<_, io::Error>(())
+    };
+    tokio::time::timeout(timeout, sniff)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "client sent nothing"))??;
+    let is_http2 = prefix == PREFACE;
+    Ok((
+        is_http2,
+        Rewind {
+            prefix,
+            pos: 0,
+            inner: stream,
+        },
+    ))
+}
+
+/// Serves an HTTP/2 client connection. Each stream is handled on its own task as a separate
+/// HTTP/1.1 request to an upstream, so a stream that fails only gets an error response (or is
+/// reset) without affecting the others.
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    client_conn: S,
+    client_addr: SocketAddr,
+    state: &ProxyState,
+    mut shutdown: ShutdownHandle,
+) {
+    let client_ip = client_addr.ip();
+    log::info!("HTTP/2 connection received from {}", client_ip);
+    let _active_connection = state.metrics.connection_opened();
+
+    let handshake = h2::server::handshake(client_conn);
+    let mut connection = match tokio::time::timeout(state.timeouts.client_header, handshake).await {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(error)) => {
+            log::info!("HTTP/2 handshake with {} failed: {}", client_ip, error);
+            return;
+        }
+        Err(_) => {
+            log::info!("HTTP/2 handshake with {} timed out", client_ip);
+            return;
+        }
+    };
+
+    let state = Arc::new(state.clone());
+    let active_streams = Arc::new(AtomicUsize::new(0));
+    let idle = tokio::time::sleep(state.timeouts.client_idle);
+    tokio::pin!(idle);
+    let mut closing = false;
+    loop {
+        let accepted = tokio::select! {
+            accepted = connection.accept() => accepted,
+            // Stop taking new streams, but let the ones in flight finish
+            _ = shutdown.draining(), if !closing => {
+                log::debug!("Shutting down; closing HTTP/2 connection from {}", client_ip);
+                connection.graceful_shutdown();
+                closing = true;
+                continue;
+            }
+            _ = &mut idle, if !closing => {
+                if active_streams.load(Ordering::SeqCst) == 0 {
+                    log::debug!("HTTP/2 connection from {} was idle for too long", client_ip);
+                    connection.graceful_shutdown();
+                    closing = true;
+                } else {
+                    idle.as_mut().reset(Instant::now() + state.timeouts.client_idle);
+                }
+                continue;
+            }
+        };
+        match accepted {
+            Some(Ok((request, respond))) => {
+                idle.as_mut()
+                    .reset(Instant::now() + state.timeouts.client_idle);
+                let state = state.clone();
+                let active_streams = active_streams.clone();
+                active_streams.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    handle_stream(request, respond, client_ip, &state).await;
+                    active_streams.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Some(Err(error)) => {
+                log::info!("HTTP/2 connection from {} failed: {}", client_ip, error);
+                return;
+            }
+            None => {
+                log::debug!("Client finished sending requests. Shutting down connection");
+                return;
+            }
+        }
+    }
+}
+
+/// Turns the head of an HTTP/2 request into an HTTP/1.1 one: the target becomes origin-form, the
+/// authority becomes the Host header, and split Cookie headers are joined back together (RFC 9113
+/// section 8.2.3). The version is left as HTTP/2 so that the request is logged as such.
+fn to_http1_head(parts: http::request::Parts) -> http::Request<Vec<u8>> {
+    let mut request = http::Request::from_parts(parts, Vec::new());
+    if let Some(authority) = request.uri().authority().cloned() {
+        if !request.headers().contains_key(http::header::HOST) {
+            let host = http::HeaderValue::from_str(authority.as_str()).unwrap();
+            request.headers_mut().insert(http::header::HOST, host);
+        }
+    }
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "/".to_string());
+    *request.uri_mut() = path.parse().unwrap();
+    let cookies: Vec<Vec<u8>> = request
+        .headers()
+        .get_all(http::header::COOKIE)
+        .iter()
+        .map(|value| value.as_bytes().to_vec())
+        .collect();
+    if cookies.len() > 1 {
+        let joined = http::HeaderValue::from_bytes(&cookies.join(&b"; "[..])).unwrap();
+        request.headers_mut().insert(http::header::COOKIE, joined);
+    }
+    request
+}
+
+/// Sends a whole response on a stream, leaving out the headers HTTP/2 doesn't allow.
+fn send_response(respond: &mut SendResponse<Bytes>, response: http::Response<Vec<u8>>) {
+    let (parts, body) = response.into_parts();
+    let mut head = http::Response::from_parts(parts, ());
+    *head.version_mut() = http::Version::HTTP_2;
+    for name in CONNECTION_HEADERS {
+        head.headers_mut().remove(name);
+    }
+    if let Ok(mut stream) = respond.send_response(head, body.is_empty()) {
+        if !body.is_empty() {
+            let _ = stream.send_data(Bytes::from(body), true);
+        }
+    }
+}
+
+/// Sends an error response on a stream, recording it as the response to the request.
+fn send_error(
+    respond: &mut SendResponse<Bytes>,
+    request_info: &RequestInfo,
+    state: &ProxyState,
+    upstream: &str,
+    status: http::StatusCode,
+) {
+    let mut response = response::make_http_error(status);
+    if let Some(request_id) = &request_info.request_id {
+        response
+            .headers_mut()
+            .insert(headers::REQUEST_ID, request_id.clone());
+    }
+    let bytes = response.body().len() as u64;
+    record_request(state, request_info, upstream, status, bytes);
+    send_response(respond, response);
+}
+
+/// Proxies one HTTP/2 stream to an upstream as an HTTP/1.1 request.
+async fn handle_stream(
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    client_addr: IpAddr,
+    state: &ProxyState,
+) {
+    let client_ip = client_addr.to_string();
+    let (parts, request_body) = request.into_parts();
+    let mut request = to_http1_head(parts);
+    headers::assign_request_id(request.headers_mut());
+    let request_info = RequestInfo::new(client_addr, Some(&request));
+    *request.version_mut() = http::Version::HTTP_11;
+
+    if request.method() == http::Method::CONNECT {
+        let status = http::StatusCode::NOT_IMPLEMENTED;
+        send_error(
+            &mut respond,
+            &request_info,
+            state,
+            metrics::NO_UPSTREAM,
+            status,
+        );
+        return;
+    }
+
+    let (pool, affinity) = match route_request(state, client_addr, &request) {
+        Ok(route) => route,
+        Err(status) => {
+            send_error(
+                &mut respond,
+                &request_info,
+                state,
+                metrics::NO_UPSTREAM,
+                status,
+            );
+            return;
+        }
+    };
+    headers::remove_hop_by_hop(request.headers_mut(), false);
+    headers::add_forwarding_headers(&mut request, client_addr, state.client_proto());
+    state.request_headers.apply(request.headers_mut());
+
+    // Answer from the cache if it has a fresh response
+    let has_body = !request_body.is_end_stream();
+    let cache = match lookup_cache(state, &request_info, &mut request, has_body).await {
+        CacheLookup::Hit(mut response) => {
+            CacheStatus::Hit.add_header(response.headers_mut());
+            log::info!(
+                "{} <- {}",
+                client_ip,
+                response::format_response_line(&response)
+            );
+            let (status, bytes) = (response.status(), response.body().len() as u64);
+            record_request(state, &request_info, metrics::NO_UPSTREAM, status, bytes);
+            send_response(&mut respond, response);
+            return;
+        }
+        CacheLookup::Forward(cache) => cache,
+    };
+
+    // HTTP/2 frames the body itself, so the upstream needs to be told how it's framed unless the
+    // client gave a Content-Length
+    let content_length = request
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse().ok());
+    let framing = match (has_body, content_length) {
+        (false, _) => body::Framing::Empty,
+        (true, Some(len)) => body::Framing::ContentLength(len),
+        (true, None) => body::Framing::Chunked,
+    };
+    if framing == body::Framing::Chunked {
+        request.headers_mut().insert(
+            http::header::TRANSFER_ENCODING,
+            http::HeaderValue::from_static("chunked"),
+        );
+    }
+
+    // Small bodies are read in full first, so that the request can be sent again if need be
+    let mut stream_body = StreamBody {
+        recv: request_body,
+        timeout: state.timeouts.body,
+        buffered: None,
+        chunked: framing == body::Framing::Chunked,
+    };
+    if fits_in_retry_buffer(framing) {
+        let mut buffered = Vec::new();
+        if let Err(error) = stream_body.stream_to(&mut buffered).await {
+            log::info!("Error reading request body from client: {:?}", error);
+            if let Some(status) = request_body_error_status(&error) {
+                let upstream = metrics::NO_UPSTREAM;
+                send_error(&mut respond, &request_info, state, upstream, status);
+            }
+            return;
+        }
+        stream_body.buffered = Some(buffered);
+    }
+    let exchanged = exchange(
+        state,
+        pool,
+        &affinity,
+        &client_ip,
+        &request,
+        &mut stream_body,
+    );
+    let UpstreamResponse {
+        stream: mut upstream_conn,
+        guard: connection_guard,
+        mut response,
+        body: response_body,
+    } = match exchanged.await {
+        Ok(exchanged) => exchanged,
+        Err(ExchangeError::Upstream(upstream, status)) => {
+            send_error(&mut respond, &request_info, state, &upstream, status);
+            return;
+        }
+        Err(ExchangeError::RequestBody(upstream, error)) => {
+            log::info!("Error reading request body from client: {:?}", error);
+            if let Some(status) = request_body_error_status(&error) {
+                send_error(&mut respond, &request_info, state, &upstream, status);
+            }
+            return;
+        }
+    };
+    let upstream_ip = connection_guard.address().to_string();
+    let upstream_wants_close = wants_close(response.version(), response.headers());
+    headers::remove_hop_by_hop(response.headers_mut(), false);
+    // Switching protocols isn't possible on an HTTP/2 stream
+    if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+        log::warn!(
+            "Upstream {} switched protocols on an HTTP/2 request",
+            upstream_ip
+        );
+        let status = http::StatusCode::BAD_GATEWAY;
+        send_error(&mut respond, &request_info, state, &upstream_ip, status);
+        return;
+    }
+    // Whether the connection can be reused depends on how the body was framed, even if the body
+    // ends up being read for the cache
+    let until_close = response_body.framing() == body::Framing::UntilClose;
+    let mut response_body = Some(response_body);
+    let updated = cache
+        .update(
+            state,
+            &request,
+            &mut response,
+            &mut response_body,
+            &mut upstream_conn,
+        )
+        .await;
+    if let Err(error) = updated {
+        log::error!("Error reading response body from server: {:?}", error);
+        connection_guard
+            .counters()
+            .failures
+            .fetch_add(1, Ordering::SeqCst);
+        let status = http::StatusCode::BAD_GATEWAY;
+        send_error(&mut respond, &request_info, state, &upstream_ip, status);
+        return;
+    }
+
+    // Compress the body as it is forwarded if the client accepts it. HTTP/2 frames the body
+    // itself, so its compressed length doesn't have to be known up front.
+    let framing = match &response_body {
+        Some(response_body) => response_body.framing(),
+        None if response.body().is_empty() => body::Framing::Empty,
+        None => body::Framing::ContentLength(response.body().len()),
+    };
+    let encoding = state
+        .compression
+        .as_ref()
+        .and_then(|settings| compress::choose_encoding(settings, &request, &response, framing));
+    if let Some(encoding) = encoding {
+        compress::mark_compressed(response.headers_mut(), encoding);
+    }
+
+    // Forward the response to the client, streaming the body straight from the upstream
+    log::info!(
+        "{} <- {}",
+        client_ip,
+        response::format_response_line(&response)
+    );
+    let mut head = http::Response::builder()
+        .status(response.status())
+        .version(http::Version::HTTP_2)
+        .body(())
+        .unwrap();
+    for (name, value) in response.headers() {
+        if !CONNECTION_HEADERS.contains(&name.as_str()) {
+            head.headers_mut().append(name, value.clone());
+        }
+    }
+    if let Some(cookie) = affinity.set_cookie(&state.affinity_cookie, &upstream_ip) {
+        head.headers_mut().append(http::header::SET_COOKIE, cookie);
+    }
+    rewrite_response_headers(state, &request_info, head.headers_mut());
+    let no_body = framing == body::Framing::Empty;
+    let send_stream = match respond.send_response(head, no_body) {
+        Ok(send_stream) => send_stream,
+        Err(error) => {
+            log::info!("Client {} reset the stream: {}", client_ip, error);
+            record_request(state, &request_info, &upstream_ip, response.status(), 0);
+            return;
+        }
+    };
+    let mut writer = StreamWriter {
+        stream: send_stream,
+    };
+    let mut client_writer = CountingWriter::new(&mut writer);
+    let forwarded = match no_body {
+        true => Ok(()),
+        false => {
+            let mut upstream_reader = ReadTimeout::new(&mut upstream_conn, state.timeouts.body);
+            let mut encoder: Pin<Box<dyn AsyncWrite + Send>> = match encoding {
+                Some(encoding) => encoding.encoder(&mut client_writer),
+                None => Box::pin(&mut client_writer),
+            };
+            let copied = match response_body {
+                Some(response_body) => {
+                    response_body
+                        .copy_decoded(&mut upstream_reader, &mut encoder)
+                        .await
+                }
+                // The body has already been read for the cache
+                None => encoder
+                    .write_all(response.body())
+                    .await
+                    .map_err(body::Error::Write),
+            };
+            match copied {
+                Ok(()) => encoder.shutdown().await.map_err(body::Error::Write),
+                Err(error) => Err(error),
+            }
+        }
+    };
+    let bytes = client_writer.count;
+    record_request(state, &request_info, &upstream_ip, response.status(), bytes);
+    match forwarded {
+        Ok(()) => log::debug!("Forwarded response to client"),
+        Err(body::Error::Write(error)) => {
+            log::warn!("Failed to send response to client: {}", error);
+            return;
+        }
+        // We've already started sending the response, so all we can do is reset the stream
+        Err(error) => {
+            log::error!("Error reading response body from server: {:?}", error);
+            connection_guard
+                .counters()
+                .failures
+                .fetch_add(1, Ordering::SeqCst);
+            writer.stream.send_reset(h2::Reason::INTERNAL_ERROR);
+            return;
+        }
+    }
+
+    // Hand the upstream connection back to the pool for a later request, unless it asked to be
+    // closed, its body ran until it hung up, or it was drained or disabled through the admin
+    // interface
+    let status = state.upstream_status.read().get(&upstream_ip).copied();
+    if !until_close
+        && !upstream_wants_close
+        && status.unwrap_or_default() == UpstreamStatus::Enabled
+    {
+        state.pool.put(&upstream_ip, upstream_conn);
+    }
+}
+
+/// The body of a request on an HTTP/2 stream.
+struct StreamBody {
+    recv: RecvStream,
+    /// How long to wait for more of the body
+    timeout: Duration,
+    buffered: Option<Vec<u8>>,
+    /// Whether to send the body with the chunked transfer coding, for want of a Content-Length
+    chunked: bool,
+}
+
+impl StreamBody {
+    /// Passes the body on to `writer` as it arrives.
+    async fn stream_to<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+    ) -> Result<(), body::Error> {
+        loop {
+            let data = tokio::time::timeout(self.timeout, self.recv.data())
+                .await
+                .map_err(|_| {
+                    body::Error::Read(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no data received for {:?}", self.timeout),
+                    ))
+                })?;
+            let data = match data {
+                Some(data) => data.map_err(|error| body::Error::Read(h2_to_io(error)))?,
+                None => break,
+            };
+            // Let the client send more
+            let _ = self.recv.flow_control().release_capacity(data.len());
+            if data.is_empty() {
+                continue;
+            }
+            let write = async {
+                if self.chunked {
+                    let size = format!("{:x}\r\n", data.len());
+                    writer.write_all(size.as_bytes()).await?;
+                    writer.write_all(&data).await?;
+                    writer.write_all(b"\r\n").await
+                } else {
+                    writer.write_all(&data).await
+                }
+            };
+            write.await.map_err(body::Error::Write)?;
+        }
+        if self.chunked {
+            writer
+                .write_all(b"0\r\n\r\n")
+                .await
+                .map_err(body::Error::Write)?;
+        }
+        Ok(())
+    }
+}
+
+impl RequestBody for StreamBody {
+    fn buffered(&self) -> Option<&[u8]> {
+        self.buffered.as_deref()
+    }
+
+    async fn stream(&mut self, upstream: &mut UpstreamStream) -> Result<(), body::Error> {
+        self.stream_to(upstream).await
+    }
+}
+
+fn h2_to_io(error: h2::Error) -> io::Error {
+    match error.into_io() {
+        Some(error) => error,
+        None => io::Error::other("HTTP/2 stream error"),
+    }
+}
+
+/// Writes to an HTTP/2 stream as DATA frames, waiting for the client's flow control window as
+/// needed. Shutting the writer down ends the stream.
+struct StreamWriter {
+    stream: SendStream<Bytes>,
+}
+
+impl AsyncWrite for StreamWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        this.stream.reserve_capacity(buf.len());
+        while this.stream.capacity() == 0 {
+            match ready!(this.stream.poll_capacity(cx)) {
+                Some(Ok(_)) => {}
+                Some(Err(error)) => return Poll::Ready(Err(h2_to_io(error))),
+                None => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "client closed the stream",
+                    )))
+                }
+            }
+        }
+        let len = buf.len().min(this.stream.capacity());
+        this.stream
+            .send_data(Bytes::copy_from_slice(&buf[..len]), false)
+            .map_err(h2_to_io)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        Poll::Ready(this.stream.send_data(Bytes::new(), true).map_err(h2_to_io))
+    }
+}
@@ -1,33 +1,253 @@
+mod access_log;
+mod admin;
+mod affinity;
+mod balancing;
+mod body;
+mod cache;
+mod chunked;
+mod circuit_breaker;
+mod compress;
+mod config;
+mod handoff;
+mod headers;
+mod health_check;
+mod http2;
+mod metrics;
+mod pool;
+mod rate_limiter;
 mod request;
 mod response;
+mod routing;
+mod shutdown;
+mod tcp;
+mod timeout;
+mod tls;
+mod tunnel;
+mod upstream;
 
+use access_log::{AccessLog, AccessLogFormat, CountingWriter, RequestInfo};
+use affinity::{Affinity, AffinityMode};
+use balancing::{ConnectionGuard, StrategyKind, Upstream, UpstreamStats, UpstreamStatus};
+use cache::{CacheStatus, CachedResponse, ResponseCache};
+use chunked::ChunkedWriter;
+use circuit_breaker::{CircuitBreakers, Outcome, Permit};
 use clap::Parser;
-use rand::{Rng, SeedableRng};
-use request::write_to_stream;
-use response::read_from_stream;
-use std::{io::{self, Error, ErrorKind}, sync::Arc, time::Duration};
-use tokio::{net::{TcpListener, TcpStream}, stream, sync::RwLock, time::Instant};
+use compress::CompressionSettings;
+use config::Config;
+use handoff::Listeners;
+use headers::{FieldName, HeaderField, HeaderRules};
+use metrics::Metrics;
+use pool::ConnectionPool;
+use rate_limiter::{RateLimitStrategy, RateLimiter};
+use routing::{Pool, Router, DEFAULT_POOL};
+use shutdown::{Shutdown, ShutdownHandle, TerminationSignals};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::atomic::Ordering,
+    sync::Arc,
+    time::Duration,
+};
+use tcp::ProxyMode;
+use timeout::{ReadTimeout, Timeouts};
+use tls::SniCert;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    signal::unix::{signal, SignalKind},
+    sync::RwLock,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use upstream::UpstreamStream;
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(about = "Fun with load balancing")]
 struct CmdOptions {
+    /// "TOML configuration file. Settings in the file override the command line, and the file is
+    /// re-read when balancebeam receives SIGHUP"
+    #[arg(short, long)]
+    config: Option<PathBuf>,
     /// "IP/port to bind to"
     #[arg(short, long, default_value = "0.0.0.0:1100")]
     bind: String,
-    /// "Upstream host to forward requests to"
+    /// "IP/port to serve the JSON admin interface on (disabled if not given)"
+    #[arg(long)]
+    admin_bind: Option<String>,
+    /// "IP/port to serve Prometheus metrics on at /metrics (disabled if not given)"
+    #[arg(long)]
+    metrics_bind: Option<String>,
+    /// "Upstream host to forward requests to, optionally followed by @weight (e.g. host:port@3).
+    /// Prefix it with https:// to connect to the upstream over TLS"
     #[arg(short, long)]
-    upstream: Vec<String>,
+    upstream: Vec<Upstream>,
+    /// "Proxy HTTP requests, or pass raw TCP connections through to the upstreams"
+    #[arg(long, value_enum, default_value = "http")]
+    mode: ProxyMode,
+    /// "How to choose which upstream each request is sent to (defaults to random, or to
+    /// least-connections with --mode tcp)"
+    #[arg(long, value_enum)]
+    strategy: Option<StrategyKind>,
+    /// "How to keep each client's requests on the same upstream. If the upstream a client is pinned
+    /// to is down, its requests are balanced by --strategy instead"
+    #[arg(long, value_enum, default_value = "none")]
+    affinity: AffinityMode,
+    /// "Name of the cookie that pins clients to an upstream with --affinity cookie"
+    #[arg(long, default_value = "balancebeam_upstream")]
+    affinity_cookie: String,
+    /// "How many more upstreams to try when an idempotent request (or one with an Idempotency-Key
+    /// header) fails before a response arrives (0 = no retries)"
+    #[arg(long, default_value = "2")]
+    max_retries: usize,
+    /// "Stop sending requests to an upstream after this many 5xx responses in a row (0 = never)"
+    #[arg(long, default_value = "5")]
+    breaker_consecutive_5xx: usize,
+    /// "Stop sending requests to an upstream once this percentage of its recent requests failed
+    /// (0 = never)"
+    #[arg(long, default_value = "50")]
+    breaker_error_rate: usize,
+    /// "Number of recent requests that --breaker-error-rate is worked out over"
+    #[arg(long, default_value = "20")]
+    breaker_window: usize,
+    /// "Seconds to wait before sending trial requests to an upstream whose circuit breaker opened"
+    #[arg(long, default_value = "10")]
+    breaker_cooldown: usize,
+    /// "Number of trial requests that have to succeed before an upstream gets requests again"
+    #[arg(long, default_value = "3")]
+    breaker_half_open_requests: usize,
     /// "Perform active health checks on this interval (in seconds)"
     #[arg(long, default_value = "10")]
     active_health_check_interval: usize,
     /// "Path to send request to for active health checks"
     #[arg(long, default_value = "/")]
     active_health_check_path: String,
+    /// "Give up on an active health check probe after this many seconds"
+    #[arg(long, default_value = "5")]
+    active_health_check_timeout: usize,
+    /// "Number of consecutive successful health checks before an upstream is put back in rotation"
+    #[arg(long, default_value = "1")]
+    healthy_threshold: usize,
+    /// "Number of consecutive failed health checks before an upstream is taken out of rotation"
+    #[arg(long, default_value = "1")]
+    unhealthy_threshold: usize,
     /// "Maximum number of requests to accept per IP per minute (0 = unlimited)"
     #[arg(long, default_value = "0")]
     max_requests_per_minute: usize,
+    /// "How requests are counted against --max-requests-per-minute"
+    #[arg(long, value_enum, default_value = "fixed")]
+    rate_limit_strategy: RateLimitStrategy,
+    /// "Maximum number of idle keep-alive connections to keep open to each upstream (0 = no
+    /// connection reuse)"
+    #[arg(long, default_value = "16")]
+    max_idle_connections: usize,
+    /// "Close idle upstream connections after this many seconds"
+    #[arg(long, default_value = "60")]
+    idle_connection_timeout: usize,
+    /// "File to append an access log to, or - for stdout (disabled if not given)"
+    #[arg(long)]
+    access_log: Option<PathBuf>,
+    /// "Format of the access log"
+    #[arg(long, value_enum, default_value = "combined")]
+    access_log_format: AccessLogFormat,
+    /// "Rotate the access log once it reaches this many bytes (0 = never). Sending SIGUSR1 reopens
+    /// the log, for rotating it with an external tool instead"
+    #[arg(long, default_value = "0")]
+    access_log_max_size: u64,
+    /// "Number of rotated access logs to keep"
+    #[arg(long, default_value = "5")]
+    access_log_max_files: usize,
+    /// "Cache responses to GET requests in up to this many bytes, evicting the least recently used
+    /// ones to make room (0 = no cache)"
+    #[arg(long, default_value = "0")]
+    cache_size: u64,
+    /// "Don't cache responses with bodies bigger than this many bytes"
+    #[arg(long, default_value = "1048576")]
+    cache_max_object_size: u64,
+    /// "Keep cached response bodies in files in this directory instead of in memory"
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+    /// "Compress responses with gzip or brotli for clients that accept it, unless the upstream
+    /// already did"
+    #[arg(long)]
+    compress: bool,
+    /// "Don't compress responses with bodies smaller than this many bytes"
+    #[arg(long, default_value = "1024")]
+    compress_min_size: u64,
+    /// "Content type to compress (can be repeated; TYPE/* matches every subtype). Defaults to
+    /// text/*, application/json, application/javascript, application/xml and image/svg+xml"
+    #[arg(long)]
+    compress_type: Vec<String>,
+    /// "Content type never to compress, even if --compress-type matches it (can be repeated).
+    /// Defaults to text/event-stream"
+    #[arg(long)]
+    compress_exclude: Vec<String>,
+    /// "Header to add to requests before they are sent upstream, as NAME: VALUE (can be
+    /// repeated). Other values the header already has are kept"
+    #[arg(long)]
+    request_header_add: Vec<HeaderField>,
+    /// "Header to set on requests before they are sent upstream, as NAME: VALUE (can be
+    /// repeated). Other values the header already has are replaced"
+    #[arg(long)]
+    request_header_set: Vec<HeaderField>,
+    /// "Header to remove from requests before they are sent upstream (can be repeated)"
+    #[arg(long)]
+    request_header_remove: Vec<FieldName>,
+    /// "Header to add to responses from upstreams, as NAME: VALUE (can be repeated)"
+    #[arg(long)]
+    response_header_add: Vec<HeaderField>,
+    /// "Header to set on responses from upstreams, as NAME: VALUE (can be repeated)"
+    #[arg(long)]
+    response_header_set: Vec<HeaderField>,
+    /// "Header to remove from responses from upstreams (can be repeated)"
+    #[arg(long)]
+    response_header_remove: Vec<FieldName>,
+    /// "Give up on connecting to an upstream (including any TLS handshake) after this many seconds"
+    #[arg(long, default_value = "10")]
+    upstream_connect_timeout: usize,
+    /// "Respond with 408 if a client takes longer than this many seconds to send a request's
+    /// headers"
+    #[arg(long, default_value = "30")]
+    client_header_timeout: usize,
+    /// "Give up if no more of a request or response body arrives for this many seconds"
+    #[arg(long, default_value = "60")]
+    body_timeout: usize,
+    /// "Respond with 504 if an upstream takes longer than this many seconds to start responding"
+    #[arg(long, default_value = "60")]
+    upstream_response_timeout: usize,
+    /// "Close client connections that sit idle between requests for this many seconds"
+    #[arg(long, default_value = "60")]
+    client_idle_timeout: usize,
+    /// "Close WebSocket and other upgraded connections (and every connection with --mode tcp) once
+    /// nothing has been sent either way for this many seconds"
+    #[arg(long, default_value = "300")]
+    tunnel_idle_timeout: usize,
+    /// "On SIGTERM or SIGINT, give open connections this many seconds to finish their current
+    /// request before exiting anyway. SIGUSR2 starts a new balancebeam process from the same
+    /// executable, hands it the listening sockets and then shuts down the same way"
+    #[arg(long, default_value = "30")]
+    drain_timeout: usize,
+    /// "PEM certificate chain to present to clients. Enables TLS on the listening address"
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// "PEM private key for --tls-cert"
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// "Certificate to present to clients that ask for a particular hostname using SNI, given as
+    /// HOSTNAME=CERT,KEY (can be repeated; the hostname may be a wildcard like *.example.com)"
+    #[arg(long)]
+    tls_sni_cert: Vec<SniCert>,
+    /// "PEM bundle of CA certificates to trust for https:// upstreams (defaults to the system's
+    /// trusted CAs)"
+    #[arg(long)]
+    upstream_ca: Option<PathBuf>,
+    /// "PEM certificate chain to present to https:// upstreams that require mutual TLS"
+    #[arg(long, requires = "upstream_client_key")]
+    upstream_client_cert: Option<PathBuf>,
+    /// "PEM private key for --upstream-client-cert"
+    #[arg(long, requires = "upstream_client_cert")]
+    upstream_client_key: Option<PathBuf>,
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
@@ -36,133 +256,723 @@ struct CmdOptions {
 /// You should add fields to this struct in later milestones.
 #[derive(Clone)]
 struct ProxyState {
+    /// Whether client connections are parsed as HTTP or passed through as they are
+    mode: ProxyMode,
     /// How frequently we check whether upstream servers are alive (Milestone 4)
-    #[allow(dead_code)]
     active_health_check_interval: usize,
-    /// Where we should send requests when doing active health checks (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: Vec<String>,
-    live_upstream: Arc<RwLock<Vec<String>>>,
+    /// How long (in seconds) a single health check probe may take before it counts as failed
+    active_health_check_timeout: usize,
+    /// Consecutive passing probes needed to bring an unhealthy upstream back
+    healthy_threshold: usize,
+    /// Consecutive failing probes needed to take a healthy upstream out
+    unhealthy_threshold: usize,
+    /// Per-IP request counts, or None if rate limiting is disabled (Milestone 5)
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Addresses of servers that we are proxying to, in every pool
+    upstream_addresses: Vec<Upstream>,
+    live_upstream: Arc<RwLock<Vec<Upstream>>>,
+    /// Decides which pool each request goes to, and holds each pool's load balancing strategy and
+    /// health check path
+    router: Arc<Router>,
+    /// Name of the cookie that pins clients to an upstream in pools with cookie affinity
+    affinity_cookie: String,
+    /// How many other upstreams a failed retryable request may be sent to
+    max_retries: usize,
+    /// How long each phase of a request may take
+    timeouts: Timeouts,
+    /// How long open connections get to finish up when shutting down
+    drain_timeout: Duration,
+    /// Connection and request counters for each upstream
+    stats: Arc<UpstreamStats>,
+    /// Stop requests from going to upstreams that keep failing
+    circuit_breakers: Arc<CircuitBreakers>,
+    /// Upstreams that have been drained or disabled through the admin interface. Upstreams that
+    /// aren't listed are enabled.
+    upstream_status: Arc<parking_lot::RwLock<HashMap<String, UpstreamStatus>>>,
+    /// Woken whenever the admin interface changes an upstream's status
+    status_changed: Arc<tokio::sync::Notify>,
+    /// Counters and histograms exported to Prometheus
+    metrics: Arc<Metrics>,
+    /// Where finished requests are recorded, or None if access logging is disabled
+    access_log: Option<Arc<AccessLog>>,
+    /// Responses to GET requests that can be served without an upstream, or None if caching is
+    /// disabled
+    cache: Option<Arc<ResponseCache>>,
+    /// Which responses to compress, or None if compression is disabled
+    compression: Option<Arc<CompressionSettings>>,
+    /// Changes made to requests before they are sent upstream
+    request_headers: Arc<HeaderRules>,
+    /// Changes made to upstream responses before they are sent to the client
+    response_headers: Arc<HeaderRules>,
+    /// Idle keep-alive connections to upstreams
+    pool: Arc<ConnectionPool>,
+    /// Terminates TLS from clients, or None if clients speak plain HTTP
+    tls_acceptor: Option<TlsAcceptor>,
+    /// Does the TLS handshake with https:// upstreams
+    upstream_tls: Option<TlsConnector>,
 }
+
+impl ProxyState {
+    /// Builds the initial state for the given configuration, with every upstream assumed live.
+    fn new(config: &Config) -> ProxyState {
+        ProxyState {
+            mode: config.mode,
+            active_health_check_interval: config.active_health_check_interval,
+            active_health_check_timeout: config.active_health_check_timeout,
+            healthy_threshold: config.healthy_threshold,
+            unhealthy_threshold: config.unhealthy_threshold,
+            rate_limiter: match config.max_requests_per_minute {
+                0 => None,
+                max_requests => Some(Arc::new(RateLimiter::new(
+                    max_requests,
+                    RATE_LIMIT_WINDOW,
+                    config.rate_limit_strategy,
+                ))),
+            },
+            upstream_addresses: config.upstreams.clone(),
+            live_upstream: Arc::new(RwLock::new(config.upstreams.clone())),
+            router: Arc::new(Router::new(config)),
+            affinity_cookie: config.affinity_cookie.clone(),
+            max_retries: config.max_retries,
+            timeouts: Timeouts {
+                upstream_connect: Duration::from_secs(config.upstream_connect_timeout as u64),
+                client_header: Duration::from_secs(config.client_header_timeout as u64),
+                body: Duration::from_secs(config.body_timeout as u64),
+                upstream_response: Duration::from_secs(config.upstream_response_timeout as u64),
+                client_idle: Duration::from_secs(config.client_idle_timeout as u64),
+                tunnel_idle: Duration::from_secs(config.tunnel_idle_timeout as u64),
+            },
+            drain_timeout: Duration::from_secs(config.drain_timeout as u64),
+            stats: Arc::new(UpstreamStats::default()),
+            circuit_breakers: Arc::new(CircuitBreakers::new(config.breaker_settings())),
+            upstream_status: Arc::new(parking_lot::RwLock::new(HashMap::new())),
+            status_changed: Arc::new(tokio::sync::Notify::new()),
+            metrics: Arc::new(Metrics::new()),
+            access_log: config.access_log.clone(),
+            cache: config.cache.clone(),
+            compression: config.compression_settings().map(Arc::new),
+            request_headers: Arc::new(config.request_headers.clone()),
+            response_headers: Arc::new(config.response_headers.clone()),
+            pool: Arc::new(ConnectionPool::new(
+                config.max_idle_connections,
+                Duration::from_secs(config.idle_connection_timeout as u64),
+            )),
+            tls_acceptor: config.tls_acceptor.clone(),
+            upstream_tls: config.upstream_tls_connector.clone(),
+        }
+    }
+
+    /// Builds the state for a reloaded configuration. The live upstream list, upstream counters,
+    /// admin statuses and metrics are shared with this state, and the rate limiter, circuit
+    /// breakers and connection pool are kept if their settings didn't change, so that reloading
+    /// doesn't forget what is going on right now.
+    fn reconfigure(&self, config: &Config) -> ProxyState {
+        let mut state = ProxyState::new(config);
+        state.live_upstream = self.live_upstream.clone();
+        state.stats = self.stats.clone();
+        state.upstream_status = self.upstream_status.clone();
+        state.status_changed = self.status_changed.clone();
+        state.metrics = self.metrics.clone();
+        if let Some(rate_limiter) = &self.rate_limiter {
+            let max_requests = config.max_requests_per_minute;
+            if rate_limiter.has_settings(max_requests, config.rate_limit_strategy) {
+                state.rate_limiter = Some(rate_limiter.clone());
+            }
+        }
+        if let (Some(access_log), Some(settings)) = (&self.access_log, config.access_log_settings())
+        {
+            if access_log.has_settings(&settings) {
+                state.access_log = Some(access_log.clone());
+            }
+        }
+        if let (Some(cache), Some(settings)) = (&self.cache, config.cache_settings()) {
+            if cache.has_settings(&settings) {
+                state.cache = Some(cache.clone());
+            }
+        }
+        if self
+            .circuit_breakers
+            .has_settings(&config.breaker_settings())
+        {
+            state.circuit_breakers = self.circuit_breakers.clone();
+        }
+        let idle_timeout = Duration::from_secs(config.idle_connection_timeout as u64);
+        if self
+            .pool
+            .has_settings(config.max_idle_connections, idle_timeout)
+        {
+            state.pool = self.pool.clone();
+        }
+        state
+    }
+
+    /// The scheme clients connect with, as passed on in X-Forwarded-Proto and Forwarded.
+    fn client_proto(&self) -> &'static str {
+        match self.tls_acceptor {
+            Some(_) => "https",
+            None => "http",
+        }
+    }
+
+    /// Returns the pool of the first route that matches the request. Requests that don't match
+    /// any route go to the default pool, unless there are no upstreams in it.
+    fn pool_for(&self, request: &http::Request<Vec<u8>>) -> Option<&Pool> {
+        self.router.route(request).or_else(|| {
+            self.upstream_addresses
+                .iter()
+                .any(|upstream| upstream.pool == DEFAULT_POOL)
+                .then(|| self.router.pool(DEFAULT_POOL).unwrap())
+        })
+    }
+}
+
+/// The current ProxyState. Reloading the configuration swaps in a new ProxyState, but connections
+/// that are already being handled keep using the copy they started with.
+type SharedState = Arc<parking_lot::RwLock<ProxyState>>;
+
+/// Requests are rate limited per minute
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Largest request body that is buffered so the request can be sent again, on another upstream or
+/// in place of a stale pooled connection
+const MAX_RETRY_BODY_SIZE: usize = 64 * 1024;
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     pretty_env_logger::init();
 
-    // Parse the command line arguments passed to this program
+    // Parse the command line arguments passed to this program, and the configuration file if there
+    // is one
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
-    }
+    let config = match Config::load(&options, options.config.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
-    // Start listening for connections
-    let listener =  TcpListener::bind(&options.bind).await?;
-    log::info!("Listening for requests on {}", options.bind);
+    // Start listening for connections, taking over the old process's sockets if this is an
+    // upgrade
+    let mut listeners = Listeners::from_env();
+    let listener = listeners.bind(&config.bind).await?;
+    log::info!("Listening for requests on {}", config.bind);
 
     // Handle incoming connections
-    let state = ProxyState {
-        live_upstream: Arc::new(RwLock::new(options.upstream.clone())),
-        upstream_addresses: options.upstream,    
-        active_health_check_interval: options.active_health_check_interval,
-        active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
-    };
+    let shared_state: SharedState = Arc::new(parking_lot::RwLock::new(ProxyState::new(&config)));
 
-    let state_clone = state.clone();
-    tokio::spawn(async move{
-        active_health_check(&state_clone).await;
+    if let Some(admin_bind) = &options.admin_bind {
+        let admin_listener = listeners.bind(admin_bind).await?;
+        log::info!("Serving the admin interface on {}", admin_bind);
+        let state_clone = shared_state.clone();
+        tokio::spawn(async move {
+            admin::serve(admin_listener, state_clone).await;
+        });
+    }
+
+    if let Some(metrics_bind) = &options.metrics_bind {
+        let metrics_listener = listeners.bind(metrics_bind).await?;
+        log::info!("Serving metrics on {}", metrics_bind);
+        let metrics = shared_state.read().metrics.clone();
+        tokio::spawn(async move {
+            metrics::serve(metrics_listener, metrics).await;
+        });
+    }
+
+    let state_clone = shared_state.clone();
+    tokio::spawn(async move {
+        health_check::active_health_check(&state_clone).await;
     });
 
-    loop{
-        if let Ok((stream, _)) = listener.accept().await {      
-            // Handle the connection!
-            let state = state.clone();
-            tokio::spawn(async move{           
-                handle_connection(stream, &state).await;
-            });  
-        }
-    } 
-}
-
-async fn active_health_check(state: &ProxyState){   
-    loop{        
-        tokio::time::sleep(Duration::from_secs(state.active_health_check_interval.try_into().unwrap())).await;
-        let mut write = state.live_upstream.write().await;
-        write.clear();
-        for stream in &state.upstream_addresses{          
-            if let Ok(mut tcp_stream) = TcpStream::connect(stream).await{
-                let request = http::Request::builder()
-                    .method(http::Method::GET)
-                    .uri(&state.active_health_check_path)
-                    .header("Host", stream)
-                    .body(Vec::new())
-                    .unwrap();
-                if let Err(err) = write_to_stream(&request, &mut tcp_stream).await{
-                    log::error!("Fail to write request to stream {}: {}", stream, err);
-                    return;
+    let state_clone = shared_state.clone();
+    tokio::spawn(async move {
+        rate_limiter_eviction(&state_clone).await;
+    });
+
+    let state_clone = shared_state.clone();
+    tokio::spawn(async move {
+        pool_eviction(&state_clone).await;
+    });
+
+    tokio::spawn(access_log::reopen_on_sigusr1(shared_state.clone()));
+
+    if let Some(path) = options.config.clone() {
+        let state_clone = shared_state.clone();
+        tokio::spawn(config::reload_on_sighup(options, path, config, state_clone));
+    }
+
+    let mut signals = TerminationSignals::new()?;
+    let mut upgrade_signals = signal(SignalKind::user_defined2())?;
+    let listener_fds = listeners.finish();
+    let shutdown = Shutdown::new();
+    loop {
+        let (stream, client_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => continue,
+            },
+            signal = signals.recv() => {
+                log::info!("Received {}, no longer accepting connections", signal);
+                break;
+            }
+            // Start the (possibly new) executable and hand our sockets over to it. It tells us to
+            // shut down once it's ready.
+            _ = upgrade_signals.recv() => {
+                if let Err(err) = handoff::upgrade(&listener_fds) {
+                    log::error!("Could not start a new balancebeam process: {}", err);
                 }
-                
-                let response = match read_from_stream(&mut tcp_stream, request.method()).await{
-                    Ok(response) => response,
-                    Err(error) => {
-                        log::error!("Error reading response from server: {:?}", error);
-                        return;
-                    }
-                };
-                match response.status().as_u16(){
-                    200 => {
-                        write.push(stream.clone());
+                continue;
+            }
+        };
+        // Handle the connection!
+        let state = shared_state.read().clone();
+        let shutdown = shutdown.handle();
+        tokio::spawn(async move {
+            match &state.tls_acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    // The client and the acceptor agreed on HTTP/2 through ALPN
+                    Ok(stream) if stream.get_ref().1.alpn_protocol() == Some(b"h2") => {
+                        http2::handle_connection(stream, client_addr, &state, shutdown).await
                     }
-                    _ => {
-                        log::error!("Get unexpected response from upstream {}", stream);
-                        return;
+                    Ok(stream) => serve_connection(stream, client_addr, &state, shutdown).await,
+                    Err(err) => {
+                        log::info!("TLS handshake with {} failed: {}", client_addr, err)
                     }
-                }
-            } 
-            else{
-                log::error!("Fail to connect to upstream {}",stream);
-                return;
+                },
+                None => serve_connection(stream, client_addr, &state, shutdown).await,
             }
-        }  
-    }  
+        });
+    }
+
+    // Let the connections that are already open finish what they are doing
+    drop(listener);
+    let drain_timeout = shared_state.read().drain_timeout;
+    if !shutdown.drain(drain_timeout).await {
+        log::error!(
+            "Connections were still open after {:?}; closing them and exiting",
+            drain_timeout
+        );
+        std::process::exit(1);
+    }
+    log::info!("All connections closed, exiting");
+    Ok(())
 }
 
-async fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::Error> {
-    let mut rng = rand::rngs::StdRng::from_entropy();   
-    loop{
-        let read = state.live_upstream.read().await;
-        let upstream_idx = rng.gen_range(0..read.len());
-        let upstream_ip = &read.get(upstream_idx).unwrap().clone();
-        drop(read);
-        
-        match TcpStream::connect(upstream_ip).await {
-            Ok(stream) => return Ok(stream),
+/// Proxies a client connection as HTTP or as raw TCP, depending on the mode. HTTP clients that
+/// start with the HTTP/2 preface (h2c with prior knowledge) are served over HTTP/2.
+async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    client_conn: S,
+    client_addr: SocketAddr,
+    state: &ProxyState,
+    mut shutdown: ShutdownHandle,
+) {
+    if state.mode == ProxyMode::Tcp {
+        tcp::handle_connection(client_conn, client_addr, state, shutdown).await;
+        return;
+    }
+    let sniffed = tokio::select! {
+        sniffed = http2::sniff_preface(client_conn, state.timeouts.client_idle) => sniffed,
+        _ = shutdown.draining() => return,
+    };
+    match sniffed {
+        Ok((true, client_conn)) => {
+            http2::handle_connection(client_conn, client_addr, state, shutdown).await
+        }
+        Ok((false, client_conn)) => {
+            handle_connection(client_conn, client_addr, state, shutdown).await
+        }
+        Err(err) => log::debug!("Closing connection from {}: {}", client_addr, err),
+    }
+}
+
+/// Periodically drops rate limiter counters for clients that have gone quiet.
+async fn rate_limiter_eviction(shared_state: &SharedState) {
+    loop {
+        tokio::time::sleep(RATE_LIMIT_WINDOW).await;
+        let rate_limiter = shared_state.read().rate_limiter.clone();
+        if let Some(rate_limiter) = rate_limiter {
+            rate_limiter.evict_expired();
+        }
+    }
+}
+
+/// Periodically closes upstream connections that have sat idle in the pool for too long.
+async fn pool_eviction(shared_state: &SharedState) {
+    loop {
+        let idle_timeout = shared_state.read().pool.idle_timeout();
+        tokio::time::sleep(idle_timeout).await;
+        let pool = shared_state.read().pool.clone();
+        pool.evict_expired();
+    }
+}
+
+/// A connection to an upstream, ready to carry one request.
+struct UpstreamConnection {
+    stream: UpstreamStream,
+    /// Counts the connection against the upstream until dropped
+    guard: ConnectionGuard,
+    /// Lets the upstream's circuit breaker know how the request turned out
+    permit: Permit,
+    /// Whether the connection came from the pool rather than being opened for this request
+    reused: bool,
+}
+
+/// Gets a connection to one of the given pool's live upstreams whose circuit breaker lets requests
+/// through: the one the request is pinned to, if any, or else one chosen by the pool's load
+/// balancing strategy. Upstreams in `tried` are only picked if there are no others. If `reuse` is
+/// true, an idle connection from the connection pool is reused if there is one; otherwise a new
+/// connection is opened. The connection is counted against the upstream until the returned guard
+/// is dropped.
+async fn connect_to_upstream(
+    state: &ProxyState,
+    pool: &Pool,
+    affinity: &Affinity,
+    tried: &HashSet<String>,
+    reuse: bool,
+) -> Result<UpstreamConnection, std::io::Error> {
+    let mut last_error = io::ErrorKind::Other;
+    loop {
+        let live: Vec<Upstream> = state
+            .live_upstream
+            .read()
+            .await
+            .iter()
+            .filter(|upstream| {
+                upstream.pool == pool.name && state.circuit_breakers.is_available(&upstream.address)
+            })
+            .cloned()
+            .collect();
+        if live.is_empty() {
+            log::error!("No live upstreams to connect to in pool {}!", pool.name);
+            // Report a timeout as such, so that the client gets a 504 rather than a 502
+            return Err(io::Error::new(last_error, "All upstreams are dead"));
+        }
+        let untried: Vec<Upstream> = live
+            .iter()
+            .filter(|upstream| !tried.contains(&upstream.address))
+            .cloned()
+            .collect();
+        let candidates = if untried.is_empty() { live } else { untried };
+        let upstream_idx = affinity
+            .choose(&candidates)
+            .unwrap_or_else(|| pool.strategy.choose(&candidates, &state.stats));
+        let upstream = &candidates[upstream_idx];
+        let upstream_ip = upstream.address.clone();
+        // Another connection may have taken the last trial slot of a half-open breaker since we
+        // looked, in which case choose again
+        let permit = match state.circuit_breakers.try_admit(&upstream_ip) {
+            Some(permit) => permit,
+            None => continue,
+        };
+
+        // Count the connection before it is established so that concurrent connections see it
+        let guard = state.stats.track(&upstream_ip);
+        if reuse {
+            if let Some(stream) = state.pool.take(&upstream_ip) {
+                log::debug!("Reusing idle connection to upstream {}", upstream_ip);
+                return Ok(UpstreamConnection {
+                    stream,
+                    guard,
+                    permit,
+                    reused: true,
+                });
+            }
+        }
+        let connect = upstream::connect(upstream, state.upstream_tls.as_ref());
+        let connected = match tokio::time::timeout(state.timeouts.upstream_connect, connect).await {
+            Ok(connected) => connected,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+        };
+        match connected {
+            Ok(stream) => {
+                return Ok(UpstreamConnection {
+                    stream,
+                    guard,
+                    permit,
+                    reused: false,
+                })
+            }
             Err(err) => {
-                log::error!("Fail to connect to upstream {}: {}",upstream_ip, err);
-                let mut write = state.live_upstream.write().await;
-                write.swap_remove(upstream_idx);
-                if write.len() == 0{
-                    log::error!("All upstream failed!");
-                    return Err(Error::new(ErrorKind::Other, "All upstreams are dead"));
-                }
+                log::error!("Fail to connect to upstream {}: {}", upstream_ip, err);
+                guard.counters().failures.fetch_add(1, Ordering::SeqCst);
+                state.metrics.record_connect_failure(&upstream_ip);
+                permit.record(Outcome::Failure);
+                last_error = err.kind();
+            }
+        }
+    }
+}
+
+/// Waits until the upstream at `address` is disabled through the admin interface, returning the
+/// error to close the tunnels open to it with.
+async fn upstream_disabled(state: &ProxyState, address: &str) -> io::Error {
+    loop {
+        // Start listening before checking, so that a change in between isn't missed
+        let changed = state.status_changed.notified();
+        if state.upstream_status.read().get(address) == Some(&UpstreamStatus::Disabled) {
+            return io::Error::new(io::ErrorKind::ConnectionAborted, "upstream was disabled");
+        }
+        changed.await;
+    }
+}
+
+/// Works out which pool of upstreams a request is for, and what (if anything) pins it to one of
+/// them. Returns the status to reject the request with instead if the client has been sending too
+/// many requests, or if no pool takes it.
+fn route_request<'a>(
+    state: &'a ProxyState,
+    client_addr: IpAddr,
+    request: &http::Request<Vec<u8>>,
+) -> Result<(&'a Pool, Affinity), http::StatusCode> {
+    if let Some(rate_limiter) = &state.rate_limiter {
+        if !rate_limiter.check(client_addr) {
+            log::info!(
+                "Rate limiting {}: {}",
+                client_addr,
+                request::format_request_line(request)
+            );
+            state.metrics.record_rate_limited();
+            return Err(http::StatusCode::TOO_MANY_REQUESTS);
+        }
+    }
+    let pool = match state.pool_for(request) {
+        Some(pool) => pool,
+        None => {
+            log::info!(
+                "No route for {}: {}",
+                client_addr,
+                request::format_request_line(request)
+            );
+            return Err(http::StatusCode::NOT_FOUND);
+        }
+    };
+    let affinity = match pool.affinity {
+        AffinityMode::None => Affinity::None,
+        AffinityMode::IpHash => Affinity::ClientIp(client_addr),
+        AffinityMode::Cookie => Affinity::Cookie(affinity::find_cookie(
+            request.headers(),
+            &state.affinity_cookie,
+        )),
+    };
+    Ok((pool, affinity))
+}
+
+/// The body of a request being forwarded, wherever the client is sending it from.
+trait RequestBody {
+    /// Returns the whole body if it was read before the request was sent. Only then can the
+    /// request be sent more than once.
+    fn buffered(&self) -> Option<&[u8]>;
+
+    /// Passes the body from the client to the upstream as it arrives. Only called for a body that
+    /// wasn't buffered, and then only once.
+    async fn stream(&mut self, upstream: &mut UpstreamStream) -> Result<(), body::Error>;
+}
+
+/// The body of a request on an HTTP/1 client connection.
+struct ClientBody<'a, S> {
+    client_conn: &'a mut S,
+    /// How long to wait for more of the body
+    timeout: Duration,
+    buffered: Option<Vec<u8>>,
+    /// The body still to be read from the client, if it wasn't buffered
+    pending: Option<body::PendingBody>,
+}
+
+impl<S: AsyncRead + Unpin + Send> RequestBody for ClientBody<'_, S> {
+    fn buffered(&self) -> Option<&[u8]> {
+        self.buffered.as_deref()
+    }
+
+    async fn stream(&mut self, upstream: &mut UpstreamStream) -> Result<(), body::Error> {
+        let pending = self
+            .pending
+            .take()
+            .expect("unbuffered request bodies are only sent once");
+        let mut client_reader = ReadTimeout::new(&mut *self.client_conn, self.timeout);
+        pending.copy(&mut client_reader, upstream).await
+    }
+}
+
+/// Why a request didn't get a response from an upstream.
+enum ExchangeError {
+    /// No upstream could be reached, or the last one tried failed before responding. Holds that
+    /// upstream (or `metrics::NO_UPSTREAM`) and the status to answer the client with.
+    Upstream(String, http::StatusCode),
+    /// The request body couldn't be read from the client while it was being sent to the upstream
+    RequestBody(String, body::Error),
+}
+
+/// The head of an upstream's response, along with the connection the rest of it is on.
+struct UpstreamResponse {
+    stream: UpstreamStream,
+    guard: ConnectionGuard,
+    response: http::Response<Vec<u8>>,
+    body: body::PendingBody,
+}
+
+/// Sends a request to an upstream in the given pool and reads the head of its response, letting the
+/// upstream's circuit breaker know how it went. This is the same for every kind of client
+/// connection, which only differ in where the request body comes from.
+///
+/// Only requests that are safe to repeat and whose body was buffered are retried on another
+/// upstream, or sent on a pooled connection: if a pooled connection turns out to have gone stale
+/// after the whole request was written, there's no telling whether the upstream acted on it.
+async fn exchange<B: RequestBody>(
+    state: &ProxyState,
+    pool: &Pool,
+    affinity: &Affinity,
+    client_ip: &str,
+    request: &http::Request<Vec<u8>>,
+    request_body: &mut B,
+) -> Result<UpstreamResponse, ExchangeError> {
+    let retryable = request_body.buffered().is_some() && is_retryable(request);
+    let mut retries_left = if retryable { state.max_retries } else { 0 };
+    let mut reuse = retryable;
+    // The upstreams the request has failed on, which retries go elsewhere than if they can
+    let mut tried = HashSet::new();
+
+    loop {
+        // Get a connection to an upstream chosen by the load balancing strategy. Each request is
+        // balanced separately, so requests from one client can go to different upstreams.
+        let connection = match connect_to_upstream(state, pool, affinity, &tried, reuse).await {
+            Ok(connection) => connection,
+            Err(error) => {
+                let status = match error.kind() {
+                    io::ErrorKind::TimedOut => http::StatusCode::GATEWAY_TIMEOUT,
+                    _ => http::StatusCode::BAD_GATEWAY,
+                };
+                let upstream = metrics::NO_UPSTREAM.to_string();
+                return Err(ExchangeError::Upstream(upstream, status));
+            }
+        };
+        let UpstreamConnection {
+            stream: mut upstream_conn,
+            guard: connection_guard,
+            permit,
+            reused,
+        } = connection;
+        let upstream_ip = connection_guard.address().to_string();
+
+        log::info!(
+            "{} -> {}: {}",
+            client_ip,
+            upstream_ip,
+            request::format_request_line(request)
+        );
+
+        // Forward the request to the server, streaming the body straight from the client
+        connection_guard
+            .counters()
+            .requests
+            .fetch_add(1, Ordering::SeqCst);
+        let forwarded = match request::write_head(request, &mut upstream_conn).await {
+            Ok(()) => match request_body.buffered() {
+                Some(buffered) => upstream_conn
+                    .write_all(buffered)
+                    .await
+                    .map_err(body::Error::Write),
+                None => request_body.stream(&mut upstream_conn).await,
             },
+            Err(error) => Err(body::Error::Write(error)),
+        };
+        // `stale` says whether the upstream hung up (or reset the connection) in a way that lets
+        // the request be sent again if the connection was a pooled one
+        let (failure, status, stale) = match forwarded {
+            Ok(()) => {
+                log::debug!("Forwarded request to server");
+                // Read the server's response headers
+                let response_head = response::read_head(&mut upstream_conn, request.method());
+                let timeout = state.timeouts.upstream_response;
+                match tokio::time::timeout(timeout, response_head).await {
+                    Ok(Ok((response, response_body))) => {
+                        permit.record(match response.status().is_server_error() {
+                            true => Outcome::ServerError,
+                            false => Outcome::Success,
+                        });
+                        return Ok(UpstreamResponse {
+                            stream: upstream_conn,
+                            guard: connection_guard,
+                            response,
+                            body: response_body,
+                        });
+                    }
+                    Ok(Err(error)) => (
+                        format!("failed to read response: {:?}", error),
+                        http::StatusCode::BAD_GATEWAY,
+                        is_retryable(request)
+                            && matches!(
+                                error,
+                                response::Error::IncompleteResponse
+                                    | response::Error::ConnectionError(_)
+                            ),
+                    ),
+                    Err(_) => (
+                        format!("did not respond within {:?}", timeout),
+                        http::StatusCode::GATEWAY_TIMEOUT,
+                        false,
+                    ),
+                }
+            }
+            Err(body::Error::Write(error)) => (
+                format!("failed to send request: {}", error),
+                http::StatusCode::BAD_GATEWAY,
+                true,
+            ),
+            Err(error) => return Err(ExchangeError::RequestBody(upstream_ip, error)),
+        };
+
+        // A pooled connection may simply have gone stale while it sat idle, which says nothing
+        // about the upstream itself, so the request is sent again on a new connection even if it
+        // has no retries left. An upstream that hung up before taking the whole request can't have
+        // acted on it; one that hung up afterwards might have, so then the request is only sent
+        // again if it's safe to repeat.
+        if reused && stale {
+            log::info!(
+                "Idle connection to upstream {} {}; retrying on a new connection",
+                upstream_ip,
+                failure
+            );
+            reuse = false;
+            continue;
+        }
+        log::error!("Upstream {} {}", upstream_ip, failure);
+        connection_guard
+            .counters()
+            .failures
+            .fetch_add(1, Ordering::SeqCst);
+        if !reused {
+            permit.record(Outcome::Failure);
+        }
+        if retries_left > 0 {
+            retries_left -= 1;
+            tried.insert(upstream_ip);
+            log::info!(
+                "Retrying {} on another upstream",
+                request::format_request_line(request)
+            );
+            continue;
         }
+        return Err(ExchangeError::Upstream(upstream_ip, status));
     }
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Sends a whole response to the client. `cache_status` says whether the response came from the
+/// cache, if the request was one that the cache could have answered.
+async fn send_response<S: AsyncWrite + Unpin>(
+    client_conn: &mut S,
+    client_ip: &str,
+    mut response: http::Response<Vec<u8>>,
+    cache_status: Option<CacheStatus>,
+) {
+    if let Some(cache_status) = cache_status {
+        cache_status.add_header(response.headers_mut());
+    }
     log::info!(
         "{} <- {}",
         client_ip,
@@ -170,30 +980,145 @@ async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Ve
     );
     if let Err(error) = response::write_to_stream(&response, client_conn).await {
         log::warn!("Failed to send response to client: {}", error);
-        return;
     }
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
+/// What the response cache had to say about a request.
+enum CacheLookup<'a> {
+    /// The cache has a fresh response, ready to be sent to the client
+    Hit(http::Response<Vec<u8>>),
+    /// The request has to be sent upstream, after which the cache is updated with the response
+    Forward(CacheUpdate<'a>),
+}
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+/// What is needed to update the response cache once the upstream has responded to a request.
+struct CacheUpdate<'a> {
+    /// The cache, if the request is one it could have answered
+    cache: Option<&'a ResponseCache>,
+    /// The stale response the request was made conditional on, if any
+    stale: Option<CachedResponse>,
+}
+
+/// Looks a request up in the response cache. A fresh response is returned with the response
+/// header rules applied and compressed if the client accepts it. A stale one is revalidated by
+/// making the request conditional, so that the upstream only has to say it hasn't changed.
+/// Requests with a body are never answered from the cache.
+async fn lookup_cache<'a>(
+    state: &'a ProxyState,
+    request_info: &RequestInfo,
+    request: &mut http::Request<Vec<u8>>,
+    has_body: bool,
+) -> CacheLookup<'a> {
+    let cache = state
+        .cache
+        .as_deref()
+        .filter(|_| cache::is_cacheable_request(request) && !has_body);
+    let mut stale = None;
+    if let Some(cache) = cache {
+        match cache.lookup(request).await {
+            Some(hit) if hit.is_fresh() => {
+                state.metrics.record_cache_lookup(CacheStatus::Hit);
+                let mut response = hit.into_response(request);
+                rewrite_response_headers(state, request_info, response.headers_mut());
+                if let Some(settings) = &state.compression {
+                    let framing = body::Framing::ContentLength(response.body().len());
+                    if let Some(encoding) =
+                        compress::choose_encoding(settings, request, &response, framing)
+                    {
+                        if let Err(error) =
+                            compress::compress_response(&mut response, encoding).await
+                        {
+                            log::error!("Failed to compress cached response: {}", error);
+                        }
+                    }
+                }
+                return CacheLookup::Hit(response);
+            }
+            Some(cached) => {
+                log::debug!("Revalidating {}", request::format_request_line(request));
+                cached.add_validators(request);
+                stale = Some(cached);
+            }
+            None => {}
         }
-    };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
+    }
+    CacheLookup::Forward(CacheUpdate { cache, stale })
+}
+
+impl CacheUpdate<'_> {
+    /// Updates the cache with the upstream's response to a request, and adds an X-Cache header to
+    /// the response if the cache could have answered the request. If the upstream says a stale
+    /// response hasn't changed, the response becomes the cached one. A cacheable response has its
+    /// whole body read from `upstream_conn` so that it can be stored, in which case the body is
+    /// left in the response and `response_body` is set to None. Responses to unsafe requests
+    /// remove what was cached for the URL. Returns an error if the body couldn't be read.
+    async fn update<R: AsyncRead + Unpin>(
+        self,
+        state: &ProxyState,
+        request: &http::Request<Vec<u8>>,
+        response: &mut http::Response<Vec<u8>>,
+        response_body: &mut Option<body::PendingBody>,
+        upstream_conn: &mut R,
+    ) -> Result<(), body::Error> {
+        let mut cache_status = self.cache.map(|_| CacheStatus::Miss);
+        match (self.cache, self.stale) {
+            // The upstream says the cached response hasn't changed, so that can be sent instead
+            (Some(cache), Some(stale)) if response.status() == http::StatusCode::NOT_MODIFIED => {
+                *response = cache.refresh(stale, response);
+                *response_body = None;
+                cache_status = Some(CacheStatus::Hit);
+            }
+            (Some(cache), _) if cache.is_storable(request, response, framing(response_body)) => {
+                let mut body = Vec::new();
+                let mut upstream_reader = ReadTimeout::new(upstream_conn, state.timeouts.body);
+                let pending = response_body.take().unwrap();
+                pending.copy(&mut upstream_reader, &mut body).await?;
+                *response.body_mut() = body;
+                cache.store(request, response).await;
+            }
+            _ => {}
+        }
+        if let Some(cache) = &state.cache {
+            if cache::is_unsafe_request(request) && !response.status().is_server_error() {
+                cache.invalidate(request);
+            }
+        }
+        if let Some(cache_status) = cache_status {
+            state.metrics.record_cache_lookup(cache_status);
+            cache_status.add_header(response.headers_mut());
+        }
+        Ok(())
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    mut client_conn: S,
+    client_addr: SocketAddr,
+    state: &ProxyState,
+    mut shutdown: ShutdownHandle,
+) {
+    let client_addr = client_addr.ip();
+    let client_ip = client_addr.to_string();
+    log::info!("Connection received from {}", client_ip);
+    let _active_connection = state.metrics.connection_opened();
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        // Read a request from the client, unless we're shutting down. A client that is between
+        // requests is hung up on right away rather than waited for.
+        if shutdown.is_draining() {
+            log::debug!("Shutting down; closing connection from {}", client_ip);
+            return;
+        }
+        let request_head = tokio::select! {
+            request_head = request::read_head(&mut client_conn, &state.timeouts) => request_head,
+            _ = shutdown.draining() => {
+                log::debug!("Shutting down; closing idle connection from {}", client_ip);
+                return;
+            }
+        };
+        let (mut request, request_body) = match request_head {
             Ok(request) => request,
             // Handle case where client closed connection and is no longer sending requests
             Err(request::Error::IncompleteRequest(0)) => {
@@ -205,57 +1130,515 @@ async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
                 log::info!("Error reading request from client stream: {}", io_err);
                 return;
             }
+            // Handle case where the client kept the connection open without sending anything
+            Err(request::Error::IdleTimeout) => {
+                log::debug!("Client connection was idle for too long. Shutting down connection");
+                return;
+            }
             Err(error) => {
                 log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
+                // After bad chunk framing (or a request cut short) there's no telling where the
+                // next request starts
+                let lost_framing = matches!(
+                    error,
+                    request::Error::InvalidTransferEncoding
+                        | request::Error::MalformedChunkedBody(_)
+                        | request::Error::HeaderTimeout
+                );
+                let status = match error {
                     request::Error::IncompleteRequest(_)
                     | request::Error::MalformedRequest(_)
                     | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
+                    | request::Error::ContentLengthMismatch
+                    | request::Error::InvalidTransferEncoding
+                    | request::Error::MalformedChunkedBody(_) => http::StatusCode::BAD_REQUEST,
                     request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
-                send_response(&mut client_conn, &response).await;
+                    request::Error::ConnectionError(_) | request::Error::IdleTimeout => {
+                        http::StatusCode::SERVICE_UNAVAILABLE
+                    }
+                    request::Error::HeaderTimeout => http::StatusCode::REQUEST_TIMEOUT,
+                };
+                let request_info = RequestInfo::new(client_addr, None);
+                let upstream = metrics::NO_UPSTREAM;
+                send_error(&mut client_conn, &request_info, state, upstream, status).await;
+                if lost_framing {
+                    return;
+                }
                 continue;
             }
         };
-        log::info!(
-            "{} -> {}: {}",
-            client_ip,
-            upstream_ip,
-            request::format_request_line(&request)
-        );
+        // Tag the request with an ID that the upstream, the access log and the client all see
+        headers::assign_request_id(request.headers_mut());
+        let request_info = RequestInfo::new(client_addr, Some(&request));
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        let (pool, affinity) = match route_request(state, client_addr, &request) {
+            Ok(route) => route,
+            Err(status) => {
+                let rejected =
+                    reject_request(&mut client_conn, &request_info, state, request_body, status);
+                if !rejected.await {
+                    return;
+                }
+                continue;
+            }
+        };
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!(
-                "Failed to send request to upstream {}: {}",
-                upstream_ip,
-                error
+        // Drop the headers that were only meant for this connection, and add X-Forwarded-For and
+        // friends so that the upstream server knows the client's IP address. (We're the ones
+        // connecting directly to the upstream server, so without these headers, the upstream
+        // server will only know our IP, not the client's.)
+        let client_version = request.version();
+        let client_wants_close = wants_close(client_version, request.headers());
+        let upgrade = tunnel::is_upgrade_request(&request);
+        headers::remove_hop_by_hop(request.headers_mut(), upgrade);
+        // An HTTP/1.0 upstream closes the connection after responding unless asked not to, and
+        // the connection to the upstream is ours to keep open
+        if request.version() == http::Version::HTTP_10 && !upgrade {
+            request.headers_mut().insert(
+                http::header::CONNECTION,
+                http::HeaderValue::from_static("keep-alive"),
             );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
+        }
+        headers::add_forwarding_headers(&mut request, client_addr, state.client_proto());
+        state.request_headers.apply(request.headers_mut());
+
+        // Answer from the cache if it has a fresh response
+        let has_body = request_body.framing() != body::Framing::Empty;
+        let cache = match lookup_cache(state, &request_info, &mut request, has_body).await {
+            CacheLookup::Hit(mut response) => {
+                let draining = shutdown.is_draining();
+                let close = draining || client_wants_close;
+                set_client_connection(response.headers_mut(), client_version, close);
+                let (status, bytes) = (response.status(), response.body().len() as u64);
+                record_request(state, &request_info, metrics::NO_UPSTREAM, status, bytes);
+                let cache_status = Some(CacheStatus::Hit);
+                send_response(&mut client_conn, &client_ip, response, cache_status).await;
+                if close {
+                    return;
+                }
+                continue;
+            }
+            CacheLookup::Forward(cache) => cache,
+        };
+
+        // A request can only be sent again if we still have its body, so small bodies are read in
+        // full before anything is sent upstream. Larger ones are streamed straight from the client.
+        let mut request_body = Some(request_body);
+        let mut replayable_body = None;
+        if let Some(pending) = request_body.take_if(|body| fits_in_retry_buffer(body.framing())) {
+            let mut buffered = Vec::new();
+            let mut client_reader = ReadTimeout::new(&mut client_conn, state.timeouts.body);
+            match pending.copy(&mut client_reader, &mut buffered).await {
+                Ok(()) => replayable_body = Some(buffered),
+                Err(error) => {
+                    log::info!("Error reading request body from client: {:?}", error);
+                    if let Some(status) = request_body_error_status(&error) {
+                        let upstream = metrics::NO_UPSTREAM;
+                        send_error(&mut client_conn, &request_info, state, upstream, status).await;
+                    }
+                    return;
+                }
+            }
+        }
+        let mut client_body = ClientBody {
+            client_conn: &mut client_conn,
+            timeout: state.timeouts.body,
+            buffered: replayable_body,
+            pending: request_body,
+        };
+        let exchanged = exchange(
+            state,
+            pool,
+            &affinity,
+            &client_ip,
+            &request,
+            &mut client_body,
+        );
+        let UpstreamResponse {
+            stream: mut upstream_conn,
+            guard: connection_guard,
+            mut response,
+            body: response_body,
+        } = match exchanged.await {
+            Ok(exchanged) => exchanged,
+            Err(ExchangeError::Upstream(upstream, status)) => {
+                send_error(&mut client_conn, &request_info, state, &upstream, status).await;
+                return;
+            }
+            // The upstream has been sent part of a request, so neither connection can be reused
+            Err(ExchangeError::RequestBody(upstream, error)) => {
+                log::info!("Error reading request body from client: {:?}", error);
+                if let Some(status) = request_body_error_status(&error) {
+                    send_error(&mut client_conn, &request_info, state, &upstream, status).await;
+                }
+                return;
+            }
+        };
+        let upstream_ip = connection_guard.address().to_string();
+        let upstream_wants_close = wants_close(response.version(), response.headers());
+        // The client gets our HTTP version, whichever one the upstream speaks
+        *response.version_mut() = http::Version::HTTP_11;
+        let switching = response.status() == http::StatusCode::SWITCHING_PROTOCOLS;
+        headers::remove_hop_by_hop(response.headers_mut(), switching);
+        let mut response_body = Some(response_body);
+        let updated = cache
+            .update(
+                state,
+                &request,
+                &mut response,
+                &mut response_body,
+                &mut upstream_conn,
+            )
+            .await;
+        if let Err(error) = updated {
+            log::error!("Error reading response body from server: {:?}", error);
+            connection_guard
+                .counters()
+                .failures
+                .fetch_add(1, Ordering::SeqCst);
+            let status = http::StatusCode::BAD_GATEWAY;
+            send_error(&mut client_conn, &request_info, state, &upstream_ip, status).await;
+            return;
+        }
+        if let Some(cookie) = affinity.set_cookie(&state.affinity_cookie, &upstream_ip) {
+            response
+                .headers_mut()
+                .append(http::header::SET_COOKIE, cookie);
+        }
+        rewrite_response_headers(state, &request_info, response.headers_mut());
+        // The upstream has agreed to switch protocols (e.g. to WebSocket), so from here on the two
+        // connections are just passed through to each other
+        if switching {
+            if !upgrade {
+                log::warn!(
+                    "Upstream {} switched protocols without being asked to",
+                    upstream_ip
+                );
+                let status = http::StatusCode::BAD_GATEWAY;
+                send_error(&mut client_conn, &request_info, state, &upstream_ip, status).await;
+                return;
+            }
+            log::info!(
+                "{} <- {}",
+                client_ip,
+                response::format_response_line(&response)
+            );
+            let status = response.status();
+            record_request(state, &request_info, &upstream_ip, status, 0);
+            if let Err(error) = response::write_head(&response, &mut client_conn).await {
+                log::warn!("Failed to send response to client: {}", error);
+                return;
+            }
+            log::info!("Tunnelling {} <-> {}", client_ip, upstream_ip);
+            let _active_tunnel = state.metrics.tunnel_opened(&upstream_ip);
+            let from_upstream = match response_body {
+                Some(response_body) => response_body.into_already_read(),
+                None => Vec::new(),
+            };
+            let idle_timeout = state.timeouts.tunnel_idle;
+            let (stats, result) = tunnel::run(
+                &mut client_conn,
+                &mut upstream_conn,
+                from_upstream,
+                idle_timeout,
+                upstream_disabled(state, &upstream_ip),
+            )
+            .await;
+            state
+                .metrics
+                .record_tunnel_bytes(&upstream_ip, stats.to_upstream, stats.to_client);
+            match result {
+                Ok(()) => log::info!("Tunnel {} <-> {} closed", client_ip, upstream_ip),
+                Err(error) => {
+                    log::info!("Tunnel {} <-> {} closed: {}", client_ip, upstream_ip, error)
+                }
+            }
             return;
         }
-        log::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
+        // Compress the body if the client accepts it. A body that is still on the upstream
+        // connection is compressed as it is forwarded, which needs chunked framing since its
+        // compressed length isn't known up front, and only HTTP/1.1 clients understand that.
+        let encoding = state.compression.as_ref().and_then(|settings| {
+            let body_framing = match &response_body {
+                Some(response_body) => response_body.framing(),
+                None => body::Framing::ContentLength(response.body().len()),
+            };
+            if response_body.is_some() && request.version() != http::Version::HTTP_11 {
+                return None;
+            }
+            compress::choose_encoding(settings, &request, &response, body_framing)
+        });
+        if let Some(encoding) = encoding {
+            if response_body.is_some() {
+                compress::mark_compressed(response.headers_mut(), encoding);
+                response.headers_mut().insert(
+                    http::header::TRANSFER_ENCODING,
+                    http::HeaderValue::from_static("chunked"),
+                );
+            } else if let Err(error) = compress::compress_response(&mut response, encoding).await {
+                log::error!("Failed to compress response: {}", error);
+                let status = http::StatusCode::INTERNAL_SERVER_ERROR;
+                send_error(&mut client_conn, &request_info, state, &upstream_ip, status).await;
                 return;
             }
+        }
+
+        // Let the client know whether it can send anything more on this connection
+        let draining = shutdown.is_draining();
+        let until_close = framing(&response_body) == body::Framing::UntilClose;
+        // An HTTP/1.0 client doesn't understand chunked framing, so a chunked body is decoded on
+        // its way through and its end is marked by hanging up
+        let dechunk = client_version == http::Version::HTTP_10
+            && encoding.is_none()
+            && framing(&response_body) == body::Framing::Chunked;
+        if dechunk {
+            response
+                .headers_mut()
+                .remove(http::header::TRANSFER_ENCODING);
+        }
+        let close = draining || client_wants_close || until_close || dechunk;
+        set_client_connection(response.headers_mut(), client_version, close);
+        // Forward the response to the client, streaming the body straight from the upstream
+        log::info!(
+            "{} <- {}",
+            client_ip,
+            response::format_response_line(&response)
+        );
+        let mut client_writer = CountingWriter::new(&mut client_conn);
+        let forwarded = match response::write_head(&response, &mut client_writer).await {
+            Ok(()) => {
+                // Only count the body, like other access logs do
+                client_writer.count = 0;
+                match (response_body, encoding) {
+                    (Some(response_body), None) if dechunk => {
+                        let mut upstream_reader =
+                            ReadTimeout::new(&mut upstream_conn, state.timeouts.body);
+                        response_body
+                            .copy_decoded(&mut upstream_reader, &mut client_writer)
+                            .await
+                    }
+                    (Some(response_body), None) => {
+                        let mut upstream_reader =
+                            ReadTimeout::new(&mut upstream_conn, state.timeouts.body);
+                        response_body
+                            .copy(&mut upstream_reader, &mut client_writer)
+                            .await
+                    }
+                    (Some(response_body), Some(encoding)) => {
+                        let mut upstream_reader =
+                            ReadTimeout::new(&mut upstream_conn, state.timeouts.body);
+                        let mut encoder = encoding.encoder(ChunkedWriter::new(&mut client_writer));
+                        match response_body
+                            .copy_decoded(&mut upstream_reader, &mut encoder)
+                            .await
+                        {
+                            Ok(()) => encoder.shutdown().await.map_err(body::Error::Write),
+                            Err(error) => Err(error),
+                        }
+                    }
+                    // The body has already been read for the cache
+                    (None, _) => client_writer
+                        .write_all(response.body())
+                        .await
+                        .map_err(body::Error::Write),
+                }
+            }
+            Err(error) => Err(body::Error::Write(error)),
         };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+        let (upstream, status) = (connection_guard.address(), response.status());
+        record_request(state, &request_info, upstream, status, client_writer.count);
+        match forwarded {
+            Ok(()) => log::debug!("Forwarded response to client"),
+            Err(body::Error::Write(error)) => {
+                log::warn!("Failed to send response to client: {}", error);
+                return;
+            }
+            // We've already started sending the response, so all we can do is hang up
+            Err(error) => {
+                log::error!("Error reading response body from server: {:?}", error);
+                connection_guard
+                    .counters()
+                    .failures
+                    .fetch_add(1, Ordering::SeqCst);
+                return;
+            }
+        }
+        // The end of the body was marked by the upstream hanging up, so the client needs to see
+        // us hang up too
+        if until_close {
+            return;
+        }
+        if draining {
+            log::debug!("Shutting down; closing connection from {}", client_ip);
+            return;
+        }
+
+        // Hand the upstream connection back to the pool for a later request, unless the upstream
+        // asked for it to be closed or was drained or disabled through the admin interface. The
+        // client's Connection header was never passed on, so the client hanging up doesn't stop
+        // the upstream connection from being reused.
+        let status = state.upstream_status.read().get(&upstream_ip).copied();
+        if upstream_wants_close {
+            log::debug!(
+                "Closing connection to upstream {} as requested",
+                upstream_ip
+            );
+        } else if status.unwrap_or_default() == UpstreamStatus::Enabled {
+            state.pool.put(&upstream_ip, upstream_conn);
+        }
+        if client_wants_close {
+            log::debug!("Closing connection from {} as requested", client_ip);
+            return;
+        }
+        if dechunk {
+            return;
+        }
+    }
+}
+
+/// Sends an error response in place of the upstream's, recording it as the response to the request.
+async fn send_error<S: AsyncWrite + Unpin>(
+    client_conn: &mut S,
+    request_info: &RequestInfo,
+    state: &ProxyState,
+    upstream: &str,
+    status: http::StatusCode,
+) {
+    let mut response = response::make_http_error(status);
+    if let Some(request_id) = &request_info.request_id {
+        response
+            .headers_mut()
+            .insert(headers::REQUEST_ID, request_id.clone());
+    }
+    let bytes = response.body().len() as u64;
+    record_request(state, request_info, upstream, status, bytes);
+    send_response(
+        client_conn,
+        &request_info.client_ip.to_string(),
+        response,
+        None,
+    )
+    .await;
+}
+
+/// Records a finished request in the metrics and, if it's enabled, the access log. `bytes` is the
+/// size of the response body sent to the client.
+fn record_request(
+    state: &ProxyState,
+    request_info: &RequestInfo,
+    upstream: &str,
+    status: http::StatusCode,
+    bytes: u64,
+) {
+    let elapsed = request_info.start.elapsed();
+    state.metrics.record_request(upstream, status, elapsed);
+    if let Some(access_log) = &state.access_log {
+        let upstream = Some(upstream).filter(|&upstream| upstream != metrics::NO_UPSTREAM);
+        access_log.log(request_info, upstream, status, bytes, elapsed);
+    }
+}
+
+/// Echoes the request's ID back to the client, then applies the response header rules to a
+/// response from an upstream (or the cache).
+fn rewrite_response_headers(
+    state: &ProxyState,
+    request_info: &RequestInfo,
+    response_headers: &mut http::HeaderMap,
+) {
+    if let Some(request_id) = &request_info.request_id {
+        response_headers.insert(headers::REQUEST_ID, request_id.clone());
+    }
+    state.response_headers.apply(response_headers);
+}
+
+/// Responds to a request that won't be forwarded with the given error status, then throws away its
+/// body so that the client's next request can be read. Returns false if the connection can't be
+/// used any more.
+async fn reject_request<S: AsyncRead + AsyncWrite + Unpin>(
+    client_conn: &mut S,
+    request_info: &RequestInfo,
+    state: &ProxyState,
+    request_body: body::PendingBody,
+    status: http::StatusCode,
+) -> bool {
+    send_error(
+        client_conn,
+        request_info,
+        state,
+        metrics::NO_UPSTREAM,
+        status,
+    )
+    .await;
+    let mut client_reader = ReadTimeout::new(client_conn, state.timeouts.body);
+    let discarded = request_body
+        .copy(&mut client_reader, &mut tokio::io::sink())
+        .await;
+    if let Err(error) = discarded {
+        log::debug!("Error discarding body of rejected request: {:?}", error);
+        return false;
     }
+    true
+}
+
+/// Works out what to tell a client whose request body couldn't be read: 408 if it stopped sending
+/// the body, 400 if the body was malformed, or nothing if the connection itself failed.
+fn request_body_error_status(error: &body::Error) -> Option<http::StatusCode> {
+    match error {
+        body::Error::Read(err) if err.kind() == io::ErrorKind::TimedOut => {
+            Some(http::StatusCode::REQUEST_TIMEOUT)
+        }
+        body::Error::Read(_) | body::Error::Write(_) => None,
+        body::Error::Incomplete | body::Error::TooLong | body::Error::MalformedChunkedBody(_) => {
+            Some(http::StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+/// Returns true if the request may be sent again after a failed attempt: its method is idempotent,
+/// or the client marked it safe to repeat with an Idempotency-Key header.
+fn is_retryable(request: &http::Request<Vec<u8>>) -> bool {
+    matches!(
+        *request.method(),
+        http::Method::GET | http::Method::HEAD | http::Method::PUT | http::Method::DELETE
+    ) || request.headers().contains_key("idempotency-key")
+}
+
+/// Returns true if the request body is small enough to hold on to in case the request needs to be
+/// retried.
+fn fits_in_retry_buffer(framing: body::Framing) -> bool {
+    match framing {
+        body::Framing::Empty => true,
+        body::Framing::ContentLength(len) => len <= MAX_RETRY_BODY_SIZE,
+        body::Framing::Chunked | body::Framing::UntilClose => false,
+    }
+}
+
+/// Tells the client whether it can send another request on this connection. An HTTP/1.0 client
+/// expects the connection to be closed unless the response says otherwise.
+fn set_client_connection(headers: &mut http::HeaderMap, version: http::Version, close: bool) {
+    let value = match (close, version) {
+        (true, _) => "close",
+        (false, http::Version::HTTP_10) => "keep-alive",
+        (false, _) => return,
+    };
+    headers.insert(
+        http::header::CONNECTION,
+        http::HeaderValue::from_static(value),
+    );
+}
+
+/// How the rest of a response body is framed, if it hasn't been read yet.
+fn framing(response_body: &Option<body::PendingBody>) -> body::Framing {
+    response_body
+        .as_ref()
+        .map_or(body::Framing::Empty, |body| body.framing())
+}
+
+/// Returns true if the connection should be closed after this message, either because the
+/// Connection header says so or because it's an HTTP/1.0 message that didn't ask for keep-alive.
+fn wants_close(version: http::Version, headers: &http::HeaderMap) -> bool {
+    request::has_connection_option(headers, "close")
+        || (version == http::Version::HTTP_10
+            && !request::has_connection_option(headers, "keep-alive"))
 }
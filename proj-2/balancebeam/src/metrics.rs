@@ -0,0 +1,372 @@
+use crate::cache::CacheStatus;
+use crate::{request, response};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Upper bounds (in seconds) of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Label used for requests that never made it to an upstream (e.g. because none were live)
+pub const NO_UPSTREAM: &str = "none";
+
+/// A Prometheus-style cumulative histogram.
+struct Histogram {
+    /// Number of observations less than or equal to each of LATENCY_BUCKETS
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: [0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Counters and histograms describing what balancebeam has been doing, exported in the Prometheus
+/// text format. Labelled metrics are kept in BTreeMaps so the output comes out in a stable order.
+pub struct Metrics {
+    /// Responses sent to clients, by upstream and status code
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    /// Time from reading a request to sending its response, by upstream
+    latency: Mutex<BTreeMap<String, Histogram>>,
+    /// Failed attempts to connect to each upstream
+    connect_failures: Mutex<BTreeMap<String, u64>>,
+    /// Active health check probes, by upstream and whether they passed
+    health_checks: Mutex<BTreeMap<(String, bool), u64>>,
+    /// Requests rejected because the client went over its rate limit
+    rate_limited: AtomicU64,
+    /// Client connections currently open
+    active_connections: Arc<AtomicI64>,
+    /// Connections that have switched protocols and are being tunnelled, by upstream
+    tunnels: Mutex<BTreeMap<String, u64>>,
+    /// Bytes passed through tunnels, by upstream and direction ("to_upstream" or "to_client")
+    tunnel_bytes: Mutex<BTreeMap<(String, &'static str), u64>>,
+    /// Tunnels currently open
+    active_tunnels: Arc<AtomicI64>,
+    /// Requests the response cache could have answered, by whether it did ("HIT" or "MISS")
+    cache_lookups: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// Keeps a client connection (or tunnel) counted in its gauge until it is dropped.
+pub struct ActiveConnectionGuard {
+    active_connections: Arc<AtomicI64>,
+}
+
+impl Drop for ActiveConnectionGuard {
+    fn drop(&mut self) {
+        self.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Escapes a label value as required by the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            requests: Mutex::new(BTreeMap::new()),
+            latency: Mutex::new(BTreeMap::new()),
+            connect_failures: Mutex::new(BTreeMap::new()),
+            health_checks: Mutex::new(BTreeMap::new()),
+            rate_limited: AtomicU64::new(0),
+            active_connections: Arc::new(AtomicI64::new(0)),
+            tunnels: Mutex::new(BTreeMap::new()),
+            tunnel_bytes: Mutex::new(BTreeMap::new()),
+            active_tunnels: Arc::new(AtomicI64::new(0)),
+            cache_lookups: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records a response sent to a client, and how long it took to produce.
+    pub fn record_request(&self, upstream: &str, status: http::StatusCode, elapsed: Duration) {
+        *self
+            .requests
+            .lock()
+            .entry((upstream.to_string(), status.as_u16()))
+            .or_insert(0) += 1;
+        self.latency
+            .lock()
+            .entry(upstream.to_string())
+            .or_insert_with(Histogram::new)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_connect_failure(&self, upstream: &str) {
+        *self
+            .connect_failures
+            .lock()
+            .entry(upstream.to_string())
+            .or_insert(0) += 1;
+    }
+
+    pub fn record_health_check(&self, upstream: &str, passed: bool) {
+        *self
+            .health_checks
+            .lock()
+            .entry((upstream.to_string(), passed))
+            .or_insert(0) += 1;
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Counts a newly opened client connection until the returned guard is dropped.
+    pub fn connection_opened(&self) -> ActiveConnectionGuard {
+        self.active_connections.fetch_add(1, Ordering::SeqCst);
+        ActiveConnectionGuard {
+            active_connections: self.active_connections.clone(),
+        }
+    }
+
+    /// Counts a newly opened tunnel to the given upstream, and keeps it in the active tunnels
+    /// gauge until the returned guard is dropped.
+    pub fn tunnel_opened(&self, upstream: &str) -> ActiveConnectionGuard {
+        *self.tunnels.lock().entry(upstream.to_string()).or_insert(0) += 1;
+        self.active_tunnels.fetch_add(1, Ordering::SeqCst);
+        ActiveConnectionGuard {
+            active_connections: self.active_tunnels.clone(),
+        }
+    }
+
+    /// Records how many bytes went each way through a tunnel once it has closed.
+    pub fn record_tunnel_bytes(&self, upstream: &str, to_upstream: u64, to_client: u64) {
+        let mut tunnel_bytes = self.tunnel_bytes.lock();
+        *tunnel_bytes
+            .entry((upstream.to_string(), "to_upstream"))
+            .or_insert(0) += to_upstream;
+        *tunnel_bytes
+            .entry((upstream.to_string(), "to_client"))
+            .or_insert(0) += to_client;
+    }
+
+    pub fn record_cache_lookup(&self, status: CacheStatus) {
+        *self
+            .cache_lookups
+            .lock()
+            .entry(status.as_str())
+            .or_insert(0) += 1;
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP balancebeam_requests_total Responses sent to clients.\n");
+        out.push_str("# TYPE balancebeam_requests_total counter\n");
+        for ((upstream, status), count) in self.requests.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_requests_total{{upstream=\"{}\",status=\"{}\"}} {}",
+                escape_label(upstream),
+                status,
+                count
+            )
+            .unwrap();
+        }
+
+        out.push_str(
+            "# HELP balancebeam_request_duration_seconds Time from reading a request to sending \
+            its response.\n",
+        );
+        out.push_str("# TYPE balancebeam_request_duration_seconds histogram\n");
+        for (upstream, histogram) in self.latency.lock().iter() {
+            let upstream = escape_label(upstream);
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                writeln!(
+                    out,
+                    "balancebeam_request_duration_seconds_bucket{{upstream=\"{}\",le=\"{}\"}} {}",
+                    upstream, bound, count
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "balancebeam_request_duration_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}} {}",
+                upstream, histogram.count
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "balancebeam_request_duration_seconds_sum{{upstream=\"{}\"}} {}",
+                upstream, histogram.sum
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "balancebeam_request_duration_seconds_count{{upstream=\"{}\"}} {}",
+                upstream, histogram.count
+            )
+            .unwrap();
+        }
+
+        out.push_str(
+            "# HELP balancebeam_upstream_connect_failures_total Failed attempts to connect to an \
+            upstream.\n",
+        );
+        out.push_str("# TYPE balancebeam_upstream_connect_failures_total counter\n");
+        for (upstream, count) in self.connect_failures.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_upstream_connect_failures_total{{upstream=\"{}\"}} {}",
+                escape_label(upstream),
+                count
+            )
+            .unwrap();
+        }
+
+        out.push_str("# HELP balancebeam_health_checks_total Active health check probes.\n");
+        out.push_str("# TYPE balancebeam_health_checks_total counter\n");
+        for ((upstream, passed), count) in self.health_checks.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_health_checks_total{{upstream=\"{}\",result=\"{}\"}} {}",
+                escape_label(upstream),
+                if *passed { "pass" } else { "fail" },
+                count
+            )
+            .unwrap();
+        }
+
+        out.push_str(
+            "# HELP balancebeam_rate_limited_requests_total Requests rejected for going over the \
+            rate limit.\n",
+        );
+        out.push_str("# TYPE balancebeam_rate_limited_requests_total counter\n");
+        writeln!(
+            out,
+            "balancebeam_rate_limited_requests_total {}",
+            self.rate_limited.load(Ordering::SeqCst)
+        )
+        .unwrap();
+
+        out.push_str("# HELP balancebeam_active_connections Client connections currently open.\n");
+        out.push_str("# TYPE balancebeam_active_connections gauge\n");
+        writeln!(
+            out,
+            "balancebeam_active_connections {}",
+            self.active_connections.load(Ordering::SeqCst)
+        )
+        .unwrap();
+
+        out.push_str(
+            "# HELP balancebeam_tunnels_total Connections that switched protocols and were \
+            tunnelled to an upstream.\n",
+        );
+        out.push_str("# TYPE balancebeam_tunnels_total counter\n");
+        for (upstream, count) in self.tunnels.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_tunnels_total{{upstream=\"{}\"}} {}",
+                escape_label(upstream),
+                count
+            )
+            .unwrap();
+        }
+
+        out.push_str(
+            "# HELP balancebeam_tunnel_bytes_total Bytes passed through closed tunnels.\n",
+        );
+        out.push_str("# TYPE balancebeam_tunnel_bytes_total counter\n");
+        for ((upstream, direction), count) in self.tunnel_bytes.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_tunnel_bytes_total{{upstream=\"{}\",direction=\"{}\"}} {}",
+                escape_label(upstream),
+                direction,
+                count
+            )
+            .unwrap();
+        }
+
+        out.push_str("# HELP balancebeam_active_tunnels Tunnels currently open.\n");
+        out.push_str("# TYPE balancebeam_active_tunnels gauge\n");
+        writeln!(
+            out,
+            "balancebeam_active_tunnels {}",
+            self.active_tunnels.load(Ordering::SeqCst)
+        )
+        .unwrap();
+
+        out.push_str(
+            "# HELP balancebeam_cache_requests_total Requests the response cache could have \
+            answered, by whether it did.\n",
+        );
+        out.push_str("# TYPE balancebeam_cache_requests_total counter\n");
+        for (result, count) in self.cache_lookups.lock().iter() {
+            writeln!(
+                out,
+                "balancebeam_cache_requests_total{{result=\"{}\"}} {}",
+                result.to_ascii_lowercase(),
+                count
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, metrics: &Metrics) {
+    loop {
+        let request = match request::read_from_stream(&mut stream).await {
+            Ok(request) => request,
+            Err(_) => return,
+        };
+        let response = if request.uri().path() == "/metrics" {
+            let body = metrics.render().into_bytes();
+            http::Response::builder()
+                .status(http::StatusCode::OK)
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .header("Content-Length", body.len().to_string())
+                .version(http::Version::HTTP_11)
+                .body(body)
+                .unwrap()
+        } else {
+            response::make_http_error(http::StatusCode::NOT_FOUND)
+        };
+        if let Err(error) = response::write_to_stream(&response, &mut stream).await {
+            log::warn!("Failed to send metrics response: {}", error);
+            return;
+        }
+    }
+}
+
+/// Serves `GET /metrics` on the given listener for Prometheus to scrape.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        if let Ok((stream, _)) = listener.accept().await {
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                handle_connection(stream, &metrics).await;
+            });
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use crate::upstream::UpstreamStream;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// A keep-alive connection to an upstream that isn't carrying a request right now.
+struct IdleConnection {
+    stream: UpstreamStream,
+    /// When the connection was put back in the pool
+    idle_since: Instant,
+}
+
+impl IdleConnection {
+    /// Returns true if the connection can still be used: it hasn't been idle for too long, and
+    /// the upstream hasn't closed it (or, unexpectedly, sent something on it).
+    fn is_usable(&self, now: Instant, idle_timeout: Duration) -> bool {
+        if now.duration_since(self.idle_since) >= idle_timeout {
+            return false;
+        }
+        // Peek underneath any TLS: pending bytes there (e.g. a close_notify alert) make the
+        // connection unusable just the same
+        let mut buffer = [0_u8; 1];
+        matches!(
+            self.stream.tcp().try_read(&mut buffer),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock
+        )
+    }
+}
+
+/// Idle keep-alive connections to each upstream, so that requests can reuse a connection instead
+/// of opening a new one every time.
+pub struct ConnectionPool {
+    /// Most idle connections to keep per upstream (0 disables pooling)
+    max_idle: usize,
+    /// How long a connection may sit idle before it is closed
+    idle_timeout: Duration,
+    /// Idle connections by upstream address, least recently used first
+    idle: Mutex<HashMap<String, Vec<IdleConnection>>>,
+}
+
+impl ConnectionPool {
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> ConnectionPool {
+        ConnectionPool {
+            max_idle,
+            idle_timeout,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if this pool uses the given settings, in which case it can be kept (along with
+    /// its connections) across a configuration reload.
+    pub fn has_settings(&self, max_idle: usize, idle_timeout: Duration) -> bool {
+        self.max_idle == max_idle && self.idle_timeout == idle_timeout
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Takes an idle connection to the given upstream out of the pool, if there is a usable one.
+    /// The most recently used connection is handed out first, so that the rest can time out if
+    /// they aren't needed.
+    pub fn take(&self, address: &str) -> Option<UpstreamStream> {
+        let now = Instant::now();
+        let mut idle = self.idle.lock();
+        let connections = idle.get_mut(address)?;
+        while let Some(connection) = connections.pop() {
+            if connection.is_usable(now, self.idle_timeout) {
+                return Some(connection.stream);
+            }
+        }
+        None
+    }
+
+    /// Puts a connection back in the pool once the response on it has been read in full. If the
+    /// pool is full, the least recently used connection to the upstream is closed.
+    pub fn put(&self, address: &str, stream: UpstreamStream) {
+        if self.max_idle == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock();
+        let connections = idle.entry(address.to_string()).or_default();
+        if connections.len() >= self.max_idle {
+            connections.remove(0);
+        }
+        connections.push(IdleConnection {
+            stream,
+            idle_since: Instant::now(),
+        });
+    }
+
+    /// Closes every idle connection to the given upstream.
+    pub fn remove(&self, address: &str) {
+        self.idle.lock().remove(address);
+    }
+
+    /// Closes connections that have been idle for too long or that the upstream has closed.
+    pub fn evict_expired(&self) {
+        let now = Instant::now();
+        let mut idle = self.idle.lock();
+        for connections in idle.values_mut() {
+            connections.retain(|connection| connection.is_usable(now, self.idle_timeout));
+        }
+        idle.retain(|_, connections| !connections.is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How requests are counted against the per-IP limit.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitStrategy {
+    /// Count requests in consecutive, non-overlapping windows. Cheap, but a client can send up to
+    /// twice the limit in a short burst straddling a window boundary.
+    Fixed,
+    /// Approximate a true sliding window by weighting the previous window's count by how much of
+    /// it still overlaps the sliding window. This smooths out the boundary bursts.
+    Sliding,
+}
+
+/// Request counts for a single client IP.
+struct WindowCounter {
+    /// When the current window started
+    window_start: Instant,
+    /// Number of requests seen in the current window
+    current: usize,
+    /// Number of requests seen in the window immediately before the current one (only used by the
+    /// sliding window strategy)
+    previous: usize,
+}
+
+impl WindowCounter {
+    fn new(now: Instant) -> WindowCounter {
+        WindowCounter {
+            window_start: now,
+            current: 0,
+            previous: 0,
+        }
+    }
+
+    /// Advances the counter so that `now` falls inside the current window.
+    fn roll(&mut self, now: Instant, window: Duration) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < window {
+            return;
+        }
+        let windows_passed = (elapsed.as_nanos() / window.as_nanos()) as u32;
+        // If more than one window went by, the previous window saw no requests at all
+        self.previous = if windows_passed == 1 { self.current } else { 0 };
+        self.current = 0;
+        self.window_start += window * windows_passed;
+    }
+}
+
+/// Limits the number of requests each client IP can make within a window of time.
+pub struct RateLimiter {
+    max_requests: usize,
+    window: Duration,
+    strategy: RateLimitStrategy,
+    counters: Mutex<HashMap<IpAddr, WindowCounter>>,
+}
+
+impl RateLimiter {
+    pub fn new(max_requests: usize, window: Duration, strategy: RateLimitStrategy) -> RateLimiter {
+        RateLimiter {
+            max_requests,
+            window,
+            strategy,
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if this limiter enforces the given settings, in which case it can be kept
+    /// (along with its counts) across a configuration reload.
+    pub fn has_settings(&self, max_requests: usize, strategy: RateLimitStrategy) -> bool {
+        self.max_requests == max_requests && self.strategy == strategy
+    }
+
+    /// Records a request from the given IP. Returns true if the request is allowed, or false if
+    /// the client has exceeded its limit and the request should be rejected. Rejected requests do
+    /// not count against the limit.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut counters = self.counters.lock();
+        let counter = counters
+            .entry(ip)
+            .or_insert_with(|| WindowCounter::new(now));
+        counter.roll(now, self.window);
+
+        let count = match self.strategy {
+            RateLimitStrategy::Fixed => counter.current as f64,
+            RateLimitStrategy::Sliding => {
+                let into_window = now.duration_since(counter.window_start).as_secs_f64()
+                    / self.window.as_secs_f64();
+                counter.previous as f64 * (1.0 - into_window) + counter.current as f64
+            }
+        };
+        if count + 1.0 > self.max_requests as f64 {
+            return false;
+        }
+        counter.current += 1;
+        true
+    }
+
+    /// Drops counters for clients that have not made any requests recently enough to affect the
+    /// limit, so that the table does not grow without bound.
+    pub fn evict_expired(&self) {
+        let now = Instant::now();
+        // The sliding window still looks at the previous window, so keep entries around for one
+        // extra window
+        let ttl = match self.strategy {
+            RateLimitStrategy::Fixed => self.window,
+            RateLimitStrategy::Sliding => self.window * 2,
+        };
+        let mut counters = self.counters.lock();
+        let before = counters.len();
+        counters.retain(|_, counter| now.duration_since(counter.window_start) < ttl);
+        log::debug!(
+            "Rate limiter evicted {} idle clients ({} remaining)",
+            before - counters.len(),
+            counters.len()
+        );
+    }
+}
@@ -1,11 +1,16 @@
+use crate::body::{Framing, PendingBody};
+use crate::chunked;
+use crate::timeout::Timeouts;
 use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
+// The wrapped errors are only read through the Debug impl when logging
+#[allow(dead_code, clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
     /// Client hung up before sending a complete request. IncompleteRequest contains the number of
@@ -19,8 +24,17 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     RequestBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// The Transfer-Encoding header is present, but chunked is not the final coding, so there is no
+    /// way to tell where the body ends
+    InvalidTransferEncoding,
+    /// The request body is chunked, but the chunk framing is invalid or incomplete
+    MalformedChunkedBody(chunked::Error),
+    /// Encountered an I/O error when reading/writing the stream
     ConnectionError(std::io::Error),
+    /// The client didn't start sending a request before the idle timeout
+    IdleTimeout,
+    /// The client started sending a request, but didn't finish the headers in time
+    HeaderTimeout,
 }
 
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
@@ -66,6 +80,15 @@ pub fn extend_header_value(
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
+/// Returns true if the Connection header lists the given option (e.g. "close"), ignoring case.
+pub fn has_connection_option(headers: &http::HeaderMap, option: &str) -> bool {
+    headers.get_all("connection").iter().any(|value| {
+        String::from_utf8_lossy(value.as_bytes())
+            .split(',')
+            .any(|listed| listed.trim().eq_ignore_ascii_case(option))
+    })
+}
+
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
 /// following:
 ///
@@ -74,16 +97,20 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req.parse(buffer).or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(Error::MalformedRequest)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
             .method(req.method.unwrap())
             .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(match req.version {
+                Some(0) => http::Version::HTTP_10,
+                _ => http::Version::HTTP_11,
+            });
         for header in req.headers {
             request = request.header(header.name, header.value);
         }
@@ -100,21 +127,43 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
 ///
 /// Returns Ok(http::Request) if a valid request is received, or Error if not.
 ///
+/// If timeouts are given, the client has `client_idle` to start sending the request, and then
+/// `client_header` to finish sending the headers.
+///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    timeouts: Option<&Timeouts>,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
     let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
     let mut bytes_read = 0;
+    let mut deadline = timeouts.map(|timeouts| Instant::now() + timeouts.client_idle);
     loop {
         // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..]).await.unwrap();
+        let read = stream.read(&mut request_buffer[bytes_read..]);
+        let new_bytes = match deadline {
+            Some(deadline) => {
+                tokio::time::timeout_at(deadline, read)
+                    .await
+                    .map_err(|_| match bytes_read {
+                        0 => Error::IdleTimeout,
+                        _ => Error::HeaderTimeout,
+                    })?
+            }
+            None => read.await,
+        }
+        .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
             return Err(Error::IncompleteRequest(bytes_read));
         }
+        if bytes_read == 0 {
+            // The client has started a request, so the header timeout starts now
+            deadline = timeouts.map(|timeouts| Instant::now() + timeouts.client_header);
+        }
         bytes_read += new_bytes;
 
         // See if we've read a valid request so far
@@ -136,8 +185,8 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
 /// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
 ) -> Result<(), Error> {
@@ -146,7 +195,10 @@ async fn read_body(
         // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
         // space to read that body.)
         let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream.read(&mut buffer).await.unwrap();
+        let bytes_read = stream
+            .read(&mut buffer)
+            .await
+            .map_err(Error::ConnectionError)?;
 
         // Make sure the client is still sending us bytes
         if bytes_read == 0 {
@@ -173,45 +225,115 @@ async fn read_body(
     Ok(())
 }
 
+/// Works out how the body of a request is framed from its headers. If the body is chunked, any
+/// Content-Length header is removed, since it must not be passed along (RFC 7230 section 3.3.3).
+fn body_framing(request: &mut http::Request<Vec<u8>>) -> Result<Framing, Error> {
+    if chunked::transfer_codings(request.headers()).is_some() {
+        // Chunked has to be the final coding, or there is no way to tell where the body ends
+        if !chunked::is_chunked(request.headers()) {
+            return Err(Error::InvalidTransferEncoding);
+        }
+        request.headers_mut().remove("content-length");
+        return Ok(Framing::Chunked);
+    }
+    match get_content_length(request)? {
+        Some(content_length) if content_length > 0 => Ok(Framing::ContentLength(content_length)),
+        _ => Ok(Framing::Empty),
+    }
+}
+
 /// This function reads and returns an HTTP request from a stream, returning an Error if the client
 /// closes the connection prematurely or sends an invalid request.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Read headers
-    let mut request = read_headers(stream).await?;
-    // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
-            return Err(Error::RequestBodyTooLarge);
-        } else {
-            read_body(stream, &mut request, content_length).await?;
+    let mut request = read_headers(stream, None).await?;
+    match body_framing(&mut request)? {
+        Framing::Chunked => {
+            let already_read = std::mem::take(request.body_mut());
+            let (body, trailers) = chunked::read_body(stream, already_read, MAX_BODY_SIZE)
+                .await
+                .map_err(|err| match err {
+                    chunked::Error::TooLarge => Error::RequestBodyTooLarge,
+                    chunked::Error::ConnectionError(io_err) => Error::ConnectionError(io_err),
+                    err => Error::MalformedChunkedBody(err),
+                })?;
+            *request.body_mut() = body;
+            request.extensions_mut().insert(trailers);
+        }
+        // Read body if the client supplied the Content-Length header (which it does for POST
+        // requests)
+        Framing::ContentLength(content_length) => {
+            if content_length > MAX_BODY_SIZE {
+                return Err(Error::RequestBodyTooLarge);
+            } else {
+                read_body(stream, &mut request, content_length).await?;
+            }
         }
+        Framing::Empty | Framing::UntilClose => {}
     }
     Ok(request)
 }
 
+/// Reads just the request line and headers from a stream. The returned request has an empty body;
+/// the body is left on the stream, to be passed along with PendingBody::copy. Unlike
+/// read_from_stream, this puts no limit on the size of the body, but the client only has as long as
+/// the idle and header timeouts allow to send the headers.
+pub async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    timeouts: &Timeouts,
+) -> Result<(http::Request<Vec<u8>>, PendingBody), Error> {
+    let mut request = read_headers(stream, Some(timeouts)).await?;
+    let framing = body_framing(&mut request)?;
+    let already_read = std::mem::take(request.body_mut());
+    Ok((request, PendingBody::new(already_read, framing)))
+}
+
+/// Writes the request line and headers of a request to the provided stream, leaving the body to
+/// the caller.
+pub async fn write_head<S: AsyncWrite + Unpin>(
+    request: &http::Request<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    stream
+        .write_all(&format_request_line(request).into_bytes())
+        .await?;
+    stream.write_all(b"\r\n").await?;
+    for (header_name, header_value) in request.headers() {
+        stream
+            .write_all(format!("{}: ", header_name).as_bytes())
+            .await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+    }
+    stream.write_all(b"\r\n").await?;
+    Ok(())
+}
+
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_request_line(request).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    for (header_name, header_value) in request.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    write_head(request, stream).await?;
+    if chunked::is_chunked(request.headers()) {
+        chunked::write_body(stream, request.body(), request.extensions().get()).await?;
+    } else if !request.body().is_empty() {
+        stream.write_all(request.body()).await?;
     }
     Ok(())
 }
 
 pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
-    format!("{} {} {:?}", request.method(), request.uri(), request.version())
+    format!(
+        "{} {} {:?}",
+        request.method(),
+        request.uri(),
+        request.version()
+    )
 }
@@ -1,10 +1,13 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use crate::body::{Framing, PendingBody};
+use crate::chunked;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
+// The wrapped errors are only read through the Debug impl when logging
+#[allow(dead_code, clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
     /// Client hung up before sending a complete request
@@ -17,7 +20,9 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     ResponseBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// The response body is chunked, but the chunk framing is invalid or incomplete
+    MalformedChunkedBody(chunked::Error),
+    /// Encountered an I/O error when reading/writing the stream
     ConnectionError(std::io::Error),
 }
 
@@ -52,17 +57,19 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 ///   Err(Error)
 ///
 /// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
-    let res = resp
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+    let res = resp.parse(buffer).map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
             .status(resp.code.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(match resp.version {
+                Some(0) => http::Version::HTTP_10,
+                _ => http::Version::HTTP_11,
+            });
         for header in resp.headers {
             response = response.header(header.name, header.value);
         }
@@ -80,7 +87,9 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
@@ -89,7 +98,9 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
     loop {
         // Read bytes from the connection into the buffer, starting at position bytes_read
         let new_bytes = stream
-            .read(&mut response_buffer[bytes_read..]).await.unwrap();
+            .read(&mut response_buffer[bytes_read..])
+            .await
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete response
             return Err(Error::IncompleteResponse);
@@ -109,20 +120,44 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
     }
 }
 
-/// This function reads the body for a response from the stream. If the Content-Length header is
-/// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
+/// This function reads the body for a response from the stream. If the body is chunked, it decodes
+/// the chunks; otherwise, if the Content-Length header is present, it reads that many bytes;
+/// otherwise, it reads bytes until the connection is closed.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
-    // The response may or may not supply a Content-Length header. If it provides the header, then
-    // we want to read that number of bytes; if it does not, we want to keep reading bytes until
-    // the connection is closed.
-    let content_length = get_content_length(response)?;
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    response: &mut http::Response<Vec<u8>>,
+    framing: Framing,
+) -> Result<(), Error> {
+    let content_length = match framing {
+        Framing::Empty => return Ok(()),
+        Framing::Chunked => {
+            let already_read = std::mem::take(response.body_mut());
+            let (body, trailers) = chunked::read_body(stream, already_read, MAX_BODY_SIZE)
+                .await
+                .map_err(|err| match err {
+                    chunked::Error::TooLarge => Error::ResponseBodyTooLarge,
+                    chunked::Error::ConnectionError(io_err) => Error::ConnectionError(io_err),
+                    err => Error::MalformedChunkedBody(err),
+                })?;
+            *response.body_mut() = body;
+            response.extensions_mut().insert(trailers);
+            return Ok(());
+        }
+        // The response may or may not supply a Content-Length header. If it provides the header,
+        // then we want to read that number of bytes; if it does not, we want to keep reading bytes
+        // until the connection is closed.
+        Framing::ContentLength(content_length) => Some(content_length),
+        Framing::UntilClose => None,
+    };
 
     while content_length.is_none() || response.body().len() < content_length.unwrap() {
         let mut buffer = [0_u8; 512];
         let bytes_read = stream
-            .read(&mut buffer).await.unwrap();
+            .read(&mut buffer)
+            .await
+            .map_err(Error::ConnectionError)?;
         if bytes_read == 0 {
             // The server has hung up!
             if content_length.is_none() {
@@ -152,44 +187,98 @@ async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>
     Ok(())
 }
 
-/// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
+/// Works out how the body of a response is framed from its headers and the method of the request
+/// it answers. If the response has a Transfer-Encoding, any Content-Length header is removed, since
+/// it must not be passed along (RFC 7230 section 3.3.3).
+fn body_framing(
+    response: &mut http::Response<Vec<u8>>,
     request_method: &http::Method,
-) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
+) -> Result<Framing, Error> {
     // A response may have a body as long as it is not responding to a HEAD request and as long as
     // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
+    if request_method == http::Method::HEAD
         || response.status().as_u16() < 200
         || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
+        || response.status() == http::StatusCode::NOT_MODIFIED
     {
-        read_body(stream, &mut response).await?;
+        return Ok(Framing::Empty);
+    }
+    // Transfer-Encoding takes precedence over Content-Length. If the response has a
+    // Transfer-Encoding that doesn't end in chunked, the body runs until the server closes the
+    // connection.
+    if chunked::transfer_codings(response.headers()).is_some() {
+        response.headers_mut().remove("content-length");
+        if chunked::is_chunked(response.headers()) {
+            return Ok(Framing::Chunked);
+        }
+        return Ok(Framing::UntilClose);
+    }
+    match get_content_length(response)? {
+        Some(content_length) => Ok(Framing::ContentLength(content_length)),
+        None => Ok(Framing::UntilClose),
     }
-    Ok(response)
 }
 
-/// This function serializes a response to bytes and writes those bytes to the provided stream.
+/// This function reads and returns an HTTP response from a stream, returning an Error if the server
+/// closes the connection prematurely or sends an invalid response.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    request_method: &http::Method,
+) -> Result<http::Response<Vec<u8>>, Error> {
+    let mut response = read_headers(stream).await?;
+    let framing = body_framing(&mut response, request_method)?;
+    read_body(stream, &mut response, framing).await?;
+    Ok(response)
+}
+
+/// Reads just the status line and headers from a stream. The returned response has an empty body;
+/// the body is left on the stream, to be passed along with PendingBody::copy. Unlike
+/// read_from_stream, this puts no limit on the size of the body.
+pub async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    request_method: &http::Method,
+) -> Result<(http::Response<Vec<u8>>, PendingBody), Error> {
+    let mut response = read_headers(stream).await?;
+    let framing = body_framing(&mut response, request_method)?;
+    let already_read = std::mem::take(response.body_mut());
+    Ok((response, PendingBody::new(already_read, framing)))
+}
+
+/// Writes the status line and headers of a response to the provided stream, leaving the body to
+/// the caller.
+pub async fn write_head<S: AsyncWrite + Unpin>(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_response_line(response).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream
+        .write_all(&format_response_line(response).into_bytes())
+        .await?;
+    stream.write_all(b"\r\n").await?;
     for (header_name, header_value) in response.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream
+            .write_all(format!("{}: ", header_name).as_bytes())
+            .await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+/// This function serializes a response to bytes and writes those bytes to the provided stream.
+///
+/// You will need to modify this function in Milestone 2.
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
+    response: &http::Response<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    write_head(response, stream).await?;
+    if chunked::is_chunked(response.headers()) {
+        chunked::write_body(stream, response.body(), response.extensions().get()).await?;
+    } else if !response.body().is_empty() {
+        stream.write_all(response.body()).await?;
     }
     Ok(())
 }
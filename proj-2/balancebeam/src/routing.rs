@@ -0,0 +1,162 @@
+use crate::affinity::AffinityMode;
+use crate::balancing::{LoadBalancingStrategy, StrategyKind};
+use crate::config::Config;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The pool that upstreams given with `--upstream` (or `[[upstream]]` in the configuration file)
+/// belong to. Requests that don't match any route are sent here.
+pub const DEFAULT_POOL: &str = "default";
+
+/// A named group of upstreams that is balanced separately from the others. Only the settings are
+/// kept here; upstreams know which pool they belong to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoolSettings {
+    pub name: String,
+    /// Falls back to the top-level strategy if not given
+    pub strategy: Option<StrategyKind>,
+    /// Falls back to the top-level affinity mode if not given
+    pub affinity: Option<AffinityMode>,
+    /// Falls back to the top-level health check path if not given
+    pub health_check_path: Option<String>,
+}
+
+/// Sends requests that match every condition given to the named pool. Routes are tried in the
+/// order they are configured, and the first one that matches wins.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    pub pool: String,
+    /// Hostname the request is for (ignoring any port). A wildcard like `*.example.com` matches
+    /// any subdomain of example.com.
+    pub host: Option<String>,
+    /// The request path must start with this
+    pub path_prefix: Option<String>,
+    pub method: Option<String>,
+    /// Headers that must be present with exactly these values
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl Route {
+    fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        if let Some(host) = &self.host {
+            match request_host(request) {
+                Some(request_host) if host_matches(host, &request_host) => {}
+                _ => return false,
+            }
+        }
+        if let Some(path_prefix) = &self.path_prefix {
+            if !request.uri().path().starts_with(path_prefix.as_str()) {
+                return false;
+            }
+        }
+        if let Some(method) = &self.method {
+            if !request.method().as_str().eq_ignore_ascii_case(method) {
+                return false;
+            }
+        }
+        self.headers.iter().all(|(name, value)| {
+            request
+                .headers()
+                .get_all(name.as_str())
+                .iter()
+                .any(|actual| actual.as_bytes() == value.as_bytes())
+        })
+    }
+}
+
+/// Returns the lowercase hostname the request is for, taken from an absolute request URI or else
+/// the Host header, without the port.
+fn request_host(request: &http::Request<Vec<u8>>) -> Option<String> {
+    if let Some(host) = request.uri().host() {
+        return Some(host.to_ascii_lowercase());
+    }
+    let host = request.headers().get("host")?.to_str().ok()?;
+    let host = match host.rsplit_once(':') {
+        Some((hostname, port))
+            if !hostname.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            hostname
+        }
+        _ => host,
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(parent) => host
+            .split_once('.')
+            .is_some_and(|(_, host_parent)| host_parent.eq_ignore_ascii_case(parent)),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// A pool as used while proxying, with its settings filled in.
+pub struct Pool {
+    pub name: String,
+    /// Decides which of the pool's live upstreams each request goes to
+    pub strategy: Arc<dyn LoadBalancingStrategy>,
+    /// How requests are pinned to the pool's upstreams
+    pub affinity: AffinityMode,
+    /// Where health checks for the pool's upstreams are sent
+    pub health_check_path: String,
+}
+
+/// Decides which pool each request goes to.
+pub struct Router {
+    routes: Vec<Route>,
+    pools: HashMap<String, Pool>,
+}
+
+impl Router {
+    /// Builds the routes and pools in the given configuration. The default pool always exists,
+    /// even if no upstreams are in it.
+    pub fn new(config: &Config) -> Router {
+        let default_pool = PoolSettings {
+            name: DEFAULT_POOL.to_string(),
+            strategy: None,
+            affinity: None,
+            health_check_path: None,
+        };
+        let pools = std::iter::once(&default_pool)
+            .chain(&config.pools)
+            .map(|settings| {
+                let pool = Pool {
+                    name: settings.name.clone(),
+                    strategy: settings.strategy.unwrap_or(config.strategy()).build(),
+                    affinity: settings.affinity.unwrap_or(config.affinity),
+                    health_check_path: settings
+                        .health_check_path
+                        .clone()
+                        .unwrap_or_else(|| config.active_health_check_path.clone()),
+                };
+                (settings.name.clone(), pool)
+            })
+            .collect();
+        Router {
+            routes: config.routes.clone(),
+            pools,
+        }
+    }
+
+    /// Returns the pool of the first route that matches the request, or None if no route matches.
+    pub fn route(&self, request: &http::Request<Vec<u8>>) -> Option<&Pool> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(request))
+            .and_then(|route| self.pools.get(&route.pool))
+    }
+
+    pub fn pool(&self, name: &str) -> Option<&Pool> {
+        self.pools.get(name)
+    }
+
+    /// Returns the path to send health checks to for upstreams in the given pool.
+    pub fn health_check_path(&self, pool: &str) -> &str {
+        let pool = self.pool(pool).unwrap_or_else(|| &self.pools[DEFAULT_POOL]);
+        &pool.health_check_path
+    }
+}
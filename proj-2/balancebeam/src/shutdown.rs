@@ -0,0 +1,92 @@
+use std::io;
+use std::time::Duration;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+
+/// Listens for the signals that ask balancebeam to shut down.
+pub struct TerminationSignals {
+    terminate: Signal,
+    interrupt: Signal,
+}
+
+impl TerminationSignals {
+    pub fn new() -> io::Result<TerminationSignals> {
+        Ok(TerminationSignals {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    /// Waits for SIGTERM or SIGINT, returning the name of the one that arrived.
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
+        }
+    }
+}
+
+/// Tells client connections when balancebeam starts shutting down, and keeps track of which ones
+/// are still open.
+pub struct Shutdown {
+    draining: watch::Sender<bool>,
+    /// Every connection holds a clone of this sender, so the receiver sees the channel close once
+    /// the last connection is done
+    active: mpsc::Sender<()>,
+    all_closed: mpsc::Receiver<()>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (active, all_closed) = mpsc::channel(1);
+        Shutdown {
+            draining: watch::channel(false).0,
+            active,
+            all_closed,
+        }
+    }
+
+    /// Returns a handle for a new client connection, which counts as open until the handle is
+    /// dropped.
+    pub fn handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            draining: self.draining.subscribe(),
+            _active: self.active.clone(),
+        }
+    }
+
+    /// Asks every connection to finish its current request and close, then waits up to
+    /// `deadline` for them to do so. Returns false if some were still open at the deadline.
+    pub async fn drain(self, deadline: Duration) -> bool {
+        let Shutdown {
+            draining,
+            active,
+            mut all_closed,
+        } = self;
+        draining.send_replace(true);
+        drop(active);
+        tokio::time::timeout(deadline, all_closed.recv())
+            .await
+            .is_ok()
+    }
+}
+
+/// Lets a client connection know when balancebeam is shutting down.
+pub struct ShutdownHandle {
+    draining: watch::Receiver<bool>,
+    _active: mpsc::Sender<()>,
+}
+
+impl ShutdownHandle {
+    /// Returns true once balancebeam has started shutting down.
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Completes once balancebeam starts shutting down.
+    pub async fn draining(&mut self) {
+        // The sender is only dropped once drain() has finished waiting, so an error can't happen
+        // before then
+        let _ = self.draining.wait_for(|&draining| draining).await;
+    }
+}
@@ -0,0 +1,89 @@
+use crate::affinity::{Affinity, AffinityMode};
+use crate::circuit_breaker::Outcome;
+use crate::routing::DEFAULT_POOL;
+use crate::shutdown::ShutdownHandle;
+use crate::{connect_to_upstream, tunnel, upstream_disabled, ProxyState, UpstreamConnection};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// What balancebeam proxies, selected with `--mode`.
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyMode {
+    /// Parse HTTP requests and balance each one separately
+    Http,
+    /// Pass each client connection's bytes straight through to one upstream, for protocols that
+    /// aren't HTTP. Health checks only check that the upstream accepts connections.
+    Tcp,
+}
+
+/// Connects the client to an upstream in the default pool and passes bytes both ways until both
+/// sides have hung up. If no upstream can be connected to, the client is simply hung up on.
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut client_conn: S,
+    client_addr: SocketAddr,
+    state: &ProxyState,
+    // Held until the connection closes so that shutting down waits for it
+    _shutdown: ShutdownHandle,
+) {
+    let client_addr = client_addr.ip();
+    log::info!("Connection received from {}", client_addr);
+    let _active_connection = state.metrics.connection_opened();
+
+    if let Some(rate_limiter) = &state.rate_limiter {
+        if !rate_limiter.check(client_addr) {
+            log::info!("Rate limiting {}: closing connection", client_addr);
+            state.metrics.record_rate_limited();
+            return;
+        }
+    }
+
+    let pool = state.router.pool(DEFAULT_POOL).unwrap();
+    let affinity = match pool.affinity {
+        AffinityMode::IpHash => Affinity::ClientIp(client_addr),
+        // Cookie affinity is rejected when the configuration is loaded
+        AffinityMode::None | AffinityMode::Cookie => Affinity::None,
+    };
+    let UpstreamConnection {
+        stream: mut upstream_conn,
+        guard,
+        permit,
+        ..
+    } = match connect_to_upstream(state, pool, &affinity, &HashSet::new(), true).await {
+        Ok(connection) => connection,
+        Err(error) => {
+            log::warn!("Closing connection from {}: {}", client_addr, error);
+            return;
+        }
+    };
+    permit.record(Outcome::Success);
+    guard.counters().requests.fetch_add(1, Ordering::SeqCst);
+
+    let upstream_ip = guard.address().to_string();
+    log::info!("Tunnelling {} <-> {}", client_addr, upstream_ip);
+    let _active_tunnel = state.metrics.tunnel_opened(&upstream_ip);
+    let idle_timeout = state.timeouts.tunnel_idle;
+    let (stats, result) = tunnel::run(
+        &mut client_conn,
+        &mut upstream_conn,
+        Vec::new(),
+        idle_timeout,
+        upstream_disabled(state, &upstream_ip),
+    )
+    .await;
+    state
+        .metrics
+        .record_tunnel_bytes(&upstream_ip, stats.to_upstream, stats.to_client);
+    match result {
+        Ok(()) => log::info!("Tunnel {} <-> {} closed", client_addr, upstream_ip),
+        Err(error) => log::info!(
+            "Tunnel {} <-> {} closed: {}",
+            client_addr,
+            upstream_ip,
+            error
+        ),
+    }
+}
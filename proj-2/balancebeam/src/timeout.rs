@@ -0,0 +1,74 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// How long each phase of handling a request may take before balancebeam gives up on it.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Opening a connection to an upstream (including the TLS handshake)
+    pub upstream_connect: Duration,
+    /// Receiving a request's headers, once the client has started sending them
+    pub client_header: Duration,
+    /// Waiting for more of a request or response body to arrive
+    pub body: Duration,
+    /// Waiting for an upstream to send its response headers after it has been sent the request
+    pub upstream_response: Duration,
+    /// Waiting for a client to start its next request on a keep-alive connection
+    pub client_idle: Duration,
+    /// Waiting for either side of a tunnelled (e.g. WebSocket) connection to send something
+    pub tunnel_idle: Duration,
+}
+
+/// Wraps a reader so that a read fails with `TimedOut` if no data arrives within the timeout. The
+/// clock only runs while a read is waiting, so time spent passing data along doesn't count.
+pub struct ReadTimeout<'a, R> {
+    inner: &'a mut R,
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+    /// Whether the last read returned Pending, i.e. the next poll continues the same read
+    waiting: bool,
+}
+
+impl<'a, R> ReadTimeout<'a, R> {
+    pub fn new(inner: &'a mut R, timeout: Duration) -> ReadTimeout<'a, R> {
+        ReadTimeout {
+            inner,
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+            waiting: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ReadTimeout<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.waiting {
+            this.sleep.as_mut().reset(Instant::now() + this.timeout);
+        }
+        match Pin::new(&mut *this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.waiting = false;
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                this.waiting = true;
+                match this.sleep.as_mut().poll(cx) {
+                    Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no data received for {:?}", this.timeout),
+                    ))),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+#[derive(Debug)]
+pub enum Error {
+    /// A certificate or key file could not be read
+    Io(PathBuf, std::io::Error),
+    /// The certificate file doesn't contain any PEM-encoded certificates
+    NoCertificates(PathBuf),
+    /// The key file doesn't contain a PEM-encoded private key
+    NoPrivateKey(PathBuf),
+    /// The private key is not an RSA, ECDSA or Ed25519 key
+    UnsupportedKey(PathBuf),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            Error::NoCertificates(path) => write!(f, "no certificates found in {}", path.display()),
+            Error::NoPrivateKey(path) => write!(f, "no private key found in {}", path.display()),
+            Error::UnsupportedKey(path) => {
+                write!(f, "unsupported private key type in {}", path.display())
+            }
+        }
+    }
+}
+
+/// A certificate to present to clients that ask for `hostname` using SNI. The hostname may be a
+/// wildcard like `*.example.com`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SniCert {
+    pub hostname: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Parses `hostname=cert.pem,key.pem`, as given to `--tls-sni-cert`.
+impl FromStr for SniCert {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SniCert, String> {
+        let (hostname, paths) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected HOSTNAME=CERT,KEY but got \"{}\"", s))?;
+        let (cert, key) = paths
+            .split_once(',')
+            .ok_or_else(|| format!("expected HOSTNAME=CERT,KEY but got \"{}\"", s))?;
+        Ok(SniCert {
+            hostname: hostname.to_string(),
+            cert: PathBuf::from(cert),
+            key: PathBuf::from(key),
+        })
+    }
+}
+
+/// Where to find the certificates used to terminate TLS from clients. TLS is enabled if there is a
+/// default certificate or at least one SNI certificate.
+#[derive(Clone, Debug, Default)]
+pub struct TlsSettings {
+    /// Certificate chain presented when no SNI certificate matches
+    pub cert: Option<PathBuf>,
+    /// Private key for `cert`
+    pub key: Option<PathBuf>,
+    pub sni: Vec<SniCert>,
+}
+
+impl TlsSettings {
+    pub fn is_enabled(&self) -> bool {
+        self.cert.is_some() || !self.sni.is_empty()
+    }
+}
+
+/// Picks the certificate to present based on the hostname the client asked for.
+struct CertResolver {
+    /// Certificates by lowercase hostname
+    by_hostname: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if let Some(server_name) = client_hello.server_name() {
+            let server_name = server_name.to_ascii_lowercase();
+            if let Some(cert) = self.by_hostname.get(&server_name) {
+                return Some(cert.clone());
+            }
+            // Try a wildcard certificate for the parent domain
+            if let Some((_, parent)) = server_name.split_once('.') {
+                if let Some(cert) = self.by_hostname.get(&format!("*.{}", parent)) {
+                    return Some(cert.clone());
+                }
+            }
+        }
+        self.default.clone()
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, Error> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| Error::Io(path.to_path_buf(), err))
+}
+
+/// Loads a PEM certificate chain.
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|err| Error::Io(path.to_path_buf(), err))?;
+    if certs.is_empty() {
+        return Err(Error::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Loads the first RSA, PKCS#8 or EC private key in a PEM file.
+fn load_private_key(path: &Path) -> Result<PrivateKey, Error> {
+    for item in rustls_pemfile::read_all(&mut open(path)?)
+        .map_err(|err| Error::Io(path.to_path_buf(), err))?
+    {
+        match item {
+            rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::ECKey(der) => return Ok(PrivateKey(der)),
+            _ => {}
+        }
+    }
+    Err(Error::NoPrivateKey(path.to_path_buf()))
+}
+
+/// Loads a certificate chain and its private key from PEM files.
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<Arc<CertifiedKey>, Error> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|_| Error::UnsupportedKey(key_path.to_path_buf()))?;
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
+
+/// Loads every certificate named in the settings and builds an acceptor that terminates TLS with
+/// them. If `http2` is set, clients can negotiate HTTP/2 through ALPN. Returns None if TLS isn't
+/// enabled.
+pub fn build_acceptor(settings: &TlsSettings, http2: bool) -> Result<Option<TlsAcceptor>, Error> {
+    if !settings.is_enabled() {
+        return Ok(None);
+    }
+    let default = match (&settings.cert, &settings.key) {
+        (Some(cert), Some(key)) => Some(load_certified_key(cert, key)?),
+        _ => None,
+    };
+    let mut by_hostname = HashMap::new();
+    for sni_cert in &settings.sni {
+        by_hostname.insert(
+            sni_cert.hostname.to_ascii_lowercase(),
+            load_certified_key(&sni_cert.cert, &sni_cert.key)?,
+        );
+    }
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(CertResolver {
+            by_hostname,
+            default,
+        }));
+    config.alpn_protocols = match http2 {
+        true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        false => vec![b"http/1.1".to_vec()],
+    };
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// How to connect to `https://` upstreams.
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamTlsSettings {
+    /// CA certificates to trust. If not given, the system's trusted CAs are used.
+    pub ca: Option<PathBuf>,
+    /// Certificate chain to present to upstreams that require mutual TLS
+    pub client_cert: Option<PathBuf>,
+    /// Private key for `client_cert`
+    pub client_key: Option<PathBuf>,
+}
+
+/// Where common Linux distributions and BSDs keep their bundle of trusted CA certificates.
+const SYSTEM_CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/pki/ca-trust/extracted/pem/tls-ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+/// Finds the system's CA bundle, preferring the one named by `SSL_CERT_FILE` like OpenSSL does.
+fn system_ca_bundle() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("SSL_CERT_FILE") {
+        return Some(PathBuf::from(path));
+    }
+    SYSTEM_CA_BUNDLES
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+}
+
+/// Builds the store of CAs that upstream certificates must chain to.
+fn load_root_store(settings: &UpstreamTlsSettings) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    match &settings.ca {
+        Some(ca) => {
+            let certs = load_certs(ca)?;
+            let (added, _) = roots.add_parsable_certificates(
+                &certs.into_iter().map(|cert| cert.0).collect::<Vec<_>>(),
+            );
+            if added == 0 {
+                return Err(Error::NoCertificates(ca.clone()));
+            }
+        }
+        None => match system_ca_bundle() {
+            Some(bundle) => {
+                let certs = rustls_pemfile::certs(&mut open(&bundle)?)
+                    .map_err(|err| Error::Io(bundle.clone(), err))?;
+                roots.add_parsable_certificates(&certs);
+            }
+            None => log::warn!("No system CA bundle found; give one with --upstream-ca"),
+        },
+    }
+    Ok(roots)
+}
+
+/// Builds a connector for `https://` upstreams from the settings, loading the CA bundle and the
+/// client certificate (if any).
+pub fn build_connector(settings: &UpstreamTlsSettings) -> Result<TlsConnector, Error> {
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(load_root_store(settings)?);
+    let mut config = match (&settings.client_cert, &settings.client_key) {
+        (Some(cert), Some(key)) => builder
+            .with_single_cert(load_certs(cert)?, load_private_key(key)?)
+            .map_err(|_| Error::UnsupportedKey(key.clone()))?,
+        _ => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsConnector::from(Arc::new(config)))
+}
@@ -0,0 +1,104 @@
+use crate::request;
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+/// Size of the buffer used for each direction of a tunnel.
+const TUNNEL_BUFFER_SIZE: usize = 16 * 1024;
+
+/// How many bytes went each way through a tunnel.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TunnelStats {
+    pub to_upstream: u64,
+    pub to_client: u64,
+}
+
+/// Returns true if the request asks to switch the connection to another protocol (e.g. a
+/// WebSocket handshake), in which case the upstream may answer with 101 Switching Protocols.
+pub fn is_upgrade_request(request: &http::Request<Vec<u8>>) -> bool {
+    request.headers().contains_key(http::header::UPGRADE)
+        && request::has_connection_option(request.headers(), "upgrade")
+}
+
+/// Passes bytes both ways between a client and an upstream that have switched to another
+/// protocol, until both sides have hung up or nothing has been sent either way for `idle_timeout`.
+/// `from_upstream` is whatever the upstream already sent after its 101 response. If `closed`
+/// completes first, the tunnel ends early with the error it returns.
+///
+/// Each side's hang-up is passed on to the other as a write shutdown, so a side can still receive
+/// after it has finished sending. Returns how much was passed each way, along with the error that
+/// ended the tunnel, if any.
+pub async fn run<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+    from_upstream: Vec<u8>,
+    idle_timeout: Duration,
+    closed: impl Future<Output = io::Error>,
+) -> (TunnelStats, io::Result<()>)
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stats = TunnelStats::default();
+    let passed = pass_bytes(client, upstream, from_upstream, idle_timeout, &mut stats);
+    let result = tokio::select! {
+        result = passed => result,
+        error = closed => Err(error),
+    };
+    (stats, result)
+}
+
+async fn pass_bytes<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+    from_upstream: Vec<u8>,
+    idle_timeout: Duration,
+    stats: &mut TunnelStats,
+) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    client.write_all(&from_upstream).await?;
+    stats.to_client += from_upstream.len() as u64;
+
+    let (mut client_reader, mut client_writer) = tokio::io::split(client);
+    let (mut upstream_reader, mut upstream_writer) = tokio::io::split(upstream);
+    let mut client_buffer = vec![0_u8; TUNNEL_BUFFER_SIZE];
+    let mut upstream_buffer = vec![0_u8; TUNNEL_BUFFER_SIZE];
+    let (mut client_done, mut upstream_done) = (false, false);
+    while !(client_done && upstream_done) {
+        let deadline = Instant::now() + idle_timeout;
+        tokio::select! {
+            read = client_reader.read(&mut client_buffer), if !client_done => match read? {
+                0 => {
+                    client_done = true;
+                    upstream_writer.shutdown().await?;
+                }
+                n => {
+                    upstream_writer.write_all(&client_buffer[..n]).await?;
+                    stats.to_upstream += n as u64;
+                }
+            },
+            read = upstream_reader.read(&mut upstream_buffer), if !upstream_done => match read? {
+                0 => {
+                    upstream_done = true;
+                    client_writer.shutdown().await?;
+                }
+                n => {
+                    client_writer.write_all(&upstream_buffer[..n]).await?;
+                    stats.to_client += n as u64;
+                }
+            },
+            _ = tokio::time::sleep_until(deadline) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("nothing sent either way for {:?}", idle_timeout),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
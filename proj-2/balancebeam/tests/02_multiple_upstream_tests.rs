@@ -2,8 +2,10 @@ mod common;
 
 use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
 
+use rand::Rng;
 use std::time::Duration;
-use tokio::time::sleep;
+use tokio::net::TcpListener;
+use tokio::time::{sleep, Instant};
 
 async fn setup_with_params(
     n_upstreams: usize,
@@ -271,7 +273,7 @@ async fn test_rate_limiting() {
     for i in 0..num_extra_requests {
         let client = reqwest::Client::new();
         let response = client
-            .get(&format!("http://{}/overboard-{}", balancebeam.address, i))
+            .get(format!("http://{}/overboard-{}", balancebeam.address, i))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await
@@ -294,3 +296,139 @@ async fn test_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// Waits up to `timeout` for the admin interface to report the upstream as being in rotation (or
+/// out of it, if `live` is false). Returns false if it never does.
+async fn wait_for_live(admin_address: &str, upstream: &str, live: bool, timeout: Duration) -> bool {
+    let url = format!("http://{}/upstreams/{}", admin_address, upstream);
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        let info: serde_json::Value = reqwest::get(&url)
+            .await
+            .expect("Error sending request to the admin interface")
+            .json()
+            .await
+            .expect("Admin interface replied with invalid JSON");
+        if info["live"].as_bool() == Some(live) {
+            return true;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+/// An upstream should only be taken out of rotation after --unhealthy-threshold failed probes in a
+/// row, and only put back after --healthy-threshold passing ones.
+#[tokio::test]
+async fn test_active_health_check_thresholds() {
+    init_logging();
+    let healthy = EchoServer::new().await;
+    let flaky: Box<dyn Server> = Box::new(EchoServer::new().await);
+    let flaky_address = flaky.address();
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&healthy.address, &flaky_address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--unhealthy-threshold",
+            "3",
+            "--healthy-threshold",
+            "2",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    log::info!("Replacing an upstream with a server that returns Error 500s...");
+    flaky.stop().await;
+    let failing: Box<dyn Server> =
+        Box::new(ErrorServer::new_at_address(flaky_address.clone()).await);
+    assert!(
+        wait_for_live(
+            &admin_address,
+            &flaky_address,
+            false,
+            Duration::from_secs(6)
+        )
+        .await,
+        "The failing upstream was never taken out of rotation"
+    );
+    // Probes may also have failed to connect while the servers were being swapped
+    failing.stop().await;
+    let failed_probes = balancebeam
+        .output()
+        .iter()
+        .take_while(|line| !line.contains(&format!("Upstream {} is unhealthy", flaky_address)))
+        .filter(|line| {
+            line.contains(&format!(
+                "Health check of upstream {} failed",
+                flaky_address
+            ))
+        })
+        .count();
+    assert_eq!(
+        failed_probes, 3,
+        "The upstream should be taken out after exactly 3 failed probes"
+    );
+
+    log::info!("Bringing the upstream back...");
+    let restored: Box<dyn Server> =
+        Box::new(EchoServer::new_at_address(flaky_address.clone()).await);
+    assert!(
+        wait_for_live(&admin_address, &flaky_address, true, Duration::from_secs(6)).await,
+        "The restored upstream was never put back in rotation"
+    );
+    assert_eq!(
+        restored.stop().await,
+        2,
+        "The upstream should be put back after exactly 2 passing probes"
+    );
+    log::info!("All done :)");
+}
+
+/// A probe that never gets an answer should count as failed without stopping the health checks, so
+/// that the upstream is still put back once it recovers.
+#[tokio::test]
+async fn test_active_health_checks_survive_failed_probes() {
+    init_logging();
+    let healthy = EchoServer::new().await;
+    let hung_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    // Accept connections but never answer on them
+    let listener = TcpListener::bind(&hung_address).await.unwrap();
+    let hung = tokio::spawn(async move {
+        let mut connections = Vec::new();
+        loop {
+            let (connection, _) = listener.accept().await.unwrap();
+            connections.push(connection);
+        }
+    });
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&healthy.address, &hung_address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-timeout",
+            "1",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+    assert!(
+        wait_for_live(&admin_address, &hung_address, false, Duration::from_secs(5)).await,
+        "The upstream that never answers was never taken out of rotation"
+    );
+
+    log::info!("Replacing the hung upstream with a working one...");
+    hung.abort();
+    let _ = hung.await;
+    let _recovered = EchoServer::new_at_address(hung_address.clone()).await;
+    assert!(
+        wait_for_live(&admin_address, &hung_address, true, Duration::from_secs(5)).await,
+        "The upstream was never put back in rotation. Active health checks may have stopped."
+    );
+    log::info!("All done :)");
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+/// Starts `n_upstreams` echo servers and a balancebeam instance in front of them. `weights`, if
+/// given, are appended to the upstream addresses as `address@weight`.
+async fn setup(
+    n_upstreams: usize,
+    weights: Option<&[usize]>,
+    extra_args: &[&str],
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in 0..n_upstreams {
+        upstreams.push(Box::new(EchoServer::new().await));
+    }
+    let upstream_args: Vec<String> = upstreams
+        .iter()
+        .enumerate()
+        .map(|(idx, upstream)| match weights {
+            Some(weights) => format!("{}@{}", upstream.address(), weights[idx]),
+            None => upstream.address(),
+        })
+        .collect();
+    let upstream_args: Vec<&str> = upstream_args.iter().map(|arg| arg.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(&upstream_args, extra_args).await;
+    (balancebeam, upstreams)
+}
+
+/// Sends `n_requests` requests (each on a new connection) and returns how many each upstream got.
+async fn send_requests(
+    balancebeam: &BalanceBeam,
+    mut upstreams: Vec<Box<dyn Server>>,
+    n_requests: usize,
+) -> Vec<usize> {
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    request_counters
+}
+
+/// Round robin should hand out connections in strict rotation, so every upstream gets exactly the
+/// same number of requests.
+#[tokio::test]
+async fn test_round_robin() {
+    let (balancebeam, upstreams) = setup(3, None, &["--strategy", "round-robin"]).await;
+    let request_counters = send_requests(&balancebeam, upstreams, 30).await;
+    assert_eq!(request_counters, vec![10, 10, 10]);
+
+    log::info!("All done :)");
+}
+
+/// With static weights, each upstream should get traffic in exact proportion to its weight.
+#[tokio::test]
+async fn test_weighted() {
+    let (balancebeam, upstreams) = setup(3, Some(&[3, 2, 1]), &["--strategy", "weighted"]).await;
+    let request_counters = send_requests(&balancebeam, upstreams, 36).await;
+    assert_eq!(request_counters, vec![18, 12, 6]);
+
+    log::info!("All done :)");
+}
+
+/// Least connections and power of two choices should send new requests away from an upstream that
+/// is still busy with a slow request. With two upstreams, power of two choices always compares
+/// both, so it should be as strict about this as least connections.
+#[tokio::test]
+async fn test_connection_based_strategies() {
+    for strategy in ["least-connections", "power-of-two"] {
+        log::info!("Testing strategy {}", strategy);
+        let (balancebeam, upstreams) = setup(2, None, &["--strategy", strategy]).await;
+
+        // Start a request whose body is too big to be buffered and never finishes, so that
+        // balancebeam holds a connection to one of the upstreams open for as long as we like
+        let mut slow_request = TcpStream::connect(&balancebeam.address).await.unwrap();
+        slow_request
+            .write_all(
+                b"POST /slow HTTP/1.1\r\nHost: balancebeam.test\r\n\
+                Content-Length: 1000000\r\n\r\nstart",
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        log::info!("Sending requests while the slow request is in progress");
+        for i in 0..10 {
+            let path = format!("/request-{}", i);
+            let response_text = balancebeam
+                .get(&path)
+                .await
+                .expect("Error sending request to balancebeam");
+            assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        }
+        drop(slow_request);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let mut request_counters = Vec::new();
+        for upstream in upstreams {
+            request_counters.push(upstream.stop().await);
+        }
+        log::info!(
+            "Number of requests received by each upstream: {:?}",
+            request_counters
+        );
+        // The busy upstream got the slow request and nothing else
+        request_counters.sort();
+        assert_eq!(request_counters, vec![1, 10]);
+    }
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, ConfigFile, EchoServer, Server};
+use nix::sys::signal::Signal;
+use std::time::Duration;
+use tokio::time::sleep;
+
+fn upstream_config(addresses: &[String]) -> String {
+    addresses
+        .iter()
+        .map(|address| format!("[[upstream]]\naddress = \"{}\"\n", address))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+async fn send_requests(balancebeam: &BalanceBeam, prefix: &str, n_requests: usize) {
+    for i in 0..n_requests {
+        let path = format!("/{}-{}", prefix, i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+/// Start balancebeam with upstreams given only in a configuration file, then rewrite the file to
+/// point somewhere else and send SIGHUP. New requests should go to the new upstream, and a broken
+/// configuration file should be ignored.
+#[tokio::test]
+async fn test_reload_on_sighup() {
+    init_logging();
+    let first: Box<dyn Server> = Box::new(EchoServer::new().await);
+    let second: Box<dyn Server> = Box::new(EchoServer::new().await);
+
+    let config_file = ConfigFile::new();
+    config_file.write(&upstream_config(&[first.address()]));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], &["--config", config_file.path.to_str().unwrap()]).await;
+
+    log::info!("Sending requests with the initial configuration");
+    send_requests(&balancebeam, "before-reload", 4).await;
+
+    log::info!("Switching the configuration over to the second upstream");
+    config_file.write(&upstream_config(&[second.address()]));
+    balancebeam.signal(Signal::SIGHUP);
+    sleep(Duration::from_millis(500)).await;
+    send_requests(&balancebeam, "after-reload", 6).await;
+
+    log::info!("Writing an invalid configuration. The current one should be kept.");
+    config_file.write("[[upstream]]\nweight = \"lots\"\n");
+    balancebeam.signal(Signal::SIGHUP);
+    sleep(Duration::from_millis(500)).await;
+    send_requests(&balancebeam, "after-bad-reload", 2).await;
+
+    assert_eq!(first.stop().await, 4);
+    assert_eq!(second.stop().await, 8);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+
+async fn admin_request(
+    admin_address: &str,
+    method: reqwest::Method,
+    path: &str,
+    body: Option<&str>,
+) -> (u16, serde_json::Value) {
+    let client = reqwest::Client::new();
+    let mut request = client.request(method, format!("http://{}{}", admin_address, path));
+    if let Some(body) = body {
+        request = request.body(body.to_string());
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to the admin interface");
+    let status = response.status().as_u16();
+    let body = response
+        .json()
+        .await
+        .expect("Admin interface replied with invalid JSON");
+    (status, body)
+}
+
+async fn send_requests(balancebeam: &BalanceBeam, prefix: &str, n_requests: usize) {
+    for i in 0..n_requests {
+        let path = format!("/{}-{}", prefix, i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+/// List upstreams, drain one and make sure it stops getting traffic, then add a new upstream at
+/// runtime and make sure it starts getting traffic.
+#[tokio::test]
+async fn test_admin_upstream_management() {
+    init_logging();
+    let first: Box<dyn Server> = Box::new(EchoServer::new().await);
+    let second: Box<dyn Server> = Box::new(EchoServer::new().await);
+    let third: Box<dyn Server> = Box::new(EchoServer::new().await);
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first.address(), &second.address()],
+        &["--strategy", "round-robin", "--admin-bind", &admin_address],
+    )
+    .await;
+
+    log::info!("Listing upstreams");
+    let (status, upstreams) =
+        admin_request(&admin_address, reqwest::Method::GET, "/upstreams", None).await;
+    assert_eq!(status, 200);
+    let upstreams = upstreams.as_array().expect("Expected a list of upstreams");
+    assert_eq!(upstreams.len(), 2);
+    assert_eq!(upstreams[0]["address"], first.address());
+    assert_eq!(upstreams[0]["live"], true);
+    assert_eq!(upstreams[0]["status"], "enabled");
+
+    log::info!("Draining the first upstream");
+    let (status, upstream) = admin_request(
+        &admin_address,
+        reqwest::Method::POST,
+        &format!("/upstreams/{}/drain", first.address()),
+        None,
+    )
+    .await;
+    assert_eq!(status, 200);
+    // Nothing was open to it, so it's drained already
+    assert_eq!(upstream["status"], "disabled");
+    assert_eq!(upstream["live"], false);
+    send_requests(&balancebeam, "drained", 4).await;
+
+    log::info!("Adding a third upstream");
+    let (status, upstream) = admin_request(
+        &admin_address,
+        reqwest::Method::POST,
+        "/upstreams",
+        Some(&format!("{{\"address\": \"{}\"}}", third.address())),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(upstream["live"], true);
+    send_requests(&balancebeam, "added", 4).await;
+
+    log::info!("Checking the counters");
+    let (_, upstream) = admin_request(
+        &admin_address,
+        reqwest::Method::GET,
+        &format!("/upstreams/{}", second.address()),
+        None,
+    )
+    .await;
+    assert_eq!(upstream["requests"], 6);
+
+    log::info!("Removing an unknown upstream should fail");
+    let (status, _) = admin_request(
+        &admin_address,
+        reqwest::Method::DELETE,
+        "/upstreams/127.0.0.1:1",
+        None,
+    )
+    .await;
+    assert_eq!(status, 404);
+
+    assert_eq!(first.stop().await, 0);
+    assert_eq!(second.stop().await, 6);
+    assert_eq!(third.stop().await, 2);
+
+    log::info!("All done :)");
+}
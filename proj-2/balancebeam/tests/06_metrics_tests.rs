@@ -0,0 +1,56 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+
+async fn scrape(metrics_address: &str) -> String {
+    reqwest::get(format!("http://{}/metrics", metrics_address))
+        .await
+        .expect("Error sending request to the metrics endpoint")
+        .text()
+        .await
+        .expect("Metrics endpoint replied with a malformed response")
+}
+
+/// Send some requests (a few of which get rate limited) and make sure the counters add up.
+#[tokio::test]
+async fn test_metrics() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let metrics_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--metrics-bind",
+            &metrics_address,
+            "--max-requests-per-minute",
+            "3",
+        ],
+    )
+    .await;
+
+    for i in 0..5 {
+        let path = format!("/request-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let metrics = scrape(&metrics_address).await;
+    log::info!("Scraped metrics:\n{}", metrics);
+    assert!(metrics.contains(&format!(
+        "balancebeam_requests_total{{upstream=\"{}\",status=\"200\"}} 3",
+        upstream.address
+    )));
+    assert!(metrics.contains("balancebeam_requests_total{upstream=\"none\",status=\"429\"} 2"));
+    assert!(metrics.contains("balancebeam_rate_limited_requests_total 2"));
+    assert!(metrics.contains(&format!(
+        "balancebeam_request_duration_seconds_count{{upstream=\"{}\"}} 3",
+        upstream.address
+    )));
+    assert!(metrics.contains("# TYPE balancebeam_active_connections gauge"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,235 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Reads from the stream until the data read so far ends with `terminator`, giving up after a few
+/// seconds.
+async fn read_until(stream: &mut TcpStream, terminator: &[u8]) -> String {
+    let mut buffer = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        let mut read_buffer = [0_u8; 512];
+        while !buffer.ends_with(terminator) {
+            let bytes_read = stream
+                .read(&mut read_buffer)
+                .await
+                .expect("Error reading from balancebeam");
+            assert!(bytes_read > 0, "balancebeam closed the connection early");
+            buffer.extend_from_slice(&read_buffer[..bytes_read]);
+        }
+    })
+    .await
+    .unwrap_or_else(|_| {
+        panic!(
+            "Timed out waiting for the response; got {:?}",
+            String::from_utf8_lossy(&buffer)
+        )
+    });
+    String::from_utf8(buffer).unwrap()
+}
+
+/// Send a chunked request body through balancebeam and make sure the upstream sees all of it.
+#[tokio::test]
+async fn test_chunked_request() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    stream
+        .write_all(
+            b"POST /chunked HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            6\r\nhello \r\n\
+            5;ext=1\r\nworld\r\n\
+            0\r\n\
+            \r\n",
+        )
+        .await
+        .unwrap();
+    // The echo server sends back the request body at the end of its response
+    let response = read_until(&mut stream, b"hello world").await;
+    log::info!("Response:\n{}", response);
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains("POST /chunked HTTP/1.1"));
+    assert!(response
+        .to_ascii_lowercase()
+        .contains("transfer-encoding: chunked"));
+
+    log::info!("Checking that the upstream got the request");
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure a chunked response, trailers and all, makes it back to the client.
+#[tokio::test]
+async fn test_chunked_response_with_trailers() {
+    init_logging();
+    let upstream_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&upstream_address).await.unwrap();
+    let upstream_task = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_until(&mut stream, b"\r\n\r\n").await;
+        stream
+            .write_all(
+                b"HTTP/1.1 200 OK\r\n\
+                Transfer-Encoding: chunked\r\n\
+                Trailer: X-Checksum\r\n\
+                \r\n\
+                4\r\nWiki\r\n\
+                5\r\npedia\r\n\
+                0\r\n\
+                X-Checksum: abc123\r\n\
+                \r\n",
+            )
+            .await
+            .unwrap();
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let response = read_until(&mut stream, b"X-Checksum: abc123\r\n\r\n").await;
+    log::info!("Response:\n{}", response);
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response
+        .to_ascii_lowercase()
+        .contains("transfer-encoding: chunked"));
+    assert!(response.contains("\r\n\r\n4\r\nWiki\r\n5\r\npedia\r\n0\r\n"));
+
+    upstream_task.await.unwrap();
+    log::info!("All done :)");
+}
+
+/// An HTTP/1.0 client doesn't understand chunked framing, so a chunked response should reach it
+/// decoded, with its end marked by the connection closing.
+#[tokio::test]
+async fn test_chunked_response_to_http10_client() {
+    init_logging();
+    let upstream_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&upstream_address).await.unwrap();
+    let upstream_task = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_until(&mut stream, b"\r\n\r\n").await;
+        stream
+            .write_all(
+                b"HTTP/1.1 200 OK\r\n\
+                Transfer-Encoding: chunked\r\n\
+                \r\n\
+                4\r\nWiki\r\n\
+                5\r\npedia\r\n\
+                0\r\n\
+                \r\n",
+            )
+            .await
+            .unwrap();
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    stream
+        .write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+        .await
+        .expect("balancebeam didn't close the connection")
+        .expect("Error reading from balancebeam");
+    log::info!("Response:\n{}", response);
+    assert!(response.starts_with("HTTP/1.1 200"));
+    let lowercase = response.to_ascii_lowercase();
+    assert!(!lowercase.contains("transfer-encoding"));
+    assert!(lowercase.contains("connection: close"));
+    assert!(response.ends_with("\r\n\r\nWikipedia"));
+
+    upstream_task.await.unwrap();
+    log::info!("All done :)");
+}
+
+/// Bad chunk framing should get a 400.
+#[tokio::test]
+async fn test_malformed_chunked_request() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    stream
+        .write_all(
+            b"POST / HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            zz\r\nhello\r\n\
+            0\r\n\
+            \r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .await
+        .expect("Error reading from balancebeam");
+    log::info!("Response:\n{}", response);
+    assert!(response.starts_with("HTTP/1.1 400"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// A chunk size too big to add to the body read so far should be turned away as too large, rather
+/// than overflowing. The admin interface reads whole request bodies, so it sees the chunk size.
+#[tokio::test]
+async fn test_huge_chunk_size() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let _balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--admin-bind", &admin_address]).await;
+
+    let mut stream = TcpStream::connect(&admin_address)
+        .await
+        .expect("Error connecting to the admin interface");
+    stream
+        .write_all(
+            b"POST /upstreams HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            1\r\nx\r\n\
+            ffffffffffffffff\r\nhello\r\n\
+            0\r\n\
+            \r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .await
+        .expect("Error reading from the admin interface");
+    log::info!("Response:\n{}", response);
+    assert!(response.starts_with("HTTP/1.1 400"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
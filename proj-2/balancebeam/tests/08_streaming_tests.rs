@@ -0,0 +1,96 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+/// Reads from the stream until the data read so far ends with `terminator`, giving up after a few
+/// seconds.
+async fn read_until(stream: &mut TcpStream, terminator: &[u8]) -> String {
+    let mut buffer = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        let mut read_buffer = [0_u8; 512];
+        while !buffer.ends_with(terminator) {
+            let bytes_read = stream
+                .read(&mut read_buffer)
+                .await
+                .expect("Error reading from stream");
+            assert!(bytes_read > 0, "Connection closed early");
+            buffer.extend_from_slice(&read_buffer[..bytes_read]);
+        }
+    })
+    .await
+    .unwrap_or_else(|_| {
+        panic!(
+            "Timed out waiting for data; got {:?}",
+            String::from_utf8_lossy(&buffer)
+        )
+    });
+    String::from_utf8(buffer).unwrap()
+}
+
+/// Upload a body bigger than balancebeam would ever buffer and make sure all of it gets through.
+#[tokio::test]
+async fn test_large_upload() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let body: Vec<u8> = (0..20_000_000).map(|i| (i % 251) as u8).collect();
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/upload", balancebeam.address))
+        .body(body.clone())
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let response_body = response
+        .bytes()
+        .await
+        .expect("Error reading response from balancebeam");
+    assert!(response_body.starts_with(b"POST /upload HTTP/1.1"));
+    assert!(response_body.ends_with(&body));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
+
+/// The start of a response should reach the client before the upstream has finished sending it.
+#[tokio::test]
+async fn test_response_is_streamed() {
+    init_logging();
+    let upstream_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&upstream_address).await.unwrap();
+    let (first_half_received_tx, first_half_received_rx) = oneshot::channel();
+    let upstream_task = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_until(&mut stream, b"\r\n\r\n").await;
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello")
+            .await
+            .unwrap();
+        // Hold back the rest of the body until the client has seen the first half
+        first_half_received_rx.await.unwrap();
+        stream.write_all(b"world").await.unwrap();
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Error connecting to balancebeam");
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let first_half = read_until(&mut stream, b"hello").await;
+    assert!(first_half.starts_with("HTTP/1.1 200"));
+    first_half_received_tx.send(()).unwrap();
+    read_until(&mut stream, b"world").await;
+
+    upstream_task.await.unwrap();
+    log::info!("All done :)");
+}
@@ -0,0 +1,102 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use rand::Rng;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// A bare-bones upstream that answers every request with the given response, and counts how many
+/// connections it has accepted. If `close` is set, it hangs up after each response.
+async fn start_upstream(response: &'static [u8], close: bool) -> (String, Arc<AtomicUsize>) {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&address).await.unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let connections_clone = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            connections_clone.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 512];
+                loop {
+                    let bytes_read = match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(bytes_read) => bytes_read,
+                    };
+                    request.extend_from_slice(&buffer[..bytes_read]);
+                    // None of the test requests have bodies, so a blank line ends each one
+                    if request.ends_with(b"\r\n\r\n") {
+                        request.clear();
+                        stream.write_all(response).await.unwrap();
+                        if close {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    (address, connections)
+}
+
+async fn send_requests(balancebeam: &BalanceBeam, n_requests: usize) {
+    for i in 0..n_requests {
+        let response_text = balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "ok");
+        // Give balancebeam a moment to put the upstream connection back in the pool
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Requests from separate client connections should all go over one upstream connection.
+#[tokio::test]
+async fn test_connections_are_reused() {
+    init_logging();
+    let (upstream_address, connections) =
+        start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok", false).await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    send_requests(&balancebeam, 5).await;
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+    log::info!("All done :)");
+}
+
+/// A connection the upstream asked to close must not be reused.
+#[tokio::test]
+async fn test_connection_close_is_respected() {
+    init_logging();
+    let (upstream_address, connections) = start_upstream(
+        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+        true,
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    send_requests(&balancebeam, 3).await;
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+    log::info!("All done :)");
+}
+
+/// Connections that sit idle for longer than the idle timeout should be closed, not reused.
+#[tokio::test]
+async fn test_idle_timeout() {
+    init_logging();
+    let (upstream_address, connections) =
+        start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok", false).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream_address], &["--idle-connection-timeout", "1"]).await;
+
+    send_requests(&balancebeam, 2).await;
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    send_requests(&balancebeam, 1).await;
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    log::info!("All done :)");
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server, TestCert};
+
+/// Terminate TLS with a single certificate and make sure requests still reach the (plain HTTP)
+/// upstream.
+#[tokio::test]
+async fn test_tls_termination() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let cert = TestCert::new(&["balancebeam.test"]);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--tls-cert", cert.cert_path(), "--tls-key", cert.key_path()],
+    )
+    .await;
+
+    let response = balancebeam
+        .get_tls("/over-tls", "balancebeam.test", &[&cert])
+        .await
+        .expect("Error sending request to balancebeam over TLS");
+    assert!(response.body.contains("GET /over-tls HTTP/1.1"));
+    assert_eq!(response.peer_cert, cert.cert_der);
+
+    log::info!("Making sure plain HTTP is refused");
+    assert!(balancebeam.get("/plain").await.is_err());
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
+
+/// Serve different certificates for different hostnames, and fall back to the default
+/// certificate for hostnames without one of their own.
+#[tokio::test]
+async fn test_sni_cert_selection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let default_cert = TestCert::new(&["default.test"]);
+    let a_cert = TestCert::new(&["a.test"]);
+    let wildcard_cert = TestCert::new(&["*.b.test"]);
+    let a_arg = format!("a.test={},{}", a_cert.cert_path(), a_cert.key_path());
+    let wildcard_arg = format!(
+        "*.b.test={},{}",
+        wildcard_cert.cert_path(),
+        wildcard_cert.key_path()
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            default_cert.cert_path(),
+            "--tls-key",
+            default_cert.key_path(),
+            "--tls-sni-cert",
+            &a_arg,
+            "--tls-sni-cert",
+            &wildcard_arg,
+        ],
+    )
+    .await;
+
+    for (server_name, expected_cert) in [
+        ("a.test", &a_cert),
+        ("www.b.test", &wildcard_cert),
+        ("default.test", &default_cert),
+    ] {
+        log::info!("Connecting as {}", server_name);
+        let response = balancebeam
+            .get_tls("/", server_name, &[expected_cert])
+            .await
+            .expect("Error sending request to balancebeam over TLS");
+        assert!(response.body.contains(&format!("host: {}", server_name)));
+        assert_eq!(response.peer_cert, expected_cert.cert_der);
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+    log::info!("All done :)");
+}
@@ -0,0 +1,181 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, TestCert};
+use parking_lot::Mutex;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// An HTTPS upstream for `localhost` that answers every request with "ok" and records the request
+/// lines it received. If `client_ca` is given, clients must present a certificate signed by it.
+async fn start_https_upstream(
+    cert: &TestCert,
+    client_ca: Option<&TestCert>,
+) -> (String, Arc<Mutex<Vec<String>>>) {
+    let port = rand::thread_rng().gen_range(1024..65535);
+    let listener = TcpListener::bind(format!("127.0.0.1:{}", port))
+        .await
+        .unwrap();
+    let key =
+        rustls_pemfile::pkcs8_private_keys(&mut std::fs::read(cert.key_path()).unwrap().as_slice())
+            .unwrap()
+            .remove(0);
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            roots.add(&Certificate(client_ca.cert_der.clone())).unwrap();
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(vec![Certificate(cert.cert_der.clone())], PrivateKey(key))
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let requests_clone = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            let requests = requests_clone.clone();
+            tokio::spawn(async move {
+                let mut stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::info!("Upstream TLS handshake failed: {}", err);
+                        return;
+                    }
+                };
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 512];
+                loop {
+                    let bytes_read = match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(bytes_read) => bytes_read,
+                    };
+                    request.extend_from_slice(&buffer[..bytes_read]);
+                    // None of the test requests have bodies, so a blank line ends each one
+                    if request.ends_with(b"\r\n\r\n") {
+                        let request_line = String::from_utf8_lossy(&request)
+                            .lines()
+                            .next()
+                            .unwrap()
+                            .to_string();
+                        requests.lock().push(request_line);
+                        request.clear();
+                        stream
+                            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                            .await
+                            .unwrap();
+                    }
+                }
+            });
+        }
+    });
+    (format!("https://localhost:{}", port), requests)
+}
+
+/// Requests and health checks to an https:// upstream should go over TLS, verified against the
+/// configured CA.
+#[tokio::test]
+async fn test_https_upstream() {
+    init_logging();
+    let cert = TestCert::new(&["localhost"]);
+    let (upstream_address, requests) = start_https_upstream(&cert, None).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--upstream-ca",
+            cert.cert_path(),
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-path",
+            "/health",
+        ],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .get("/over-tls")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "ok");
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    let requests = requests.lock();
+    assert!(requests.contains(&"GET /over-tls HTTP/1.1".to_string()));
+    assert!(requests.contains(&"GET /health HTTP/1.1".to_string()));
+    log::info!("All done :)");
+}
+
+/// An upstream whose certificate isn't signed by a trusted CA must not be sent requests.
+#[tokio::test]
+async fn test_untrusted_upstream_cert() {
+    init_logging();
+    let cert = TestCert::new(&["localhost"]);
+    let other_ca = TestCert::new(&["localhost"]);
+    let (upstream_address, requests) = start_https_upstream(&cert, None).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--upstream-ca", other_ca.cert_path()],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .get("/")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_ne!(response_text, "ok");
+    assert!(requests.lock().is_empty());
+    log::info!("All done :)");
+}
+
+/// An upstream that requires mutual TLS should only be reachable with the client certificate.
+#[tokio::test]
+async fn test_upstream_mutual_tls() {
+    init_logging();
+    let cert = TestCert::new(&["localhost"]);
+    let client_cert = TestCert::new(&["balancebeam.test"]);
+    let (upstream_address, requests) = start_https_upstream(&cert, Some(&client_cert)).await;
+
+    log::info!("Connecting without a client certificate");
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream_address], &["--upstream-ca", cert.cert_path()])
+            .await;
+    let response_text = balancebeam
+        .get("/")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_ne!(response_text, "ok");
+    assert!(requests.lock().is_empty());
+    drop(balancebeam);
+
+    log::info!("Connecting with a client certificate");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--upstream-ca",
+            cert.cert_path(),
+            "--upstream-client-cert",
+            client_cert.cert_path(),
+            "--upstream-client-key",
+            client_cert.key_path(),
+        ],
+    )
+    .await;
+    let response_text = balancebeam
+        .get("/mtls")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "ok");
+    assert_eq!(*requests.lock(), vec!["GET /mtls HTTP/1.1".to_string()]);
+    log::info!("All done :)");
+}
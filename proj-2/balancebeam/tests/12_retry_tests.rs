@@ -0,0 +1,354 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// An upstream that accepts connections and reads the request, but hangs up without responding.
+/// Returns its address and a count of the connections it has accepted.
+async fn start_broken_upstream() -> (String, Arc<AtomicUsize>) {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&address).await.unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let connections_clone = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            connections_clone.fetch_add(1, Ordering::SeqCst);
+            let mut buffer = [0_u8; 512];
+            let _ = stream.read(&mut buffer).await;
+        }
+    });
+    (address, connections)
+}
+
+/// An upstream that answers the first request on each connection by echoing its body, then hangs
+/// up on the next one without responding, like a server whose keep-alive timeout ran out just as
+/// the request arrived. If `stall` is true, it leaves the next request unanswered without hanging
+/// up instead. Returns its address and a count of the connections it has accepted.
+async fn start_forgetful_upstream(stall: bool) -> (String, Arc<AtomicUsize>) {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&address).await.unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let connections_clone = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            connections_clone.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 512];
+                let body = loop {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(bytes_read) => request.extend_from_slice(&buffer[..bytes_read]),
+                    }
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .to_ascii_lowercase()
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length: "))
+                            .map_or(0, |length| length.parse().unwrap());
+                        if body.len() >= length {
+                            break body.to_string();
+                        }
+                    }
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                let _ = stream.read(&mut buffer).await;
+                if stall {
+                    std::future::pending::<()>().await;
+                }
+            });
+        }
+    });
+    (address, connections)
+}
+
+/// Sends a POST request and returns the response status and body.
+async fn post(
+    balancebeam: &BalanceBeam,
+    body: &str,
+    idempotency_key: Option<&str>,
+) -> (u16, String) {
+    let mut request = reqwest::Client::new()
+        .post(format!("http://{}/submit", balancebeam.address))
+        .body(body.to_string());
+    if let Some(key) = idempotency_key {
+        request = request.header("Idempotency-Key", key);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// An idempotent request that fails on one upstream should be retried on another, and the failed
+/// upstream should be taken out of rotation.
+#[tokio::test]
+async fn test_idempotent_requests_are_retried() {
+    init_logging();
+    let (broken_address, broken_connections) = start_broken_upstream().await;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&broken_address, &upstream.address],
+        &["--strategy", "round-robin"],
+    )
+    .await;
+
+    for i in 0..4 {
+        let path = format!("/retried-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(broken_connections.load(Ordering::SeqCst), 1);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 4);
+    log::info!("All done :)");
+}
+
+/// A POST request isn't safe to repeat, so it should only be retried if the client sent an
+/// Idempotency-Key header. Retried requests should arrive with their body intact.
+#[tokio::test]
+async fn test_post_retried_only_with_idempotency_key() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+
+    log::info!("Sending a POST without an Idempotency-Key");
+    let (broken_address, _) = start_broken_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&broken_address, &upstream.address],
+        &["--strategy", "round-robin"],
+    )
+    .await;
+    let (status, _) = post(&balancebeam, "first", None).await;
+    assert_eq!(status, 502);
+    drop(balancebeam);
+
+    log::info!("Sending a POST with an Idempotency-Key");
+    let (broken_address, broken_connections) = start_broken_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&broken_address, &upstream.address],
+        &["--strategy", "round-robin"],
+    )
+    .await;
+    let (status, body) = post(&balancebeam, "second", Some("abc123")).await;
+    assert_eq!(status, 200);
+    assert!(body.contains("POST /submit HTTP/1.1"));
+    assert!(body.ends_with("second"));
+    assert_eq!(broken_connections.load(Ordering::SeqCst), 1);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
+
+/// With retries turned off, a failed request should get a 502 straight away.
+#[tokio::test]
+async fn test_retries_disabled() {
+    init_logging();
+    let (broken_address, _) = start_broken_upstream().await;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&broken_address, &upstream.address],
+        &["--strategy", "round-robin", "--max-retries", "0"],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+    log::info!("All done :)");
+}
+
+/// A request sent on a pooled connection that has gone stale should be sent again on a new
+/// connection, even with retries turned off, without counting as a failure of the upstream.
+#[tokio::test]
+async fn test_stale_pooled_connection_is_replaced() {
+    init_logging();
+    let (upstream_address, connections) = start_forgetful_upstream(false).await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--max-retries", "0", "--admin-bind", &admin_address],
+    )
+    .await;
+
+    for body in ["first", "second", "third"] {
+        let response = reqwest::Client::new()
+            .put(format!("http://{}/submit", balancebeam.address))
+            .body(body)
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.text().await.unwrap(), body);
+        // Give balancebeam a moment to put the upstream connection back in the pool
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    // Each request after the first found its pooled connection stale and opened a new one
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+
+    let upstream: serde_json::Value = reqwest::get(format!(
+        "http://{}/upstreams/{}",
+        admin_address, upstream_address
+    ))
+    .await
+    .expect("Error sending request to the admin interface")
+    .json()
+    .await
+    .expect("Admin interface replied with invalid JSON");
+    assert_eq!(upstream["failures"], 0);
+    log::info!("All done :)");
+}
+
+/// A request that isn't safe to repeat can't be sent again if a pooled connection turns out to
+/// have gone stale after it was sent, so it should go on a new connection in the first place.
+#[tokio::test]
+async fn test_non_idempotent_requests_use_new_connections() {
+    init_logging();
+    let (upstream_address, connections) = start_forgetful_upstream(false).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream_address], &["--max-retries", "0"]).await;
+
+    for body in ["first", "second", "third"] {
+        let (status, response_text) = post(&balancebeam, body, None).await;
+        assert_eq!(status, 200);
+        assert_eq!(response_text, body);
+        // Give balancebeam a moment to put the upstream connection back in the pool
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+    log::info!("All done :)");
+}
+
+/// A retried request should go to another upstream, even if affinity pins the client to the one
+/// that failed.
+#[tokio::test]
+async fn test_retries_avoid_failed_upstream_with_affinity() {
+    init_logging();
+    let (address_a, connections_a) = start_forgetful_upstream(true).await;
+    let (address_b, connections_b) = start_forgetful_upstream(true).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&address_a, &address_b],
+        &[
+            "--affinity",
+            "ip-hash",
+            "--upstream-response-timeout",
+            "1",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    // The second request goes to the pinned upstream's pooled connection, which never answers
+    for path in ["/first", "/second"] {
+        let response = reqwest::get(format!("http://{}{}", balancebeam.address, path))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        // Give balancebeam a moment to put the upstream connection back in the pool
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let mut connections = [
+        connections_a.load(Ordering::SeqCst),
+        connections_b.load(Ordering::SeqCst),
+    ];
+    connections.sort();
+    assert_eq!(connections, [1, 1]);
+    log::info!("All done :)");
+}
+
+/// Requests that arrive over HTTP/2 should be retried like any other.
+#[tokio::test]
+async fn test_http2_requests_are_retried() {
+    init_logging();
+    let (broken_address, broken_connections) = start_broken_upstream().await;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&broken_address, &upstream.address],
+        &["--strategy", "round-robin"],
+    )
+    .await;
+    let client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<hyper::Body>();
+
+    for i in 0..4 {
+        let uri = format!("http://{}/retried-{}", balancebeam.address, i);
+        let response = client
+            .get(uri.parse().unwrap())
+            .await
+            .expect("Error sending request over HTTP/2");
+        assert_eq!(response.status(), 200);
+    }
+    assert_eq!(broken_connections.load(Ordering::SeqCst), 1);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 4);
+    log::info!("All done :)");
+}
+
+/// A stale pooled connection should be replaced for requests that arrive over HTTP/2 too, without
+/// counting as a failure of the upstream.
+#[tokio::test]
+async fn test_http2_stale_pooled_connection_is_replaced() {
+    init_logging();
+    let (upstream_address, connections) = start_forgetful_upstream(false).await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--max-retries", "0", "--admin-bind", &admin_address],
+    )
+    .await;
+    let client = hyper::Client::builder().http2_only(true).build_http();
+
+    for body in ["first", "second", "third"] {
+        let request = hyper::Request::put(format!("http://{}/submit", balancebeam.address))
+            .body(hyper::Body::from(body))
+            .unwrap();
+        let response = client
+            .request(request)
+            .await
+            .expect("Error sending request over HTTP/2");
+        assert_eq!(response.status(), 200);
+        let response_body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(response_body, body);
+        // Give balancebeam a moment to put the upstream connection back in the pool
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+
+    let upstream: serde_json::Value = reqwest::get(format!(
+        "http://{}/upstreams/{}",
+        admin_address, upstream_address
+    ))
+    .await
+    .expect("Error sending request to the admin interface")
+    .json()
+    .await
+    .expect("Admin interface replied with invalid JSON");
+    assert_eq!(upstream["failures"], 0);
+    log::info!("All done :)");
+}
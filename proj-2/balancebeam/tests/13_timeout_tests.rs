@@ -0,0 +1,101 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Sends the given bytes to balancebeam on a new connection and returns everything it sends back
+/// before hanging up. Fails if balancebeam keeps the connection open for longer than `limit`.
+async fn send_and_wait_for_close(
+    balancebeam: &BalanceBeam,
+    data: &[u8],
+    limit: Duration,
+) -> String {
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream.write_all(data).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(limit, stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam did not close the connection in time")
+        .unwrap();
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// A client that starts a request but doesn't finish the headers should get a 408, and a client
+/// that never sends anything should be hung up on.
+#[tokio::test]
+async fn test_client_timeouts() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--client-header-timeout", "1", "--client-idle-timeout", "1"],
+    )
+    .await;
+
+    log::info!("Sending part of a request's headers");
+    let response = send_and_wait_for_close(
+        &balancebeam,
+        b"GET / HTTP/1.1\r\nHost: balancebeam.test\r\n",
+        Duration::from_secs(3),
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 408"));
+
+    log::info!("Sending nothing at all");
+    let response = send_and_wait_for_close(&balancebeam, b"", Duration::from_secs(3)).await;
+    assert!(response.is_empty());
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+    log::info!("All done :)");
+}
+
+/// A client that stops sending its request body partway through should get a 408.
+#[tokio::test]
+async fn test_body_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--body-timeout", "1"]).await;
+
+    let response = send_and_wait_for_close(
+        &balancebeam,
+        b"POST / HTTP/1.1\r\nHost: balancebeam.test\r\nContent-Length: 10\r\n\r\nabc",
+        Duration::from_secs(3),
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 408"));
+    log::info!("All done :)");
+}
+
+/// An upstream that takes too long to respond should result in a 504.
+#[tokio::test]
+async fn test_upstream_response_timeout() {
+    init_logging();
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&address).await.unwrap();
+    tokio::spawn(async move {
+        loop {
+            // Hold on to the connection without ever responding
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                drop(stream);
+            });
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&address],
+        &["--upstream-response-timeout", "1", "--max-retries", "0"],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+    log::info!("All done :)");
+}
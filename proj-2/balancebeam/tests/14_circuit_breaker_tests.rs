@@ -0,0 +1,135 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use rand::Rng;
+use std::time::Duration;
+
+/// Sends a request and returns the response status.
+async fn get_status(balancebeam: &BalanceBeam, path: &str) -> u16 {
+    reqwest::get(format!("http://{}{}", balancebeam.address, path))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Returns the circuit breaker state the admin interface reports for the given upstream.
+async fn circuit_state(admin_address: &str, upstream_address: &str) -> String {
+    let upstreams: serde_json::Value = reqwest::get(format!("http://{}/upstreams", admin_address))
+        .await
+        .expect("Error sending request to the admin interface")
+        .json()
+        .await
+        .expect("Admin interface replied with invalid JSON");
+    let upstream = upstreams
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|upstream| upstream["address"] == upstream_address)
+        .expect("Upstream missing from the admin interface");
+    upstream["circuit"].as_str().unwrap().to_string()
+}
+
+/// An upstream that keeps returning 5xx errors should stop getting requests once its breaker
+/// opens, and get them again once it has recovered and a trial request has succeeded.
+#[tokio::test]
+async fn test_breaker_opens_on_consecutive_5xx_and_recovers() {
+    init_logging();
+    let error_upstream = ErrorServer::new().await;
+    let error_address = error_upstream.address.clone();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&error_address, &upstream.address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "600",
+            "--breaker-consecutive-5xx",
+            "2",
+            "--breaker-cooldown",
+            "1",
+            "--breaker-half-open-requests",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests until the failing upstream's breaker opens");
+    let mut statuses = Vec::new();
+    for i in 0..4 {
+        statuses.push(get_status(&balancebeam, &format!("/failing-{}", i)).await);
+    }
+    statuses.sort_unstable();
+    assert_eq!(statuses, vec![200, 200, 500, 500]);
+    assert_eq!(circuit_state(&admin_address, &error_address).await, "open");
+
+    log::info!("Making sure the failing upstream doesn't get any more requests");
+    for i in 0..4 {
+        assert_eq!(get_status(&balancebeam, &format!("/open-{}", i)).await, 200);
+    }
+    let num_errors_returned = Box::new(error_upstream).stop().await;
+    assert_eq!(num_errors_returned, 2);
+
+    log::info!("Fixing the upstream and waiting for the cooldown");
+    let fixed_upstream = EchoServer::new_at_address(error_address.clone()).await;
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(
+        circuit_state(&admin_address, &error_address).await,
+        "half_open"
+    );
+    for i in 0..4 {
+        assert_eq!(
+            get_status(&balancebeam, &format!("/recovered-{}", i)).await,
+            200
+        );
+    }
+    assert_eq!(
+        circuit_state(&admin_address, &error_address).await,
+        "closed"
+    );
+
+    let num_requests_received = Box::new(fixed_upstream).stop().await;
+    assert!(num_requests_received >= 2);
+    log::info!("All done :)");
+}
+
+/// With the consecutive-5xx limit turned off, a breaker should still open once too many of the
+/// requests in its window have failed.
+#[tokio::test]
+async fn test_breaker_opens_on_error_rate() {
+    init_logging();
+    let error_upstream = ErrorServer::new().await;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&error_upstream.address, &upstream.address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "600",
+            "--breaker-consecutive-5xx",
+            "0",
+            "--breaker-error-rate",
+            "75",
+            "--breaker-window",
+            "3",
+            "--breaker-cooldown",
+            "60",
+        ],
+    )
+    .await;
+
+    for i in 0..10 {
+        get_status(&balancebeam, &format!("/request-{}", i)).await;
+    }
+
+    let num_errors_returned = Box::new(error_upstream).stop().await;
+    assert_eq!(num_errors_returned, 3);
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 7);
+    log::info!("All done :)");
+}
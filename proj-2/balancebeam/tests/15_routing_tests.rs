@@ -0,0 +1,162 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, ConfigFile, EchoServer, Server};
+
+/// Sends a request with the given Host header (plus any extra headers) and returns the response
+/// status and body.
+async fn send(
+    balancebeam: &BalanceBeam,
+    method: reqwest::Method,
+    host: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> (u16, String) {
+    let mut request = reqwest::Client::new()
+        .request(method, format!("http://{}{}", balancebeam.address, path))
+        .header("Host", host);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// Requests should go to the pool of the first route they match, and anything that doesn't match
+/// a route should go to the default pool.
+#[tokio::test]
+async fn test_requests_are_routed_to_pools() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let api_upstream = EchoServer::new().await;
+    let static_upstream = EchoServer::new().await;
+    let config_file = ConfigFile::new();
+    config_file.write(&format!(
+        r#"
+[[upstream]]
+address = "{}"
+
+[[pool]]
+name = "api"
+strategy = "round-robin"
+
+[[pool.upstream]]
+address = "{}"
+
+[[pool]]
+name = "static"
+health_check_path = "/static/health"
+
+[[pool.upstream]]
+address = "{}"
+
+[[route]]
+host = "api.example.test"
+pool = "api"
+
+[[route]]
+path_prefix = "/static/"
+pool = "static"
+
+[[route]]
+method = "POST"
+headers = {{ "X-Canary" = "1" }}
+pool = "static"
+"#,
+        default_upstream.address, api_upstream.address, static_upstream.address
+    ));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], &["--config", config_file.path.to_str().unwrap()]).await;
+
+    log::info!("Routing by host");
+    let (status, body) = send(
+        &balancebeam,
+        reqwest::Method::GET,
+        "api.example.test:8080",
+        "/static/logo.png",
+        &[],
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert!(body.contains("GET /static/logo.png HTTP/1.1"));
+
+    log::info!("Routing by path prefix");
+    for i in 0..2 {
+        let path = format!("/static/{}.css", i);
+        let (status, _) = send(&balancebeam, reqwest::Method::GET, "www.test", &path, &[]).await;
+        assert_eq!(status, 200);
+    }
+
+    log::info!("Routing by method and header");
+    let canary = [("X-Canary", "1")];
+    let (status, _) = send(
+        &balancebeam,
+        reqwest::Method::POST,
+        "www.test",
+        "/",
+        &canary,
+    )
+    .await;
+    assert_eq!(status, 200);
+    let (status, _) = send(&balancebeam, reqwest::Method::GET, "www.test", "/", &canary).await;
+    assert_eq!(status, 200);
+
+    log::info!("Falling back to the default pool");
+    let (status, _) = send(&balancebeam, reqwest::Method::GET, "www.test", "/", &[]).await;
+    assert_eq!(status, 200);
+
+    assert_eq!(Box::new(api_upstream).stop().await, 1);
+    assert_eq!(Box::new(static_upstream).stop().await, 3);
+    assert_eq!(Box::new(default_upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Without any upstreams in the default pool, requests that don't match a route should get a 404.
+#[tokio::test]
+async fn test_unrouted_requests_get_404() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_file = ConfigFile::new();
+    config_file.write(&format!(
+        r#"
+[[pool]]
+name = "api"
+
+[[pool.upstream]]
+address = "{}"
+
+[[route]]
+path_prefix = "/api/"
+pool = "api"
+"#,
+        upstream.address
+    ));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], &["--config", config_file.path.to_str().unwrap()]).await;
+
+    let (status, _) = send(
+        &balancebeam,
+        reqwest::Method::GET,
+        "www.test",
+        "/other",
+        &[],
+    )
+    .await;
+    assert_eq!(status, 404);
+    let (status, body) = send(
+        &balancebeam,
+        reqwest::Method::GET,
+        "www.test",
+        "/api/x",
+        &[],
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert!(body.contains("GET /api/x HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
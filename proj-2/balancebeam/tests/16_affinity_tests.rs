@@ -0,0 +1,148 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::collections::HashMap;
+
+/// Returns how many requests the admin interface says each upstream has been sent.
+async fn request_counts(admin_address: &str) -> HashMap<String, u64> {
+    let upstreams: serde_json::Value = reqwest::get(format!("http://{}/upstreams", admin_address))
+        .await
+        .expect("Error sending request to the admin interface")
+        .json()
+        .await
+        .expect("Admin interface replied with invalid JSON");
+    upstreams
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|upstream| {
+            let address = upstream["address"].as_str().unwrap().to_string();
+            (address, upstream["requests"].as_u64().unwrap())
+        })
+        .collect()
+}
+
+/// Stops the upstream at the given address.
+async fn stop_upstream(upstreams: &mut Vec<EchoServer>, address: &str) {
+    let idx = upstreams
+        .iter()
+        .position(|upstream| upstream.address == address)
+        .unwrap();
+    Box::new(upstreams.remove(idx)).stop().await;
+}
+
+/// Sends a request with the given Cookie header (if any) and returns the Set-Cookie header that
+/// came back (if any).
+async fn get_with_cookie(balancebeam: &BalanceBeam, cookie: Option<&str>) -> Option<String> {
+    let mut request = reqwest::Client::new().get(format!("http://{}/", balancebeam.address));
+    if let Some(cookie) = cookie {
+        request = request.header("Cookie", cookie);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    response
+        .headers()
+        .get("set-cookie")
+        .map(|value| value.to_str().unwrap().to_string())
+}
+
+/// Starts balancebeam in front of `n` upstreams with round-robin balancing (so that any stickiness
+/// has to come from the affinity mode) and an admin interface.
+async fn start(n: usize, affinity: &str) -> (BalanceBeam, Vec<EchoServer>, String) {
+    let mut upstreams = Vec::new();
+    for _ in 0..n {
+        upstreams.push(EchoServer::new().await);
+    }
+    let addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &addresses,
+        &[
+            "--strategy",
+            "round-robin",
+            "--affinity",
+            affinity,
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+    (balancebeam, upstreams, admin_address)
+}
+
+/// With IP hashing, every request from a client should go to the same upstream. If that upstream
+/// goes down, the client should move to one other upstream and stay there.
+#[tokio::test]
+async fn test_ip_hash_affinity() {
+    init_logging();
+    let (balancebeam, mut upstreams, admin_address) = start(3, "ip-hash").await;
+
+    for i in 0..6 {
+        let path = format!("/pinned-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let counts = request_counts(&admin_address).await;
+    let (pinned, _) = counts.iter().find(|(_, &count)| count == 6).unwrap();
+    assert_eq!(counts.values().sum::<u64>(), 6);
+
+    log::info!("Stopping the pinned upstream {}", pinned);
+    stop_upstream(&mut upstreams, pinned).await;
+    for i in 0..4 {
+        let path = format!("/moved-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let new_counts = request_counts(&admin_address).await;
+    let mut moved: Vec<u64> = upstreams
+        .iter()
+        .map(|upstream| new_counts[&upstream.address] - counts[&upstream.address])
+        .collect();
+    moved.sort_unstable();
+    assert_eq!(moved, vec![0, 4]);
+    log::info!("All done :)");
+}
+
+/// With cookie affinity, a client should be given a cookie naming its upstream and stay there
+/// while it sends the cookie back. If its upstream goes down, it should be given a new cookie.
+#[tokio::test]
+async fn test_cookie_affinity() {
+    init_logging();
+    let (balancebeam, mut upstreams, admin_address) = start(2, "cookie").await;
+
+    let set_cookie = get_with_cookie(&balancebeam, None)
+        .await
+        .expect("No affinity cookie was set");
+    assert!(set_cookie.starts_with("balancebeam_upstream="));
+    let cookie = set_cookie.split(';').next().unwrap().to_string();
+    for _ in 0..4 {
+        assert_eq!(get_with_cookie(&balancebeam, Some(&cookie)).await, None);
+    }
+    let counts = request_counts(&admin_address).await;
+    let (pinned, _) = counts.iter().find(|(_, &count)| count == 5).unwrap();
+
+    log::info!("Stopping the pinned upstream {}", pinned);
+    stop_upstream(&mut upstreams, pinned).await;
+    let set_cookie = get_with_cookie(&balancebeam, Some(&cookie))
+        .await
+        .expect("No new affinity cookie was set");
+    let new_cookie = set_cookie.split(';').next().unwrap().to_string();
+    assert_ne!(new_cookie, cookie);
+    assert_eq!(get_with_cookie(&balancebeam, Some(&new_cookie)).await, None);
+    log::info!("All done :)");
+}
@@ -0,0 +1,177 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer};
+use nix::sys::signal::Signal;
+use rand::Rng;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// An access log path in the temp directory. The log and any rotated copies of it are deleted
+/// when this is dropped.
+struct LogFile {
+    path: PathBuf,
+}
+
+impl LogFile {
+    fn new() -> LogFile {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "balancebeam-test-{}.log",
+            rand::thread_rng().gen::<u64>()
+        ));
+        LogFile { path }
+    }
+
+    /// The path of the nth rotated copy of the log, or the log itself for 0.
+    fn rotated(&self, n: usize) -> PathBuf {
+        match n {
+            0 => self.path.clone(),
+            n => PathBuf::from(format!("{}.{}", self.path.display(), n)),
+        }
+    }
+
+    /// Returns the lines in the given file, or none if it doesn't exist.
+    fn lines(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        for n in 0..10 {
+            let _ = std::fs::remove_file(self.rotated(n));
+        }
+        let _ = std::fs::remove_file(format!("{}.moved", self.path.display()));
+    }
+}
+
+async fn start(upstream: &EchoServer, log_file: &LogFile, extra_args: &[&str]) -> BalanceBeam {
+    let mut args = vec!["--access-log", log_file.path.to_str().unwrap()];
+    args.extend_from_slice(extra_args);
+    BalanceBeam::new_with_args(&[&upstream.address], &args).await
+}
+
+/// Sends a GET request with a User-Agent header and returns the response body.
+async fn get(balancebeam: &BalanceBeam, path: &str) -> String {
+    reqwest::Client::new()
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .header("User-Agent", "balancebeam-tests/1.0")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap()
+}
+
+/// Every request should get a JSON line with the details of the request and response.
+#[tokio::test]
+async fn test_json_access_log() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log_file = LogFile::new();
+    let balancebeam = start(&upstream, &log_file, &["--access-log-format", "json"]).await;
+
+    let body = get(&balancebeam, "/logged?x=1").await;
+    sleep(Duration::from_millis(200)).await;
+
+    let lines = LogFile::lines(&log_file.path);
+    assert_eq!(lines.len(), 1);
+    let entry: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(entry["client_ip"], "127.0.0.1");
+    assert_eq!(entry["method"], "GET");
+    assert_eq!(entry["path"], "/logged?x=1");
+    assert_eq!(entry["protocol"], "HTTP/1.1");
+    assert_eq!(entry["status"], 200);
+    assert_eq!(entry["bytes"], body.len());
+    assert_eq!(entry["upstream"], upstream.address.as_str());
+    assert_eq!(entry["user_agent"], "balancebeam-tests/1.0");
+    assert!(entry["referer"].is_null());
+    assert!(entry["latency_ms"].as_f64().unwrap() >= 0.0);
+    assert!(entry["time"].as_str().unwrap().ends_with('Z'));
+    log::info!("All done :)");
+}
+
+/// The Combined Log Format should have the request line, status, size, Referer and User-Agent.
+#[tokio::test]
+async fn test_combined_access_log() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log_file = LogFile::new();
+    let balancebeam = start(&upstream, &log_file, &[]).await;
+
+    let body = get(&balancebeam, "/combined").await;
+    sleep(Duration::from_millis(200)).await;
+
+    let lines = LogFile::lines(&log_file.path);
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with("127.0.0.1 - - ["));
+    assert!(lines[0].ends_with(&format!(
+        "] \"GET /combined HTTP/1.1\" 200 {} \"-\" \"balancebeam-tests/1.0\"",
+        body.len()
+    )));
+    log::info!("All done :)");
+}
+
+/// The log should be rotated once it gets too big, keeping only the configured number of old
+/// copies.
+#[tokio::test]
+async fn test_access_log_rotation() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log_file = LogFile::new();
+    let balancebeam = start(
+        &upstream,
+        &log_file,
+        &["--access-log-max-size", "1", "--access-log-max-files", "2"],
+    )
+    .await;
+
+    // With a tiny size limit, every request after the first starts a new file
+    for i in 0..4 {
+        get(&balancebeam, &format!("/rotated-{}", i)).await;
+    }
+    sleep(Duration::from_millis(200)).await;
+
+    for (n, path) in ["/rotated-3", "/rotated-2", "/rotated-1"]
+        .iter()
+        .enumerate()
+    {
+        let lines = LogFile::lines(&log_file.rotated(n));
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(&format!("\"GET {} HTTP/1.1\"", path)));
+    }
+    assert!(!log_file.rotated(3).exists());
+    log::info!("All done :)");
+}
+
+/// After the log has been moved away, SIGUSR1 should make balancebeam start a new one.
+#[tokio::test]
+async fn test_access_log_reopen_on_sigusr1() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log_file = LogFile::new();
+    let balancebeam = start(&upstream, &log_file, &[]).await;
+
+    get(&balancebeam, "/before").await;
+    sleep(Duration::from_millis(200)).await;
+    let moved = PathBuf::from(format!("{}.moved", log_file.path.display()));
+    std::fs::rename(&log_file.path, &moved).unwrap();
+    balancebeam.signal(Signal::SIGUSR1);
+    sleep(Duration::from_millis(200)).await;
+    get(&balancebeam, "/after").await;
+    sleep(Duration::from_millis(200)).await;
+
+    let old_lines = LogFile::lines(&moved);
+    assert_eq!(old_lines.len(), 1);
+    assert!(old_lines[0].contains("/before"));
+    let new_lines = LogFile::lines(&log_file.path);
+    assert_eq!(new_lines.len(), 1);
+    assert!(new_lines[0].contains("/after"));
+    log::info!("All done :)");
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer};
+use nix::sys::signal::Signal;
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::sleep;
+
+/// Starts an upstream that waits `delay` after reading each request before responding with
+/// "slow", and returns its address.
+async fn start_slow_upstream(delay: Duration) -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&address).await.unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                if stream.read(&mut buf).await.unwrap_or(0) == 0 {
+                    return;
+                }
+                sleep(delay).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow")
+                    .await;
+                // Hold on to the connection, like a keep-alive upstream would
+                sleep(Duration::from_secs(30)).await;
+            });
+        }
+    });
+    address
+}
+
+async fn start(upstream: &str, drain_timeout: &str) -> BalanceBeam {
+    BalanceBeam::new_with_args(
+        &[upstream],
+        &[
+            "--drain-timeout",
+            drain_timeout,
+            "--active-health-check-interval",
+            "600",
+            "--max-retries",
+            "0",
+        ],
+    )
+    .await
+}
+
+/// A request that is in flight when balancebeam gets SIGTERM should still get its response, with
+/// Connection: close, while new connections are refused. balancebeam should then exit cleanly.
+#[tokio::test]
+async fn test_request_finishes_during_shutdown() {
+    init_logging();
+    let upstream = start_slow_upstream(Duration::from_secs(2)).await;
+    let mut balancebeam = start(&upstream, "10").await;
+
+    let url = format!("http://{}/", balancebeam.address);
+    let in_flight = tokio::spawn(async move { reqwest::get(url).await });
+    sleep(Duration::from_millis(500)).await;
+    balancebeam.signal(Signal::SIGTERM);
+    sleep(Duration::from_millis(500)).await;
+
+    log::info!("Connecting after SIGTERM");
+    assert!(TcpStream::connect(&balancebeam.address).await.is_err());
+
+    let response = in_flight
+        .await
+        .unwrap()
+        .expect("In-flight request failed during shutdown");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["connection"], "close");
+    assert_eq!(response.text().await.unwrap(), "slow");
+
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(3))
+        .await
+        .expect("balancebeam did not exit after its last request finished");
+    assert!(status.success());
+    log::info!("All done :)");
+}
+
+/// Keep-alive connections that are waiting for their next request should be closed straight away
+/// on SIGINT, without waiting for the drain deadline.
+#[tokio::test]
+async fn test_idle_connections_closed_on_shutdown() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = start(&upstream.address, "30").await;
+
+    let mut idle = TcpStream::connect(&balancebeam.address).await.unwrap();
+    sleep(Duration::from_millis(200)).await;
+    balancebeam.signal(Signal::SIGINT);
+
+    let mut leftover = Vec::new();
+    tokio::time::timeout(Duration::from_secs(3), idle.read_to_end(&mut leftover))
+        .await
+        .expect("balancebeam did not close the idle connection")
+        .unwrap();
+    assert!(leftover.is_empty());
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(3))
+        .await
+        .expect("balancebeam did not exit with only idle connections open");
+    assert!(status.success());
+    log::info!("All done :)");
+}
+
+/// If a connection is still busy when the drain deadline passes, balancebeam should exit anyway,
+/// with a non-zero status.
+#[tokio::test]
+async fn test_drain_deadline() {
+    init_logging();
+    let upstream = start_slow_upstream(Duration::from_secs(30)).await;
+    let mut balancebeam = start(&upstream, "1").await;
+
+    let url = format!("http://{}/", balancebeam.address);
+    let in_flight = tokio::spawn(async move { reqwest::get(url).await });
+    sleep(Duration::from_millis(500)).await;
+    balancebeam.signal(Signal::SIGTERM);
+
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("balancebeam did not exit at the drain deadline");
+    assert!(!status.success());
+    assert!(in_flight.await.unwrap().is_err());
+    log::info!("All done :)");
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Kills the process with the given pid when dropped, so that the process balancebeam upgraded to
+/// doesn't outlive the test.
+struct KillOnDrop(Pid);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = nix::sys::signal::kill(self.0, Signal::SIGKILL);
+    }
+}
+
+/// Finds the pid of the new process from the old one's output.
+fn upgraded_pid(balancebeam: &BalanceBeam) -> Option<Pid> {
+    balancebeam.output().iter().find_map(|line| {
+        let rest = line.split("Started new balancebeam process ").nth(1)?;
+        let pid = rest.split_whitespace().next()?.parse().ok()?;
+        Some(Pid::from_raw(pid))
+    })
+}
+
+/// On SIGUSR2, balancebeam should hand its listening socket to a new process and exit, without
+/// any request along the way being refused.
+#[tokio::test]
+async fn test_upgrade_without_refusing_connections() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--drain-timeout",
+            "5",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    // Keep sending requests, each on a new connection, for the whole upgrade
+    let stop = Arc::new(AtomicBool::new(false));
+    let (sent, failed) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let client = {
+        let url = format!("http://{}/", balancebeam.address);
+        let (stop, sent, failed) = (stop.clone(), sent.clone(), failed.clone());
+        tokio::spawn(async move {
+            let client = reqwest::Client::builder()
+                .pool_max_idle_per_host(0)
+                .build()
+                .unwrap();
+            while !stop.load(Ordering::SeqCst) {
+                sent.fetch_add(1, Ordering::SeqCst);
+                match client.get(&url).send().await {
+                    Ok(response) if response.status().is_success() => {}
+                    Ok(response) => {
+                        log::error!("Request failed with {}", response.status());
+                        failed.fetch_add(1, Ordering::SeqCst);
+                    }
+                    Err(err) => {
+                        log::error!("Request failed: {}", err);
+                        failed.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+    };
+    sleep(Duration::from_millis(200)).await;
+
+    balancebeam.signal(Signal::SIGUSR2);
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(10))
+        .await
+        .expect("Old balancebeam process did not exit after the upgrade");
+    let _new_process = KillOnDrop(upgraded_pid(&balancebeam).expect("No new process was started"));
+    assert!(status.success());
+
+    // The new process should carry on where the old one left off
+    sleep(Duration::from_millis(500)).await;
+    stop.store(true, Ordering::SeqCst);
+    client.await.unwrap();
+    let response_text = balancebeam
+        .get("/after-upgrade")
+        .await
+        .expect("Error sending request to the new balancebeam process");
+    assert!(response_text.contains("GET /after-upgrade HTTP/1.1"));
+
+    assert!(sent.load(Ordering::SeqCst) > 10);
+    assert_eq!(failed.load(Ordering::SeqCst), 0);
+    assert!(Box::new(upstream).stop().await > 10);
+    log::info!("All done :)");
+}
@@ -0,0 +1,166 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const UPGRADE_REQUEST: &[u8] =
+    b"GET /chat HTTP/1.1\r\nHost: balancebeam.test\r\nConnection: Upgrade\r\nUpgrade: shout\r\n\r\n";
+
+/// Starts an upstream that switches to a made-up "shout" protocol when asked to: it sends
+/// "hello", then echoes everything back in upper case until the client hangs up.
+async fn start_shout_upstream() -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&address).await.unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let head = read_until(&mut stream, b"\r\n\r\n").await;
+                if !head.to_ascii_lowercase().contains("upgrade: shout") {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                        .await;
+                    return;
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\n\
+                        Upgrade: shout\r\n\r\nhello",
+                    )
+                    .await
+                    .unwrap();
+                let mut buf = [0; 1024];
+                loop {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => {
+                            let shouted = buf[..n].to_ascii_uppercase();
+                            if stream.write_all(&shouted).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Reads from the stream until what has been read ends with `suffix`, and returns it.
+async fn read_until(stream: &mut TcpStream, suffix: &[u8]) -> String {
+    let mut read = Vec::new();
+    let mut buf = [0; 1];
+    while !read.ends_with(suffix) {
+        let n = tokio::time::timeout(Duration::from_secs(3), stream.read(&mut buf))
+            .await
+            .expect("Timed out waiting for data")
+            .unwrap();
+        assert!(n > 0, "Connection closed early: {:?}", read);
+        read.push(buf[0]);
+    }
+    String::from_utf8_lossy(&read).to_string()
+}
+
+async fn scrape(metrics_address: &str) -> String {
+    reqwest::get(format!("http://{}/metrics", metrics_address))
+        .await
+        .expect("Error sending request to the metrics endpoint")
+        .text()
+        .await
+        .unwrap()
+}
+
+/// After the upstream answers an upgrade request with 101, balancebeam should pass bytes both ways
+/// until the client hangs up, and count the tunnel in its metrics.
+#[tokio::test]
+async fn test_upgrade_tunnel() {
+    init_logging();
+    let upstream = start_shout_upstream().await;
+    let metrics_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--metrics-bind",
+            &metrics_address,
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(UPGRADE_REQUEST).await.unwrap();
+    let head = read_until(&mut client, b"\r\n\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 101"));
+    read_until(&mut client, b"hello").await;
+    for message in ["ping", "still there?"] {
+        client.write_all(message.as_bytes()).await.unwrap();
+        read_until(&mut client, message.to_uppercase().as_bytes()).await;
+    }
+    assert!(scrape(&metrics_address)
+        .await
+        .contains("balancebeam_active_tunnels 1"));
+
+    // Hanging up should be passed on to the upstream, which then hangs up on balancebeam
+    client.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(3), client.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam did not close the tunnel")
+        .unwrap();
+    assert!(rest.is_empty());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let metrics = scrape(&metrics_address).await;
+    log::info!("Scraped metrics:\n{}", metrics);
+    assert!(metrics.contains(&format!(
+        "balancebeam_tunnels_total{{upstream=\"{}\"}} 1",
+        upstream
+    )));
+    assert!(metrics.contains(&format!(
+        "balancebeam_tunnel_bytes_total{{upstream=\"{}\",direction=\"to_upstream\"}} 16",
+        upstream
+    )));
+    assert!(metrics.contains(&format!(
+        "balancebeam_tunnel_bytes_total{{upstream=\"{}\",direction=\"to_client\"}} 21",
+        upstream
+    )));
+    assert!(metrics.contains("balancebeam_active_tunnels 0"));
+    assert!(metrics.contains(&format!(
+        "balancebeam_requests_total{{upstream=\"{}\",status=\"101\"}} 1",
+        upstream
+    )));
+    log::info!("All done :)");
+}
+
+/// A tunnel that nothing is sent through should be closed after the tunnel idle timeout.
+#[tokio::test]
+async fn test_tunnel_idle_timeout() {
+    init_logging();
+    let upstream = start_shout_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--tunnel-idle-timeout",
+            "1",
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(UPGRADE_REQUEST).await.unwrap();
+    read_until(&mut client, b"hello").await;
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(3), client.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam did not close the idle tunnel")
+        .unwrap();
+    assert!(rest.is_empty());
+    log::info!("All done :)");
+}
@@ -0,0 +1,222 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// A TCP server that speaks a made-up, non-HTTP protocol: it greets every connection with its
+/// name and a newline, then echoes back whatever it is sent.
+struct GreetingServer {
+    address: String,
+    task: JoinHandle<()>,
+}
+
+impl GreetingServer {
+    async fn new(name: &'static str) -> GreetingServer {
+        let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+        let listener = TcpListener::bind(&address).await.unwrap();
+        let task = tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    if stream
+                        .write_all(format!("{}\n", name).as_bytes())
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        GreetingServer { address, task }
+    }
+
+    /// Stops accepting connections, so that connecting to it fails.
+    fn stop(&self) {
+        self.task.abort();
+    }
+}
+
+/// Connects to balancebeam and returns the connection along with the name of the upstream it was
+/// passed through to.
+async fn connect(balancebeam: &BalanceBeam) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut greeting = Vec::new();
+    let mut buf = [0; 1];
+    while !greeting.ends_with(b"\n") {
+        let n = tokio::time::timeout(Duration::from_secs(3), stream.read(&mut buf))
+            .await
+            .expect("Timed out waiting for the upstream's greeting")
+            .unwrap();
+        assert!(n > 0, "Connection closed before the greeting");
+        greeting.push(buf[0]);
+    }
+    let name = String::from_utf8(greeting).unwrap().trim().to_string();
+    (stream, name)
+}
+
+/// Bytes should be passed through both ways untouched, and connections should be spread by how
+/// many each upstream already has.
+#[tokio::test]
+async fn test_tcp_mode() {
+    init_logging();
+    let upstreams = [
+        GreetingServer::new("a").await,
+        GreetingServer::new("b").await,
+    ];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        &["--mode", "tcp", "--active-health-check-interval", "600"],
+    )
+    .await;
+
+    // With least-connections balancing, two connections held open at once go to different
+    // upstreams
+    let (mut first, first_name) = connect(&balancebeam).await;
+    let (mut second, second_name) = connect(&balancebeam).await;
+    assert_ne!(first_name, second_name);
+
+    // Anything at all should make it through, including things that look nothing like HTTP
+    let message = b"\x00\x01 not HTTP \xff\r\n\r\n";
+    first.write_all(message).await.unwrap();
+    let mut echoed = vec![0; message.len()];
+    first.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, message);
+
+    // Hanging up should be passed through to the upstream, which hangs up in turn
+    second.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(3), second.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam did not pass the hang-up along")
+        .unwrap();
+    assert!(rest.is_empty());
+    log::info!("All done :)");
+}
+
+/// Health checks should only check that upstreams accept connections, so a non-HTTP upstream
+/// stays in rotation, while one that stops accepting connections is taken out.
+#[tokio::test]
+async fn test_tcp_health_checks() {
+    init_logging();
+    let upstreams = [
+        GreetingServer::new("a").await,
+        GreetingServer::new("b").await,
+    ];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        &[
+            "--mode",
+            "tcp",
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "1",
+            "--unhealthy-threshold",
+            "1",
+        ],
+    )
+    .await;
+
+    // Give the health checks time to run a couple of times
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let mut names = Vec::new();
+    for _ in 0..4 {
+        names.push(connect(&balancebeam).await.1);
+    }
+    names.sort();
+    assert_eq!(names, ["a", "a", "b", "b"]);
+
+    log::info!("Stopping upstream b");
+    upstreams[1].stop();
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    for _ in 0..4 {
+        assert_eq!(connect(&balancebeam).await.1, "a");
+    }
+    log::info!("All done :)");
+}
+
+/// Changes an upstream's status through the admin interface and returns the status it reports.
+async fn set_status(admin_address: &str, upstream_address: &str, action: &str) -> String {
+    let url = format!(
+        "http://{}/upstreams/{}/{}",
+        admin_address, upstream_address, action
+    );
+    upstream_status(reqwest::Client::new().post(url)).await
+}
+
+/// Returns the status the admin interface reports for an upstream.
+async fn get_status(admin_address: &str, upstream_address: &str) -> String {
+    let url = format!("http://{}/upstreams/{}", admin_address, upstream_address);
+    upstream_status(reqwest::Client::new().get(url)).await
+}
+
+async fn upstream_status(request: reqwest::RequestBuilder) -> String {
+    let upstream: serde_json::Value = request
+        .send()
+        .await
+        .expect("Error sending request to the admin interface")
+        .json()
+        .await
+        .expect("Admin interface replied with invalid JSON");
+    upstream["status"].as_str().unwrap().to_string()
+}
+
+/// Draining an upstream should let the connections already open to it carry on, and it should be
+/// reported as disabled once they have closed. Disabling it should close them right away.
+#[tokio::test]
+async fn test_drain_and_disable() {
+    init_logging();
+    let upstream = GreetingServer::new("a").await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--mode",
+            "tcp",
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "600",
+        ],
+    )
+    .await;
+
+    log::info!("Draining the upstream with a connection open");
+    let (mut stream, _) = connect(&balancebeam).await;
+    let status = set_status(&admin_address, &upstream.address, "drain").await;
+    assert_eq!(status, "draining");
+    stream.write_all(b"still here\n").await.unwrap();
+    let mut buf = [0; 11];
+    tokio::time::timeout(Duration::from_secs(3), stream.read_exact(&mut buf))
+        .await
+        .expect("Timed out waiting for the echo")
+        .unwrap();
+    assert_eq!(&buf, b"still here\n");
+    drop(stream);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(
+        get_status(&admin_address, &upstream.address).await,
+        "disabled"
+    );
+
+    log::info!("Disabling the upstream with a connection open");
+    let status = set_status(&admin_address, &upstream.address, "enable").await;
+    assert_eq!(status, "enabled");
+    let (mut stream, _) = connect(&balancebeam).await;
+    let status = set_status(&admin_address, &upstream.address, "disable").await;
+    assert_eq!(status, "disabled");
+    let mut buf = [0; 1];
+    let n = tokio::time::timeout(Duration::from_secs(3), stream.read(&mut buf))
+        .await
+        .expect("Connection was left open")
+        .unwrap_or(0);
+    assert_eq!(n, 0);
+    log::info!("All done :)");
+}
@@ -0,0 +1,184 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, ConfigFile, EchoServer, Server, TestCert};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Response};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+
+/// Makes a client that speaks HTTP/2 without TLS, relying on prior knowledge (h2c). It multiplexes
+/// all of its requests over a single connection.
+fn h2c_client() -> Client<HttpConnector> {
+    Client::builder().http2_only(true).build_http()
+}
+
+/// Sends a request and returns the response with its body.
+async fn send(
+    client: &Client<HttpConnector>,
+    request: Request<Body>,
+) -> Result<(Response<()>, String), hyper::Error> {
+    read_response(client.request(request).await?).await
+}
+
+/// Splits a response into its head and its body as a string.
+async fn read_response(response: Response<Body>) -> Result<(Response<()>, String), hyper::Error> {
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    Ok((
+        Response::from_parts(parts, ()),
+        String::from_utf8(body.to_vec()).unwrap(),
+    ))
+}
+
+/// Many requests should be multiplexed over a single h2c connection, each reaching the upstream as
+/// its own HTTP/1.1 request, bodies included.
+#[tokio::test]
+async fn test_h2c_multiplexed_requests() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let client = h2c_client();
+
+    let mut requests = Vec::new();
+    for i in 0..10 {
+        let client = client.clone();
+        let authority = balancebeam.address.clone();
+        requests.push(tokio::spawn(async move {
+            let request = Request::post(format!("http://{}/stream/{}", authority, i))
+                .header("cookie", "a=1")
+                .header("cookie", "b=2")
+                .body(Body::from(format!("body of request {}", i)))
+                .unwrap();
+            (i, send(&client, request).await)
+        }));
+    }
+    for request in requests {
+        let (i, result) = request.await.unwrap();
+        let (response, body) = result.expect("Error sending request over HTTP/2");
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        assert!(body.contains(&format!("POST /stream/{} HTTP/1.1", i)));
+        assert!(body.contains(&format!("host: {}", balancebeam.address)));
+        assert!(body.contains("cookie: a=1; b=2"));
+        assert!(body.contains("x-forwarded-for: 127.0.0.1"));
+        assert!(body.ends_with(&format!("body of request {}", i)));
+    }
+
+    log::info!("Making sure HTTP/1.1 still works on the same listener");
+    let response_text = balancebeam
+        .get("/http1")
+        .await
+        .expect("Error sending HTTP/1.1 request to balancebeam");
+    assert!(response_text.contains("GET /http1 HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 11);
+    log::info!("All done :)");
+}
+
+/// A request that fails should only fail its own stream; other requests on the same connection
+/// should carry on as usual.
+#[tokio::test]
+async fn test_errors_are_per_stream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_file = ConfigFile::new();
+    config_file.write(&format!(
+        r#"
+[[pool]]
+name = "echo"
+
+[[pool.upstream]]
+address = "{}"
+
+[[route]]
+path_prefix = "/echo/"
+pool = "echo"
+"#,
+        upstream.address
+    ));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], &["--config", config_file.path.to_str().unwrap()]).await;
+    let client = h2c_client();
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+
+    let (response, _) = send(
+        &client,
+        Request::get(url("/echo/1")).body(Body::empty()).unwrap(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), 200);
+
+    log::info!("Sending a request with no route");
+    let (response, _) = send(
+        &client,
+        Request::get(url("/nowhere")).body(Body::empty()).unwrap(),
+    )
+    .await
+    .expect("Unroutable request broke the connection");
+    assert_eq!(response.status(), 404);
+
+    log::info!("Making sure the connection still works");
+    let (response, body) = send(
+        &client,
+        Request::get(url("/echo/2")).body(Body::empty()).unwrap(),
+    )
+    .await
+    .expect("Connection stopped working after an error on another stream");
+    assert_eq!(response.status(), 200);
+    assert!(body.contains("GET /echo/2 HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Over TLS, clients that offer h2 through ALPN should get HTTP/2.
+#[tokio::test]
+async fn test_alpn_h2() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let cert = TestCert::new(&["balancebeam.test"]);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--tls-cert", cert.cert_path(), "--tls-key", cert.key_path()],
+    )
+    .await;
+
+    let mut roots = tokio_rustls::rustls::RootCertStore::empty();
+    roots
+        .add(&tokio_rustls::rustls::Certificate(cert.cert_der.clone()))
+        .unwrap();
+    let mut config = tokio_rustls::rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+    let stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let stream = connector
+        .connect("balancebeam.test".try_into().unwrap(), stream)
+        .await
+        .expect("TLS handshake with balancebeam failed");
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+    let (mut sender, connection) = hyper::client::conn::Builder::new()
+        .http2_only(true)
+        .handshake(stream)
+        .await
+        .expect("HTTP/2 handshake with balancebeam failed");
+    tokio::spawn(connection);
+    let request = Request::get("https://balancebeam.test/over-h2")
+        .body(Body::empty())
+        .unwrap();
+    let response = sender.send_request(request).await.unwrap();
+    let (response, body) = read_response(response)
+        .await
+        .expect("Error sending request over HTTP/2 with TLS");
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.version(), hyper::Version::HTTP_2);
+    assert!(body.contains("GET /over-h2 HTTP/1.1"));
+    assert!(body.contains("host: balancebeam.test"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
@@ -0,0 +1,355 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, ConfigFile};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use nix::sys::signal::Signal;
+use rand::Rng;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Requests received by the origin, as their path and If-None-Match header (if any).
+type RequestLog = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+/// Responds to a request the way a typical origin serving static assets would. The path picks the
+/// caching headers: `/fresh/...` can be cached for a minute, `/etag/...` must be revalidated every
+/// time, `/private/...` must not be stored by a shared cache, and `/vary/...` varies on
+/// Accept-Language. `/big/...` is cacheable with a 1000-byte body.
+async fn origin(log: RequestLog, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let path = request.uri().path().to_string();
+    let if_none_match = request
+        .headers()
+        .get("if-none-match")
+        .map(|value| value.to_str().unwrap().to_string());
+    let language = request
+        .headers()
+        .get("accept-language")
+        .map(|value| value.to_str().unwrap().to_string())
+        .unwrap_or_default();
+    let count = {
+        let mut log = log.lock().unwrap();
+        log.push((path.clone(), if_none_match.clone()));
+        log.len()
+    };
+    let mut response = Response::builder();
+    let body = if path.starts_with("/fresh/") {
+        response = response.header("cache-control", "public, max-age=60");
+        format!("{} #{}", path, count)
+    } else if path.starts_with("/etag/") {
+        response = response
+            .header("cache-control", "no-cache")
+            .header("etag", "\"v1\"");
+        if if_none_match.as_deref() == Some("\"v1\"") {
+            return Ok(response.status(304).body(Body::empty()).unwrap());
+        }
+        format!("{} #{}", path, count)
+    } else if path.starts_with("/private/") {
+        response = response.header("cache-control", "private, max-age=60");
+        format!("{} #{}", path, count)
+    } else if path.starts_with("/vary/") {
+        response = response
+            .header("cache-control", "max-age=60")
+            .header("vary", "Accept-Language");
+        format!("{} in {} #{}", path, language, count)
+    } else {
+        response = response.header("cache-control", "max-age=60");
+        "x".repeat(1000)
+    };
+    Ok(response.body(Body::from(body)).unwrap())
+}
+
+/// Starts the origin and returns its address, along with the log of requests it receives.
+async fn start_origin() -> (String, RequestLog) {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let log = RequestLog::default();
+    let server_log = log.clone();
+    let service = make_service_fn(move |_| {
+        let log = server_log.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| origin(log.clone(), req))) }
+    });
+    let server = hyper::Server::bind(&address.parse().unwrap()).serve(service);
+    tokio::spawn(server);
+    (address, log)
+}
+
+/// Sends a request through balancebeam and returns its X-Cache header and body.
+async fn get(balancebeam: &BalanceBeam, path: &str, headers: &[(&str, &str)]) -> (String, String) {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let x_cache = response.headers()["x-cache"].to_str().unwrap().to_string();
+    (x_cache, response.text().await.unwrap())
+}
+
+async fn start(origin: &str, extra_args: &[&str]) -> BalanceBeam {
+    let mut args = vec!["--active-health-check-interval", "600"];
+    args.extend_from_slice(extra_args);
+    BalanceBeam::new_with_args(&[origin], &args).await
+}
+
+/// Fresh responses should be served from the cache, while responses the origin marked private
+/// should not be stored. A POST to a URL should forget what was cached for it.
+#[tokio::test]
+async fn test_fresh_responses_are_cached() {
+    init_logging();
+    let (origin, log) = start_origin().await;
+    let metrics_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = start(
+        &origin,
+        &[
+            "--cache-size",
+            "1000000",
+            "--metrics-bind",
+            &metrics_address,
+        ],
+    )
+    .await;
+
+    let (x_cache, first) = get(&balancebeam, "/fresh/a", &[]).await;
+    assert_eq!(x_cache, "MISS");
+    let (x_cache, second) = get(&balancebeam, "/fresh/a", &[]).await;
+    assert_eq!(x_cache, "HIT");
+    assert_eq!(first, second);
+
+    for _ in 0..2 {
+        let (x_cache, _) = get(&balancebeam, "/private/a", &[]).await;
+        assert_eq!(x_cache, "MISS");
+    }
+
+    log::info!("Making sure a POST invalidates the cached response");
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/fresh/a", balancebeam.address))
+        .body("new contents")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers().get("x-cache").is_none());
+    let (x_cache, third) = get(&balancebeam, "/fresh/a", &[]).await;
+    assert_eq!(x_cache, "MISS");
+    assert_ne!(first, third);
+
+    let paths: Vec<String> = log.lock().unwrap().iter().map(|(p, _)| p.clone()).collect();
+    assert_eq!(
+        paths,
+        [
+            "/fresh/a",
+            "/private/a",
+            "/private/a",
+            "/fresh/a",
+            "/fresh/a"
+        ]
+    );
+
+    let metrics = reqwest::get(format!("http://{}/metrics", metrics_address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("balancebeam_cache_requests_total{result=\"hit\"} 1"));
+    assert!(metrics.contains("balancebeam_cache_requests_total{result=\"miss\"} 4"));
+    log::info!("All done :)");
+}
+
+/// HTTP/2 requests should share the cache with HTTP/1.1 ones: a response cached over HTTP/1.1
+/// should be served over HTTP/2, and a POST over HTTP/2 should forget it.
+#[tokio::test]
+async fn test_cache_is_shared_with_http2() {
+    init_logging();
+    let (origin, log) = start_origin().await;
+    let balancebeam = start(&origin, &["--cache-size", "1000000"]).await;
+    let h2c_client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<Body>();
+    let url = format!("http://{}/fresh/a", balancebeam.address);
+
+    let (x_cache, first) = get(&balancebeam, "/fresh/a", &[]).await;
+    assert_eq!(x_cache, "MISS");
+    let response = h2c_client.get(url.parse().unwrap()).await.unwrap();
+    assert_eq!(response.version(), hyper::Version::HTTP_2);
+    assert_eq!(response.headers()["x-cache"], "HIT");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, first.as_bytes());
+
+    log::info!("Making sure a POST over HTTP/2 invalidates the cached response");
+    let request = Request::post(&url)
+        .body(Body::from("new contents"))
+        .unwrap();
+    let response = h2c_client.request(request).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let (x_cache, second) = get(&balancebeam, "/fresh/a", &[]).await;
+    assert_eq!(x_cache, "MISS");
+    assert_ne!(first, second);
+    assert_eq!(log.lock().unwrap().len(), 3);
+    log::info!("All done :)");
+}
+
+/// A stale response with an ETag should be revalidated with If-None-Match, and served from the
+/// cache when the origin answers 304 Not Modified.
+#[tokio::test]
+async fn test_stale_responses_are_revalidated() {
+    init_logging();
+    let (origin, log) = start_origin().await;
+    let balancebeam = start(&origin, &["--cache-size", "1000000"]).await;
+
+    let (x_cache, first) = get(&balancebeam, "/etag/a", &[]).await;
+    assert_eq!(x_cache, "MISS");
+    let (x_cache, second) = get(&balancebeam, "/etag/a", &[]).await;
+    assert_eq!(x_cache, "HIT");
+    assert_eq!(first, second);
+
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            ("/etag/a".to_string(), None),
+            ("/etag/a".to_string(), Some("\"v1\"".to_string())),
+        ]
+    );
+    log::info!("All done :)");
+}
+
+/// Responses that vary on a request header should be stored once for each value of it.
+#[tokio::test]
+async fn test_vary() {
+    init_logging();
+    let (origin, log) = start_origin().await;
+    let balancebeam = start(&origin, &["--cache-size", "1000000"]).await;
+
+    let english = [("accept-language", "en")];
+    let french = [("accept-language", "fr")];
+    let (x_cache, in_english) = get(&balancebeam, "/vary/a", &english).await;
+    assert_eq!(x_cache, "MISS");
+    let (x_cache, in_french) = get(&balancebeam, "/vary/a", &french).await;
+    assert_eq!(x_cache, "MISS");
+    assert!(in_french.contains("in fr"));
+    let (x_cache, body) = get(&balancebeam, "/vary/a", &english).await;
+    assert_eq!(x_cache, "HIT");
+    assert_eq!(body, in_english);
+    assert_eq!(log.lock().unwrap().len(), 2);
+    log::info!("All done :)");
+}
+
+/// Once the cache is full, the least recently used response should be evicted, and with
+/// --cache-dir the bodies should live on disk.
+#[tokio::test]
+async fn test_lru_eviction_on_disk() {
+    init_logging();
+    let (origin, log) = start_origin().await;
+    let mut dir = std::env::temp_dir();
+    dir.push(format!(
+        "balancebeam-test-cache-{}",
+        rand::thread_rng().gen::<u64>()
+    ));
+    let balancebeam = start(
+        &origin,
+        &["--cache-size", "2500", "--cache-dir", dir.to_str().unwrap()],
+    )
+    .await;
+
+    assert_eq!(get(&balancebeam, "/big/a", &[]).await.0, "MISS");
+    assert_eq!(get(&balancebeam, "/big/b", &[]).await.0, "MISS");
+    assert_eq!(count_files(&dir), 2);
+    // Using a makes b the least recently used
+    assert_eq!(get(&balancebeam, "/big/a", &[]).await.0, "HIT");
+    assert_eq!(get(&balancebeam, "/big/c", &[]).await.0, "MISS");
+    assert_eq!(count_files(&dir), 2);
+    let (x_cache, body) = get(&balancebeam, "/big/a", &[]).await;
+    assert_eq!(x_cache, "HIT");
+    assert_eq!(body.len(), 1000);
+    assert_eq!(get(&balancebeam, "/big/b", &[]).await.0, "MISS");
+    assert_eq!(log.lock().unwrap().len(), 4);
+
+    drop(balancebeam);
+    let _ = std::fs::remove_dir_all(&dir);
+    log::info!("All done :)");
+}
+
+fn count_files(dir: &PathBuf) -> usize {
+    std::fs::read_dir(dir).unwrap().count()
+}
+
+/// Opening a disk-backed cache should only remove the files of processes that have exited, and
+/// leave those of a running process (such as one handing over on an upgrade) alone.
+#[tokio::test]
+async fn test_cache_dir_keeps_files_of_running_processes() {
+    init_logging();
+    let (origin, _) = start_origin().await;
+    let mut dir = std::env::temp_dir();
+    dir.push(format!(
+        "balancebeam-test-cache-{}",
+        rand::thread_rng().gen::<u64>()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut exited = std::process::Command::new("true").spawn().unwrap();
+    let exited_pid = exited.id();
+    exited.wait().unwrap();
+    let orphaned = dir.join(format!("balancebeam-{}-0.cache", exited_pid));
+    let running = dir.join(format!("balancebeam-{}-0.cache", std::process::id()));
+    std::fs::write(&orphaned, "old").unwrap();
+    std::fs::write(&running, "in use").unwrap();
+
+    let balancebeam = start(
+        &origin,
+        &[
+            "--cache-size",
+            "1000000",
+            "--cache-dir",
+            dir.to_str().unwrap(),
+        ],
+    )
+    .await;
+    assert!(!orphaned.exists());
+    assert!(running.exists());
+
+    drop(balancebeam);
+    let _ = std::fs::remove_dir_all(&dir);
+    log::info!("All done :)");
+}
+
+/// A cache replaced by a configuration reload should remove the files its bodies were in.
+#[tokio::test]
+async fn test_replaced_cache_removes_its_files() {
+    init_logging();
+    let (origin, _) = start_origin().await;
+    let mut dir = std::env::temp_dir();
+    dir.push(format!(
+        "balancebeam-test-cache-{}",
+        rand::thread_rng().gen::<u64>()
+    ));
+    let config = |size: u64| {
+        format!(
+            "[[upstream]]\naddress = \"{}\"\n\n[cache]\nsize = {}\ndir = \"{}\"\n",
+            origin,
+            size,
+            dir.display()
+        )
+    };
+    let config_file = ConfigFile::new();
+    config_file.write(&config(1000000));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], &["--config", config_file.path.to_str().unwrap()]).await;
+
+    assert_eq!(get(&balancebeam, "/big/a", &[]).await.0, "MISS");
+    assert_eq!(count_files(&dir), 1);
+
+    log::info!("Changing the cache size");
+    config_file.write(&config(2000000));
+    balancebeam.signal(Signal::SIGHUP);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(count_files(&dir), 0);
+    assert_eq!(get(&balancebeam, "/big/a", &[]).await.0, "MISS");
+    assert_eq!(count_files(&dir), 1);
+
+    drop(balancebeam);
+    let _ = std::fs::remove_dir_all(&dir);
+    log::info!("All done :)");
+}
@@ -0,0 +1,241 @@
+mod common;
+
+use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder};
+use common::{init_logging, BalanceBeam};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use rand::Rng;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Responds with a body chosen by the path. `/text` is a large HTML page, `/small` a short one,
+/// `/events` a large event stream, `/image` a large PNG, `/encoded` a large page the origin has
+/// already gzipped itself, and `/chunked` a large JSON document sent without a Content-Length.
+/// `/cached` is like `/text`, but can be cached for a minute.
+async fn origin(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = Response::builder();
+    let response = match request.uri().path() {
+        "/text" => response
+            .header("content-type", "text/html; charset=utf-8")
+            .header("etag", "\"v1\"")
+            .body(Body::from(page())),
+        "/small" => response
+            .header("content-type", "text/html")
+            .body(Body::from("<p>hi</p>")),
+        "/events" => response
+            .header("content-type", "text/event-stream")
+            .body(Body::from(page())),
+        "/image" => response
+            .header("content-type", "image/png")
+            .body(Body::from(page())),
+        "/encoded" => response
+            .header("content-type", "text/html")
+            .header("content-encoding", "gzip")
+            .body(Body::from(page())),
+        "/chunked" => {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                for _ in 0..10 {
+                    let part = "{\"greeting\": \"hello\"},".repeat(100);
+                    if sender.send_data(part.into()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            response
+                .header("content-type", "application/json")
+                .body(body)
+        }
+        "/cached" => response
+            .header("content-type", "text/html")
+            .header("cache-control", "max-age=60")
+            .body(Body::from(page())),
+        _ => response.status(404).body(Body::empty()),
+    };
+    Ok(response.unwrap())
+}
+
+fn page() -> String {
+    "<p>All work and no play makes Jack a dull boy.</p>\n".repeat(200)
+}
+
+async fn start_origin() -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(origin)) });
+    let server = hyper::Server::bind(&address.parse().unwrap()).serve(service);
+    tokio::spawn(server);
+    address
+}
+
+async fn start(origin: &str, extra_args: &[&str]) -> BalanceBeam {
+    let mut args = vec!["--active-health-check-interval", "600", "--compress"];
+    args.extend_from_slice(extra_args);
+    BalanceBeam::new_with_args(&[origin], &args).await
+}
+
+/// Sends a request through balancebeam, and returns the response headers and the body as it
+/// arrived (compressed or not).
+async fn get(
+    balancebeam: &BalanceBeam,
+    path: &str,
+    accept_encoding: Option<&str>,
+) -> (reqwest::header::HeaderMap, Vec<u8>) {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", balancebeam.address, path));
+    if let Some(accept_encoding) = accept_encoding {
+        request = request.header("accept-encoding", accept_encoding);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let headers = response.headers().clone();
+    (headers, response.bytes().await.unwrap().to_vec())
+}
+
+fn header<'a>(headers: &'a reqwest::header::HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).map(|value| value.to_str().unwrap())
+}
+
+async fn decode(encoding: &str, body: &[u8]) -> String {
+    let mut decoded = String::new();
+    match encoding {
+        "gzip" => GzipDecoder::new(body).read_to_string(&mut decoded).await,
+        "br" => BrotliDecoder::new(body).read_to_string(&mut decoded).await,
+        _ => panic!("Unexpected encoding {}", encoding),
+    }
+    .expect("Error decoding response body");
+    decoded
+}
+
+/// Text should be compressed with the encoding the client prefers, and come with the headers
+/// that go with that.
+#[tokio::test]
+async fn test_compresses_text() {
+    init_logging();
+    let origin = start_origin().await;
+    let balancebeam = start(&origin, &[]).await;
+
+    for (accept_encoding, expected) in [
+        ("gzip", "gzip"),
+        ("gzip, deflate, br", "br"),
+        ("br;q=0.5, gzip", "gzip"),
+    ] {
+        let (headers, body) = get(&balancebeam, "/text", Some(accept_encoding)).await;
+        assert_eq!(header(&headers, "content-encoding"), Some(expected));
+        assert_eq!(header(&headers, "vary"), Some("Accept-Encoding"));
+        assert_eq!(header(&headers, "etag"), Some("W/\"v1\""));
+        assert!(header(&headers, "content-length").is_none());
+        assert!(body.len() < page().len() / 4);
+        assert_eq!(decode(expected, &body).await, page());
+    }
+
+    log::info!("Making sure a body without a Content-Length is compressed too");
+    let (headers, body) = get(&balancebeam, "/chunked", Some("gzip")).await;
+    assert_eq!(header(&headers, "content-encoding"), Some("gzip"));
+    let decoded = decode("gzip", &body).await;
+    assert_eq!(decoded, "{\"greeting\": \"hello\"},".repeat(1000));
+    log::info!("All done :)");
+}
+
+/// Responses the client can't decode, that are too small, of types that aren't compressed, or
+/// that the origin already compressed should be passed along as they are.
+#[tokio::test]
+async fn test_leaves_other_responses_alone() {
+    init_logging();
+    let origin = start_origin().await;
+    let balancebeam = start(&origin, &[]).await;
+
+    for (path, accept_encoding) in [
+        ("/text", None),
+        ("/text", Some("identity")),
+        ("/text", Some("gzip;q=0, br;q=0")),
+        ("/small", Some("gzip")),
+        ("/events", Some("gzip")),
+        ("/image", Some("gzip")),
+    ] {
+        let (headers, body) = get(&balancebeam, path, accept_encoding).await;
+        assert!(header(&headers, "content-encoding").is_none());
+        assert!(header(&headers, "vary").is_none());
+        assert_eq!(
+            header(&headers, "content-length"),
+            Some(body.len().to_string().as_str())
+        );
+    }
+
+    let (headers, body) = get(&balancebeam, "/encoded", Some("gzip, br")).await;
+    assert_eq!(header(&headers, "content-encoding"), Some("gzip"));
+    assert_eq!(body, page().as_bytes());
+
+    log::info!("Making sure --compress-type and --compress-min-size are honoured");
+    let balancebeam = start(
+        &origin,
+        &["--compress-type", "image/*", "--compress-min-size", "5"],
+    )
+    .await;
+    let (headers, _) = get(&balancebeam, "/text", Some("gzip")).await;
+    assert!(header(&headers, "content-encoding").is_none());
+    let (headers, body) = get(&balancebeam, "/image", Some("gzip")).await;
+    assert_eq!(header(&headers, "content-encoding"), Some("gzip"));
+    assert_eq!(decode("gzip", &body).await, page());
+    log::info!("All done :)");
+}
+
+/// The cache should store the uncompressed response, and compress it for each client that wants
+/// it, with the length of the compressed body.
+#[tokio::test]
+async fn test_compresses_cached_responses() {
+    init_logging();
+    let origin = start_origin().await;
+    let balancebeam = start(&origin, &["--cache-size", "1000000"]).await;
+
+    let (headers, body) = get(&balancebeam, "/cached", Some("gzip")).await;
+    assert_eq!(header(&headers, "x-cache"), Some("MISS"));
+    assert_eq!(header(&headers, "content-encoding"), Some("gzip"));
+    assert_eq!(decode("gzip", &body).await, page());
+
+    let (headers, body) = get(&balancebeam, "/cached", Some("br")).await;
+    assert_eq!(header(&headers, "x-cache"), Some("HIT"));
+    assert_eq!(header(&headers, "content-encoding"), Some("br"));
+    assert_eq!(
+        header(&headers, "content-length"),
+        Some(body.len().to_string().as_str())
+    );
+    assert_eq!(decode("br", &body).await, page());
+
+    let (headers, body) = get(&balancebeam, "/cached", None).await;
+    assert_eq!(header(&headers, "x-cache"), Some("HIT"));
+    assert!(header(&headers, "content-encoding").is_none());
+    assert_eq!(body, page().as_bytes());
+    log::info!("All done :)");
+}
+
+/// HTTP/1.0 clients don't understand chunked framing, so a body whose compressed length isn't known
+/// up front should be sent to them uncompressed.
+#[tokio::test]
+async fn test_http10_client_gets_uncompressed_body() {
+    init_logging();
+    let origin = start_origin().await;
+    let balancebeam = start(&origin, &[]).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET /chunked HTTP/1.0\r\nAccept-Encoding: gzip\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(3), stream.read_to_end(&mut response))
+        .await
+        .expect("Timed out waiting for balancebeam to close the connection")
+        .unwrap();
+    let response = String::from_utf8(response).expect("Response body was compressed");
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let head = head.to_ascii_lowercase();
+    assert!(head.contains(" 200 "));
+    assert!(!head.contains("transfer-encoding"));
+    assert!(!head.contains("content-encoding"));
+    assert_eq!(body, "{\"greeting\": \"hello\"},".repeat(1000));
+    log::info!("All done :)");
+}
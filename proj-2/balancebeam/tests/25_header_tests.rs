@@ -0,0 +1,298 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, ConfigFile, EchoServer};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use rand::Rng;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Responds with headers that an origin might leak, along with the request's X-Request-Id if it
+/// got one.
+async fn origin(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let mut response = Response::builder()
+        .header("server", "origin/1.0")
+        .header("x-powered-by", "php")
+        .header("cache-control", "no-cache");
+    if let Some(request_id) = request.headers().get("x-request-id") {
+        response = response.header("x-upstream-saw", request_id);
+    }
+    Ok(response.body(Body::from("ok")).unwrap())
+}
+
+async fn start_origin() -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(origin)) });
+    let server = hyper::Server::bind(&address.parse().unwrap()).serve(service);
+    tokio::spawn(server);
+    address
+}
+
+/// Sends a GET request through balancebeam, and returns the response headers and body.
+async fn get(
+    balancebeam: &BalanceBeam,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> (reqwest::header::HeaderMap, String) {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let headers = response.headers().clone();
+    (headers, response.text().await.unwrap())
+}
+
+fn header<'a>(headers: &'a reqwest::header::HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).map(|value| value.to_str().unwrap())
+}
+
+/// The upstream should be told who the client is, what it connected with and which host it asked
+/// for, both in the X-Forwarded-* headers and in a Forwarded header.
+#[tokio::test]
+async fn test_forwarding_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let (_, body) = get(&balancebeam, "/", &[("forwarded", "for=192.0.2.1")]).await;
+    assert!(body.contains("x-forwarded-for: 127.0.0.1\n"));
+    assert!(body.contains("x-forwarded-proto: http\n"));
+    assert!(body.contains(&format!("x-forwarded-host: {}\n", balancebeam.address)));
+    assert!(body.contains(&format!(
+        "forwarded: for=192.0.2.1, for=127.0.0.1;proto=http;host=\"{}\"\n",
+        balancebeam.address
+    )));
+    log::info!("All done :)");
+}
+
+/// Every request should get an X-Request-Id that the upstream sees and the client gets back. An ID
+/// sent by the client is kept.
+#[tokio::test]
+async fn test_request_id() {
+    init_logging();
+    let origin = start_origin().await;
+    let balancebeam = BalanceBeam::new(&[&origin], None, None).await;
+
+    let (first, _) = get(&balancebeam, "/", &[]).await;
+    let (second, _) = get(&balancebeam, "/", &[]).await;
+    let request_id = header(&first, "x-request-id").expect("No X-Request-Id in the response");
+    assert_eq!(request_id.len(), 36);
+    assert_eq!(header(&first, "x-upstream-saw"), Some(request_id));
+    assert_ne!(header(&second, "x-request-id"), Some(request_id));
+
+    log::info!("Sending a request that already has an ID");
+    let (headers, _) = get(&balancebeam, "/", &[("x-request-id", "abc-123")]).await;
+    assert_eq!(header(&headers, "x-request-id"), Some("abc-123"));
+    assert_eq!(header(&headers, "x-upstream-saw"), Some("abc-123"));
+    log::info!("All done :)");
+}
+
+/// Headers that only mean something to the client's connection, including the ones the client
+/// listed in its Connection header, should not reach the upstream.
+#[tokio::test]
+async fn test_hop_by_hop_headers_are_removed() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"GET /hop HTTP/1.1\r\nHost: balancebeam.test\r\nConnection: close, X-Secret\r\n\
+            X-Secret: hunter2\r\nKeep-Alive: timeout=5\r\nTE: trailers\r\n\
+            Proxy-Authorization: Basic Zm9vOmJhcg==\r\nX-Kept: yes\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(3), stream.read_to_string(&mut response))
+        .await
+        .expect("Timed out waiting for the response")
+        .unwrap();
+    let response = response.to_ascii_lowercase();
+    assert!(response.starts_with("http/1.1 200"));
+    // The echo server lists the headers it got in the body
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(body.contains("x-kept: yes\n"));
+    for name in [
+        "connection",
+        "x-secret",
+        "keep-alive",
+        "te",
+        "proxy-authorization",
+    ] {
+        assert!(
+            !body.contains(&format!("\n{}: ", name)),
+            "{} was passed to the upstream: {}",
+            name,
+            body
+        );
+    }
+    log::info!("All done :)");
+}
+
+/// Header rules given on the command line should change requests before they are sent upstream
+/// and responses before they are sent to the client.
+#[tokio::test]
+async fn test_header_rules() {
+    init_logging();
+    let origin = start_origin().await;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--request-header-set",
+            "X-Environment: test",
+            "--request-header-add",
+            "X-Tag: balancebeam",
+            "--request-header-remove",
+            "X-Debug",
+        ],
+    )
+    .await;
+    let (_, body) = get(
+        &balancebeam,
+        "/",
+        &[
+            ("x-environment", "production"),
+            ("x-tag", "client"),
+            ("x-debug", "1"),
+        ],
+    )
+    .await;
+    assert!(body.contains("x-environment: test\n"));
+    assert!(!body.contains("x-environment: production"));
+    assert!(body.contains("x-tag: client\n"));
+    assert!(body.contains("x-tag: balancebeam\n"));
+    assert!(!body.contains("x-debug"));
+    drop(balancebeam);
+
+    log::info!("Checking the response rules");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&origin],
+        &[
+            "--response-header-set",
+            "Cache-Control: no-store",
+            "--response-header-add",
+            "Strict-Transport-Security: max-age=31536000",
+            "--response-header-remove",
+            "Server",
+            "--response-header-remove",
+            "X-Powered-By",
+        ],
+    )
+    .await;
+    let (headers, _) = get(&balancebeam, "/", &[]).await;
+    assert_eq!(header(&headers, "cache-control"), Some("no-store"));
+    assert_eq!(
+        header(&headers, "strict-transport-security"),
+        Some("max-age=31536000")
+    );
+    assert!(header(&headers, "server").is_none());
+    assert!(header(&headers, "x-powered-by").is_none());
+    assert!(header(&headers, "x-request-id").is_some());
+    log::info!("All done :)");
+}
+
+/// Header rules can also be given in the configuration file, where rules that would break message
+/// framing are rejected.
+#[tokio::test]
+async fn test_header_rules_in_config_file() {
+    init_logging();
+    let origin = start_origin().await;
+    let config_file = ConfigFile::new();
+    config_file.write(&format!(
+        "[[upstream]]\naddress = \"{}\"\n\n\
+        [headers.response]\nset = [\"X-Frame-Options: DENY\"]\nremove = [\"Server\"]\n",
+        origin
+    ));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], &["--config", config_file.path.to_str().unwrap()]).await;
+    let (headers, _) = get(&balancebeam, "/", &[]).await;
+    assert_eq!(header(&headers, "x-frame-options"), Some("DENY"));
+    assert!(header(&headers, "server").is_none());
+    drop(balancebeam);
+
+    log::info!("Starting with a rule that removes Content-Length");
+    config_file.write(&format!(
+        "[[upstream]]\naddress = \"{}\"\n\n[headers.response]\nremove = [\"Content-Length\"]\n",
+        origin
+    ));
+    let mut balancebeam =
+        BalanceBeam::new_with_args(&[], &["--config", config_file.path.to_str().unwrap()]).await;
+    let status = balancebeam.wait_for_exit(Duration::from_secs(3)).await;
+    assert!(!status.expect("balancebeam should have exited").success());
+    log::info!("All done :)");
+}
+
+/// Reads one response with a Content-Length from the stream, returning its head and body.
+async fn read_response(stream: &mut TcpStream) -> (String, String) {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = tokio::time::timeout(Duration::from_secs(3), stream.read(&mut buf))
+            .await
+            .expect("Timed out waiting for the response")
+            .unwrap();
+        assert!(n > 0, "Connection closed before the response was complete");
+        data.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&data).to_ascii_lowercase();
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .expect("Response has no Content-Length")
+                .parse()
+                .unwrap();
+            if body.len() >= length {
+                return (head.to_string(), body.to_string());
+            }
+        }
+    }
+}
+
+/// An HTTP/1.0 request should reach the upstream as HTTP/1.0, and the client's connection should
+/// only be kept open if it asked for keep-alive.
+#[tokio::test]
+async fn test_http10_keep_alive() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for path in ["/first", "/second"] {
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: balancebeam.test\r\nConnection: keep-alive\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let (head, body) = read_response(&mut stream).await;
+        assert!(head.starts_with("http/1.1 200"));
+        assert!(head.contains("\r\nconnection: keep-alive"));
+        assert!(body.starts_with(&format!("get {} http/1.0\n", path)));
+        // Keeping the upstream connection open is for balancebeam to ask for
+        assert!(body.contains("connection: keep-alive\n"));
+    }
+
+    log::info!("Sending a request without keep-alive");
+    stream
+        .write_all(b"GET /last HTTP/1.0\r\nHost: balancebeam.test\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(3), stream.read_to_string(&mut response))
+        .await
+        .expect("Connection was not closed after an HTTP/1.0 response")
+        .unwrap();
+    let response = response.to_ascii_lowercase();
+    assert!(response.starts_with("http/1.1 200"));
+    assert!(response.contains("\r\nconnection: close\r\n"));
+    log::info!("All done :)");
+}
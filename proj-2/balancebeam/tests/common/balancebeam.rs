@@ -1,13 +1,25 @@
+use crate::common::test_cert::TestCert;
 use rand::Rng;
+use std::sync::{Arc, Mutex};
 //use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
 use tokio::process::{Child, Command};
 use tokio::time::sleep;
 
+/// What came back from a request sent over TLS.
+#[allow(dead_code)]
+pub struct TlsResponse {
+    pub body: String,
+    /// The certificate balancebeam presented during the handshake, in DER form
+    pub peer_cert: Vec<u8>,
+}
+
 pub struct BalanceBeam {
-    #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
+    /// Everything balancebeam has printed so far, including any processes it started
+    output: Arc<Mutex<Vec<String>>>,
 }
 
 impl BalanceBeam {
@@ -19,11 +31,28 @@ impl BalanceBeam {
         path
     }
 
+    #[allow(dead_code)]
     pub async fn new(
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
+        let mut extra_args = Vec::new();
+        if let Some(active_health_check_interval) = active_health_check_interval {
+            extra_args.push("--active-health-check-interval".to_string());
+            extra_args.push(active_health_check_interval.to_string());
+        }
+        if let Some(max_requests_per_minute) = max_requests_per_minute {
+            extra_args.push("--max-requests-per-minute".to_string());
+            extra_args.push(max_requests_per_minute.to_string());
+        }
+        let extra_args: Vec<&str> = extra_args.iter().map(|arg| arg.as_str()).collect();
+        BalanceBeam::new_with_args(upstreams, &extra_args).await
+    }
+
+    /// Starts balancebeam with the given upstreams, passing any additional command-line arguments
+    /// through as-is.
+    pub async fn new_with_args(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
         let mut rng = rand::thread_rng();
         let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
         let mut cmd = Command::new(BalanceBeam::target_bin_path());
@@ -31,29 +60,26 @@ impl BalanceBeam {
         for upstream in upstreams {
             cmd.arg("--upstream").arg(upstream);
         }
-        if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
-        }
-        if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
-        }
+        cmd.args(extra_args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute balancebeam binary {}",
+                BalanceBeam::target_bin_path().to_str().unwrap()
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
         // suppressed if the test passes and displayed if it fails.
+        let output = Arc::new(Mutex::new(Vec::new()));
         let stdout = child
             .stdout
             .take()
             .expect("Child process somehow missing stdout pipe!");
+        let output_clone = output.clone();
         tokio::spawn(async move {
             let mut stdout_reader = BufReader::new(stdout).lines();
             while let Some(line) = stdout_reader
@@ -62,12 +88,14 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stdout")
             {
                 println!("Balancebeam output: {}", line);
+                output_clone.lock().unwrap().push(line);
             }
         });
         let stderr = child
             .stderr
             .take()
             .expect("Child process somehow missing stderr pipe!");
+        let output_clone = output.clone();
         tokio::spawn(async move {
             let mut stderr_reader = BufReader::new(stderr).lines();
             while let Some(line) = stderr_reader
@@ -76,19 +104,54 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stderr")
             {
                 println!("Balancebeam output: {}", line);
+                output_clone.lock().unwrap().push(line);
             }
         });
 
         // Hack: wait for executable to start running
         sleep(std::time::Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        BalanceBeam {
+            child,
+            address,
+            output,
+        }
+    }
+
+    /// Sends a signal (e.g. SIGHUP) to the balancebeam process.
+    #[allow(dead_code)]
+    pub fn signal(&self, signal: nix::sys::signal::Signal) {
+        let pid = self
+            .child
+            .id()
+            .expect("balancebeam process has already exited");
+        nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), signal)
+            .expect("Failed to send signal to balancebeam");
+    }
+
+    /// Returns the lines balancebeam has printed so far.
+    #[allow(dead_code)]
+    pub fn output(&self) -> Vec<String> {
+        self.output.lock().unwrap().clone()
+    }
+
+    /// Waits up to `limit` for the balancebeam process to exit, returning its exit status, or None
+    /// if it is still running.
+    #[allow(dead_code)]
+    pub async fn wait_for_exit(
+        &mut self,
+        limit: std::time::Duration,
+    ) -> Option<std::process::ExitStatus> {
+        tokio::time::timeout(limit, self.child.wait())
+            .await
+            .ok()
+            .map(|status| status.expect("Failed to wait for balancebeam to exit"))
     }
 
     #[allow(dead_code)]
     pub async fn get(&self, path: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .get(&format!("http://{}{}", self.address, path))
+            .get(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await?
@@ -100,7 +163,7 @@ impl BalanceBeam {
     pub async fn post(&self, path: &str, body: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .post(&format!("http://{}{}", self.address, path))
+            .post(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "balancebeam-tests")
             .body(body.to_string())
             .send()
@@ -108,4 +171,47 @@ impl BalanceBeam {
             .text()
             .await
     }
+
+    /// Sends a GET request over TLS, asking for `server_name` using SNI and trusting only the given
+    /// certificates.
+    #[allow(dead_code)]
+    pub async fn get_tls(
+        &self,
+        path: &str,
+        server_name: &str,
+        trusted: &[&TestCert],
+    ) -> Result<TlsResponse, Box<dyn std::error::Error + Send + Sync>> {
+        let mut roots = tokio_rustls::rustls::RootCertStore::empty();
+        for cert in trusted {
+            roots.add(&tokio_rustls::rustls::Certificate(cert.cert_der.clone()))?;
+        }
+        let config = tokio_rustls::rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+
+        let stream = TcpStream::connect(&self.address).await?;
+        let stream = connector.connect(server_name.try_into()?, stream).await?;
+        let peer_cert = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(|cert| cert.0.clone())
+            .unwrap_or_default();
+
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+        tokio::spawn(connection);
+        let request = hyper::Request::get(path)
+            .header("host", server_name)
+            .header("x-sent-by", "balancebeam-tests")
+            .body(hyper::Body::empty())?;
+        let response = sender.send_request(request).await?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(TlsResponse {
+            body: String::from_utf8(body.to_vec())?,
+            peer_cert,
+        })
+    }
 }
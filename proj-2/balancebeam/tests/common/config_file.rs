@@ -0,0 +1,28 @@
+use rand::Rng;
+use std::path::PathBuf;
+
+/// A configuration file in the temp directory that is deleted when dropped.
+pub struct ConfigFile {
+    pub path: PathBuf,
+}
+
+impl ConfigFile {
+    pub fn new() -> ConfigFile {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "balancebeam-test-{}.toml",
+            rand::thread_rng().gen::<u64>()
+        ));
+        ConfigFile { path }
+    }
+
+    pub fn write(&self, contents: &str) {
+        std::fs::write(&self.path, contents).expect("Failed to write configuration file");
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -1,14 +1,29 @@
 mod balancebeam;
+// Not every test file uses every test server
+#[allow(dead_code)]
+mod config_file;
+#[allow(dead_code)]
 mod echo_server;
+#[allow(dead_code)]
 mod error_server;
+#[allow(dead_code)]
 mod server;
+#[allow(dead_code)]
+mod test_cert;
 
 use std::sync;
 
 pub use balancebeam::BalanceBeam;
+#[allow(unused_imports)]
+pub use config_file::ConfigFile;
+#[allow(unused_imports)]
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
+#[allow(unused_imports)]
 pub use server::Server;
+#[allow(unused_imports)]
+pub use test_cert::TestCert;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
@@ -0,0 +1,57 @@
+use rand::Rng;
+use std::path::PathBuf;
+
+/// A freshly generated self-signed certificate and its private key, written out as PEM files in
+/// the temp directory so they can be passed to balancebeam. The files are deleted when dropped.
+pub struct TestCert {
+    dir: PathBuf,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// The certificate in DER form, for trusting it or comparing it with what a server presented
+    pub cert_der: Vec<u8>,
+}
+
+impl TestCert {
+    pub fn new(hostnames: &[&str]) -> TestCert {
+        let hostnames: Vec<String> = hostnames.iter().map(|name| name.to_string()).collect();
+        let cert = rcgen::generate_simple_self_signed(hostnames)
+            .expect("Failed to generate a self-signed certificate");
+        // Serialize once: every call signs the certificate again, which can give different bytes
+        let cert_pem = cert.serialize_pem().unwrap();
+        let cert_der = rustls_pemfile::certs(&mut cert_pem.as_bytes())
+            .unwrap()
+            .remove(0);
+
+        let mut dir = std::env::temp_dir();
+        dir.push(format!(
+            "balancebeam-test-cert-{}",
+            rand::thread_rng().gen::<u64>()
+        ));
+        std::fs::create_dir(&dir).expect("Failed to create certificate directory");
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert_pem).expect("Failed to write certificate");
+        std::fs::write(&key_path, cert.serialize_private_key_pem())
+            .expect("Failed to write private key");
+        TestCert {
+            dir,
+            cert_path,
+            key_path,
+            cert_der,
+        }
+    }
+
+    pub fn cert_path(&self) -> &str {
+        self.cert_path.to_str().unwrap()
+    }
+
+    pub fn key_path(&self) -> &str {
+        self.key_path.to_str().unwrap()
+    }
+}
+
+impl Drop for TestCert {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}